@@ -12,8 +12,8 @@ pub struct Opts {
 
 pub fn execute(opts: &Opts) -> anyhow::Result<()> {
     let config = configure(opts)?;
-    let mut engine = Engine::start(config)?;
-    repl::run(&mut engine);
+    let engine = Engine::start(config)?;
+    repl::run(&engine);
     Ok(())
 }
 
@@ -40,7 +40,7 @@ impl Output {
     }
 }
 
-pub fn run(engine: &mut Engine) {
+pub fn run(engine: &Engine) {
     let mut editor = Editor::<()>::new();
     editor.load_history(HISTORY_FILE).ok();
     println!("r2d2 repl :: use :help to get help and :quit to exit");
@@ -76,7 +76,7 @@ fn read(editor: &mut Editor<()>) -> Result<Input, String> {
     }
 }
 
-fn eval(cmd: Command, engine: &mut Engine) -> Output {
+fn eval(cmd: Command, engine: &Engine) -> Output {
     match cmd {
         Command::Quit => Output::Break,
 
@@ -104,9 +104,18 @@ fn eval(cmd: Command, engine: &mut Engine) -> Output {
         },
 
         Command::ListKeys => {
-            let string_keys: Result<Vec<String>, _> =
-                engine.iter().map(|(k, _v)| TryInto::try_into(k)).collect();
-            Output::Message(format!("OK <{}>", string_keys.unwrap().join(", ")))
+            let keys: crate::engine::Result<Vec<Key>> = engine
+                .iter()
+                .and_then(|iter| iter.map(|record| record.map(|(k, _v)| k)).collect());
+
+            match keys {
+                Ok(keys) => {
+                    let string_keys: Result<Vec<String>, _> =
+                        keys.into_iter().map(TryInto::try_into).collect();
+                    Output::Message(format!("OK <{}>", string_keys.unwrap().join(", ")))
+                }
+                Err(msg) => Output::error(format!("{:?}", msg)),
+            }
         }
 
         Command::Help => Output::message(
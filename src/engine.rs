@@ -13,17 +13,22 @@
 pub use key::Key;
 use log;
 use std::fmt::Debug;
+use std::ops::RangeBounds;
+pub use storage::lsm::batch::WriteBatch;
+pub use storage::lsm::recovery::RecoveryReport;
+pub use storage::lsm::snapshot::Snapshot;
+pub use storage::lsm::sstable::CorruptedRange;
 use thiserror::Error;
+pub use transaction::Transaction;
 pub use value::Value;
 
 use crate::engine::configuration::Configuration;
 
-use self::storage::lsm::memtable;
-
 pub mod configuration;
 pub mod directories;
 pub mod key;
 pub mod storage;
+pub mod transaction;
 pub mod value;
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -39,9 +44,14 @@ pub enum Error {
     StorageError(#[from] storage::lsm::Error),
     #[error(transparent)]
     FileSystemError(#[from] directories::Error),
+    #[error("TransactionConflict: {0:?} has been changed by a concurrent write")]
+    TransactionConflict(Key),
 }
 
 /// The engine encapsulates local storage, replication and distribution
+///
+/// The engine is `Send` and `Sync`, so it can be shared between threads, e.g. in an `Arc`.
+/// Reads run in parallel, while writes are applied one after the other.
 pub struct Engine {
     lsm: storage::lsm::LSM,
 }
@@ -55,13 +65,31 @@ impl Engine {
         Ok(Self { lsm })
     }
 
+    /// What the recovery has done when the engine started
+    ///
+    /// After a crash the engine replays the writes that haven't been persisted yet.
+    /// The report tells how many operations have been replayed and which damaged parts
+    /// of the WALs have been dropped, according to the configured recovery mode.
+    pub fn recovery_report(&self) -> &RecoveryReport {
+        self.lsm.recovery_report()
+    }
+
+    /// Scan every live SSTable and check the checksums of all of its blocks
+    ///
+    /// Returns the ranges of the files that are corrupted, e.g. by silent corruption of
+    /// the disk. An empty result means that all data that has been flushed is intact.
+    /// The data in the WAL and the memtables isn't covered.
+    pub fn verify_all(&self) -> Result<Vec<CorruptedRange>> {
+        Ok(self.lsm.verify_all()?)
+    }
+
     /// Insert a key value pair into the store
     ///
     /// when this function returns successfully, the following guarantees hold:
-    /// * the change is durable on the local node.
+    /// * the change is durable on the local node, with the default WAL sync policy.
     /// * a local lookup will return the inserted value (unless there was an update in between)
     pub fn set<K: Into<Key> + Debug, V: Into<Value> + Debug>(
-        &mut self,
+        &self,
         key: K,
         value: V,
     ) -> Result<Option<Value>> {
@@ -76,13 +104,23 @@ impl Engine {
     /// been deleted if it existed.
     ///
     /// If the function returns successfully, the following guarantees hold:
-    /// * the change is durable on the local node.
+    /// * the change is durable on the local node, with the default WAL sync policy.
     /// * the key/value can not be found anymore (unless it has been re-inserted)
-    pub fn del(&mut self, key: &Key) -> Result<Option<Value>> {
+    pub fn del(&self, key: &Key) -> Result<Option<Value>> {
         log::trace!(target: "engine", "Delete {:?}", key);
         Ok(self.lsm.del(&key)?)
     }
 
+    /// Apply all operations of the `batch` atomically
+    ///
+    /// When this function returns successfully, all changes of the batch are durable
+    /// on the local node, with the default WAL sync policy. A crash either preserves all of them or none of them,
+    /// and reads never see only a part of the batch.
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        log::trace!(target: "engine", "Write batch of {} operations", batch.len());
+        Ok(self.lsm.write(batch)?)
+    }
+
     /// Lookup a value for the given key
     ///
     /// Find a value for the given key if it exists.
@@ -93,8 +131,88 @@ impl Engine {
         Ok(self.lsm.get(&key)?)
     }
 
-    pub fn iter(&self) -> EngineIterator {
-        self.lsm.iter()
+    /// Take a snapshot of the current state of the store
+    ///
+    /// Reads through the snapshot see the key value pairs as they were when the
+    /// snapshot was taken, no matter which writes happen afterwards.
+    /// The snapshot keeps the old data around until it's dropped.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        Ok(self.lsm.snapshot()?)
+    }
+
+    /// Begin an optimistic transaction
+    ///
+    /// The transaction reads from a snapshot of the current state and buffers its writes
+    /// until `Transaction::commit`, which fails if a key that has been read was changed
+    /// by another write in the meantime.
+    pub fn begin(&self) -> Result<Transaction<'_>> {
+        log::trace!(target: "engine", "Begin transaction");
+        Ok(Transaction::new(self, self.snapshot()?))
+    }
+
+    /// Lookup the value that the key had when the `snapshot` was taken
+    pub fn get_at(&self, key: &Key, snapshot: &Snapshot) -> Result<Option<Value>> {
+        log::trace!(target: "engine", "Lookup {:?} at {}", key, snapshot.sequence());
+        Ok(self.lsm.get_at(key, snapshot)?)
+    }
+
+    /// Iterate over all key value pairs in ascending key order
+    ///
+    /// The iterator yields the most recent value of every key that hasn't been deleted.
+    /// Reading the data might fail, in which case the iterator yields the error.
+    pub fn iter(&self) -> Result<EngineIterator<'_>> {
+        Ok(self.lsm.iter()?)
+    }
+
+    /// Iterate over all key value pairs as they were when the `snapshot` was taken
+    pub fn iter_at(&self, snapshot: &Snapshot) -> Result<EngineIterator<'_>> {
+        Ok(self.lsm.iter_at(snapshot)?)
+    }
+
+    /// Iterate over the key value pairs with keys in the `range`, in ascending key order
+    ///
+    /// This allows to read a page of keys without scanning everything in front of it.
+    pub fn range<R: RangeBounds<Key>>(&self, range: R) -> Result<EngineIterator<'_>> {
+        Ok(self.lsm.range(range)?)
+    }
+
+    /// Iterate over the key value pairs with keys in the `range` as they were when the `snapshot` was taken
+    pub fn range_at<R: RangeBounds<Key>>(
+        &self,
+        range: R,
+        snapshot: &Snapshot,
+    ) -> Result<EngineIterator<'_>> {
+        Ok(self.lsm.range_at(range, snapshot)?)
+    }
+
+    /// Iterate over the key value pairs with keys that start with `prefix`, in ascending key order
+    pub fn scan_prefix<K: Into<Key>>(&self, prefix: K) -> Result<EngineIterator<'_>> {
+        Ok(self.lsm.scan_prefix(&prefix.into())?)
+    }
+
+    /// Iterate over the key value pairs with keys that start with `prefix` as they were when the `snapshot` was taken
+    pub fn scan_prefix_at<K: Into<Key>>(
+        &self,
+        prefix: K,
+        snapshot: &Snapshot,
+    ) -> Result<EngineIterator<'_>> {
+        Ok(self.lsm.scan_prefix_at(&prefix.into(), snapshot)?)
+    }
+
+    /// Create a cursor that can be moved through the key value pairs in both directions
+    ///
+    /// The cursor is not positioned yet, use one of its seek methods first.
+    pub fn cursor(&self) -> Result<EngineCursor<'_>> {
+        Ok(EngineCursor {
+            cursor: self.lsm.cursor()?,
+        })
+    }
+
+    /// Create a cursor over the key value pairs as they were when the `snapshot` was taken
+    pub fn cursor_at(&self, snapshot: &Snapshot) -> Result<EngineCursor<'_>> {
+        Ok(EngineCursor {
+            cursor: self.lsm.cursor_at(snapshot)?,
+        })
     }
 }
 
@@ -109,9 +227,58 @@ impl<'a> EngineIterator<'a> {
 }
 
 impl<'a> Iterator for EngineIterator<'a> {
-    type Item = (&'a Key, &'a memtable::Entry);
+    type Item = Result<(Key, Value)>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.iter.next()
+        Some(self.iter.next()?.map_err(Error::from))
+    }
+}
+
+/// A cursor over the key value pairs of the engine
+///
+/// In contrast to the `EngineIterator` the cursor can be positioned at any key
+/// and moves in both directions. For example the latest entries of a sorted
+/// key space are read with `seek_to_last` followed by calls to `prev`.
+pub struct EngineCursor<'a> {
+    cursor: storage::lsm::LiveCursor<'a>,
+}
+
+// the cursor moves in both directions, which can't be expressed with `Iterator`
+#[allow(clippy::should_implement_trait)]
+impl<'a> EngineCursor<'a> {
+    /// Position the cursor at the smallest key
+    pub fn seek_to_first(&mut self) -> Result<()> {
+        Ok(self.cursor.seek_to_first()?)
+    }
+
+    /// Position the cursor at the greatest key
+    pub fn seek_to_last(&mut self) -> Result<()> {
+        Ok(self.cursor.seek_to_last()?)
+    }
+
+    /// Position the cursor at the first key that is not smaller than `key`
+    pub fn seek(&mut self, key: &Key) -> Result<()> {
+        Ok(self.cursor.seek(key)?)
+    }
+
+    /// Position the cursor at the last key that is not greater than `key`
+    pub fn seek_for_prev(&mut self, key: &Key) -> Result<()> {
+        Ok(self.cursor.seek_for_prev(key)?)
+    }
+
+    /// Move the cursor to the next greater key
+    pub fn next(&mut self) -> Result<()> {
+        Ok(self.cursor.next()?)
+    }
+
+    /// Move the cursor to the next smaller key
+    pub fn prev(&mut self) -> Result<()> {
+        Ok(self.cursor.prev()?)
+    }
+
+    /// The key value pair at the position of the cursor, or `None` if the cursor
+    /// has moved past either end
+    pub fn current(&self) -> Option<(&Key, &Value)> {
+        self.cursor.current()
     }
 }
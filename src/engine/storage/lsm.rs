@@ -1,6 +1,3 @@
-use crate::engine::storage::lsm::sstable::Slab;
-use crate::engine::storage::lsm::wal::writer::WalWriter;
-
 /// The LSM implements a log structured merge tree using SSTables as C1
 ///
 /// Docs: https://en.wikipedia.org/wiki/Log-structured_merge-tree/
@@ -11,30 +8,74 @@ use crate::engine::storage::lsm::wal::writer::WalWriter;
 /// related to the management of the local LSM. It might spawn additional
 /// threads.
 use crate::engine::{EngineIterator, Key, Value};
-use configuration::Configuration;
+use configuration::{Configuration, RecoveryMode};
 use log;
+use std::collections::BTreeMap;
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use thiserror::Error;
 
+pub mod batch;
 pub mod binary_io;
+pub mod bloom;
+pub mod cache;
+pub mod commit;
+pub mod compaction;
 pub mod configuration;
+pub mod cursor;
+pub mod flush;
+pub mod levels;
+pub mod manifest;
 pub mod memtable;
+pub mod merge;
+pub mod recovery;
+pub mod snapshot;
 pub mod sstable;
+pub mod tables;
 pub mod wal;
 
-use memtable::BTreeMemtable;
+use batch::WriteBatch;
+use cache::{BlockCache, TableCache};
+use commit::CommitQueue;
+use compaction::Compactor;
+use cursor::{Cursor, MergingCursor};
+use flush::{Flusher, Memtables};
+use memtable::SkipListMemtable;
+use recovery::RecoveryReport;
+use snapshot::{Snapshot, Snapshots};
+use tables::Tables;
+use wal::writer::{IntervalSync, WalWriter};
 
 use self::memtable::Entry;
 
 type Result<T> = std::result::Result<T, Error>;
 
+/// Every write is tagged with a sequence number, which is greater than those of all previous writes
+pub type SequenceNumber = u64;
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error(transparent)]
     SSTableError(#[from] sstable::Error),
     #[error(transparent)]
     WalError(#[from] wal::Error),
+    #[error(transparent)]
+    ManifestError(#[from] manifest::Error),
     #[error("IoError: {0}")]
     IoError(#[from] std::io::Error),
+    #[error("LockError")]
+    LockError,
+    #[error("FlushError: memtables can't be flushed anymore after a failed flush")]
+    FlushError,
+    #[error("GroupCommitError: the write was committed in a group whose commit failed")]
+    GroupCommitError,
+}
+
+impl<T> From<std::sync::PoisonError<T>> for Error {
+    fn from(_: std::sync::PoisonError<T>) -> Self {
+        Error::LockError
+    }
 }
 
 /// The LSM implementation is comprised of some classical components.
@@ -42,115 +83,751 @@ pub enum Error {
 /// It uses an in memory index / table to have a fast C0 system for key-value pairs
 /// It uses SSTables in the C1 system to allow relatively fast look-up and very fast
 /// (io-optmized) disc access for huge amounts of data.
+///
+/// The LSM can be shared between threads. Concurrent writes are committed in groups, see the
+/// `commit` module, and groups are serialized by the lock of the WAL. Reads run in parallel
+/// to them. A write becomes visible to readers at once, when its sequence number
+/// is published after it has been applied to the memtable.
+///
+/// Full memtables are flushed to SSTables in the background, see the `flush` module.
 pub struct LSM {
     config: Configuration,
-    wal: WalWriter,
-    memtable: BTreeMemtable,
-    slabs: Vec<Slab>,
+    /// The writes that wait to be committed with the next group
+    commit_queue: CommitQueue<PendingWrite, Option<Value>>,
+    /// The lock of the WAL is held for the whole commit of a group, which orders all writes
+    wal: Arc<Mutex<WalWriter>>,
+    /// Syncs the WAL in the background, if it's synced in intervals
+    _wal_sync: Option<IntervalSync>,
+    wal_manager: Arc<wal::WalManager>,
+    /// The active memtable and the full ones that wait to be flushed
+    memtables: Arc<RwLock<Memtables>>,
+    tables: Arc<Tables>,
+    flusher: Flusher,
+    compactor: Arc<Compactor>,
+    /// The sequence number of the most recent write that is visible to readers
+    last_seq: AtomicU64,
+    snapshots: Arc<Snapshots>,
+    /// What the recovery has done when the LSM started, empty if there was nothing to recover
+    recovery_report: RecoveryReport,
+}
+
+/// Iterator over the live key value pairs of the LSM in ascending key order
+///
+/// The memtable and the SSTables are merged, where the most recent entry of a key wins.
+/// Deleted keys are skipped. The iteration ends at the `end` bound.
+///
+/// The iterator holds on to the snapshot it reads, so that the versions it
+/// sees aren't compacted away while it's running.
+pub struct Iter<'a> {
+    cursor: MergingCursor<'a>,
+    end: Bound<Key>,
+    started: bool,
+    done: bool,
+    _snapshot: Snapshot,
+}
+
+impl<'a> Iter<'a> {
+    /// Create an iterator that starts at the current position of the `cursor`
+    fn new(cursor: MergingCursor<'a>, end: Bound<Key>, snapshot: Snapshot) -> Self {
+        Iter {
+            cursor,
+            end,
+            started: false,
+            done: false,
+            _snapshot: snapshot,
+        }
+    }
+
+    fn next_record(&mut self) -> Result<Option<(Key, Value)>> {
+        loop {
+            if self.started {
+                self.cursor.next()?;
+            }
+            self.started = true;
+
+            let (key, entry) = match self.cursor.current() {
+                Some(current) => current,
+                None => return Ok(None),
+            };
+
+            let before_end = match &self.end {
+                Bound::Included(end) => key <= end,
+                Bound::Excluded(end) => key < end,
+                Bound::Unbounded => true,
+            };
+            if !before_end {
+                return Ok(None);
+            }
+
+            if let Entry::Val(value) = entry {
+                return Ok(Some((key.clone(), value.clone())));
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Result<(Key, Value)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let record = self.next_record().transpose();
+        // don't continue after the end or errors
+        if !matches!(record, Some(Ok(_))) {
+            self.done = true;
+        }
+        record
+    }
+}
+
+/// A cursor over the live key value pairs of the LSM
+///
+/// The cursor is positioned on the most recent entry of a key and skips deleted keys
+/// in the direction it moves. Like the `Iter` it holds on to the snapshot it reads.
+pub struct LiveCursor<'a> {
+    cursor: MergingCursor<'a>,
+    _snapshot: Snapshot,
 }
 
-pub type Iter<'a> = memtable::Iter<'a>;
+// the cursor moves in both directions, which can't be expressed with `Iterator`
+#[allow(clippy::should_implement_trait)]
+impl<'a> LiveCursor<'a> {
+    pub fn seek_to_first(&mut self) -> Result<()> {
+        self.cursor.seek_to_first()?;
+        self.skip_deleted(Cursor::next)
+    }
+
+    pub fn seek_to_last(&mut self) -> Result<()> {
+        self.cursor.seek_to_last()?;
+        self.skip_deleted(Cursor::prev)
+    }
+
+    /// Position the cursor at the first key that is not smaller than `k`
+    pub fn seek(&mut self, k: &Key) -> Result<()> {
+        self.cursor.seek(k)?;
+        self.skip_deleted(Cursor::next)
+    }
+
+    /// Position the cursor at the last key that is not greater than `k`
+    pub fn seek_for_prev(&mut self, k: &Key) -> Result<()> {
+        self.cursor.seek_for_prev(k)?;
+        self.skip_deleted(Cursor::prev)
+    }
+
+    pub fn next(&mut self) -> Result<()> {
+        self.cursor.next()?;
+        self.skip_deleted(Cursor::next)
+    }
+
+    pub fn prev(&mut self) -> Result<()> {
+        self.cursor.prev()?;
+        self.skip_deleted(Cursor::prev)
+    }
+
+    /// The key value pair at the position of the cursor, or `None` if the cursor is exhausted
+    pub fn current(&self) -> Option<(&Key, &Value)> {
+        match self.cursor.current() {
+            Some((key, Entry::Val(value))) => Some((key, value)),
+            _ => None,
+        }
+    }
+
+    fn skip_deleted(&mut self, step: fn(&mut MergingCursor<'a>) -> Result<()>) -> Result<()> {
+        while let Some((_, Entry::Tombstone)) = self.cursor.current() {
+            step(&mut self.cursor)?;
+        }
+        Ok(())
+    }
+}
 
 impl LSM {
     pub fn new(config: Configuration) -> Result<Self> {
-        let wal = wal::WalManager::init(&config.storage_path)?;
+        let wal_manager = wal::WalManager::init(&config.storage_path, config.wal_options())?;
 
-        let lsm = if wal.recovery_needed() {
-            Self::init_with_recovery(config, &wal)
+        let lsm = if wal_manager.recovery_needed()? {
+            Self::init_with_recovery(config, wal_manager)
         } else {
-            Self::init_clean(config, &wal)
+            Self::init_clean(config, wal_manager)
         };
 
         log::info!(target: "LSM","lsm subsystem initialized and ready");
         lsm
     }
 
-    fn init_clean(config: Configuration, wal: &wal::WalManager) -> Result<Self> {
-        let memtable = BTreeMemtable::new();
-
+    fn init_clean(config: Configuration, wal_manager: wal::WalManager) -> Result<Self> {
         log::info!(target: "LSM", "starting lsm with fresh commit log",);
 
+        let wal = wal_manager.create()?;
+        let lsm = Self::init(config, wal_manager, wal)?;
+        lsm.compactor.schedule();
+        Ok(lsm)
+    }
+
+    fn init(config: Configuration, wal_manager: wal::WalManager, wal: WalWriter) -> Result<Self> {
+        let memtables = Arc::new(RwLock::new(Memtables::new()));
+        let tables = Arc::new(Self::open_tables(&config)?);
+        let snapshots = Arc::new(Snapshots::new());
+        let compactor = Arc::new(Compactor::start(
+            tables.clone(),
+            snapshots.clone(),
+            compaction::strategy(&config),
+        )?);
+        let wal_manager = Arc::new(wal_manager);
+        let flusher = Flusher::start(
+            memtables.clone(),
+            tables.clone(),
+            snapshots.clone(),
+            compactor.clone(),
+            wal_manager.clone(),
+            config.max_immutable_memtables,
+        )?;
+
+        let wal = Arc::new(Mutex::new(wal));
+        let wal_sync = match config.wal_sync_policy {
+            wal::SyncPolicy::Interval(interval) => {
+                Some(IntervalSync::start(wal.clone(), interval)?)
+            }
+            _ => None,
+        };
+
         Ok(LSM {
             config,
-            wal: wal.create()?,
-            memtable,
-            slabs: Vec::new(),
+            commit_queue: CommitQueue::new(),
+            wal,
+            _wal_sync: wal_sync,
+            wal_manager,
+            memtables,
+            last_seq: AtomicU64::new(tables.last_sequence()?),
+            tables,
+            flusher,
+            compactor,
+            snapshots,
+            recovery_report: RecoveryReport::default(),
         })
     }
 
-    fn init_with_recovery(config: Configuration, wal: &wal::WalManager) -> Result<Self> {
+    fn open_tables(config: &Configuration) -> Result<Tables> {
+        let block_cache = Arc::new(BlockCache::new(config.block_cache_size.as_u64() as usize));
+        let table_cache = TableCache::new(
+            config.table_cache_capacity,
+            config.read_options(),
+            block_cache,
+        );
+        Tables::open(&config.storage_path, config.writer_options(), table_cache)
+    }
+
+    /// Recovery replays the WAL segments into the memtable and finishes with a checkpoint.
+    /// That is, the recovered memtable is written to an SSTable and the
+    /// WAL starts out empty again.
+    fn init_with_recovery(config: Configuration, wal_manager: wal::WalManager) -> Result<Self> {
         log::info!(target: "LSM", "starting recovery from WAL");
 
-        let memtable = BTreeMemtable::new();
-        let mut lsm_for_repair = LSM {
-            config,
-            wal: wal.null()?,
-            memtable,
-            slabs: Vec::new(),
-        };
+        let wal = wal_manager.null()?;
+        let mut lsm = Self::init(config, wal_manager, wal)?;
 
-        Self::recover(&mut lsm_for_repair, &wal)?;
-        log::info!(target: "LSM", "recovery completed successfully");
+        lsm.recovery_report = Self::recover(&mut lsm)?;
+        lsm.write_active_memtable()?;
+        // all records of the WAL segments are in SSTables now
+        *lsm.wal.lock()? = lsm.wal_manager.create()?;
+        lsm.wal_manager.remove_before(lsm.wal_manager.newest())?;
+        lsm.compactor.schedule();
+        log::info!(target: "LSM", "recovery completed successfully: {}", lsm.recovery_report);
 
-        Ok(LSM {
-            wal: wal.resume()?,
-            ..lsm_for_repair
-        })
+        Ok(lsm)
     }
 
-    fn recover(lsm: &mut Self, wal: &wal::WalManager) -> Result<()> {
-        let reader = wal.open()?;
+    /// Replay the operations of the WAL segments, from oldest to newest
+    ///
+    /// Operations are applied to the memtable directly, since they're already in the WAL.
+    /// They keep the sequence numbers they were written with.
+    /// Every record is read completely before any of its operations are applied,
+    /// so a batch whose record is incomplete isn't applied at all.
+    ///
+    /// If the memtable fills up during the replay, it is written to an SSTable
+    /// but the segments are kept around until the replay is complete.
+    ///
+    /// Damaged WALs are handled according to the `RecoveryMode` of the configuration.
+    /// A torn record at the end of a segment is left behind by a crash while it was written,
+    /// so it's cut off unless absolute consistency is required. Corrupted records before
+    /// the end of a segment fail the recovery, unless they are to be skipped.
+    fn recover(lsm: &mut Self) -> Result<RecoveryReport> {
+        let mode = lsm.config.recovery_mode;
+        let mut report = RecoveryReport::default();
 
-        for result_of_op in reader {
-            match result_of_op? {
-                wal::Operation::Set(key, value) => {
-                    lsm.set(key, value)?;
-                    ()
+        for mut reader in lsm.wal_manager.open()? {
+            while let Some(record) = reader.next() {
+                let (first_seq, ops) = match record {
+                    Ok(record) => record,
+                    Err(wal::Error::CorruptedRecord(offset))
+                        if mode == RecoveryMode::SkipCorruptedRecords =>
+                    {
+                        log::warn!(target: "LSM", "skipping the corrupted record at offset {} of {:?}", offset, reader.path());
+                        report.skipped.push((reader.path().to_path_buf(), offset));
+                        continue;
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+
+                let memtable = lsm.active_memtable()?;
+                for (seq, op) in (first_seq..).zip(ops) {
+                    Self::apply(&memtable, seq, op);
+                    lsm.last_seq.fetch_max(seq, Ordering::Release);
+                    report.replayed += 1;
+                }
+
+                if lsm.memtable_is_full()? {
+                    lsm.write_active_memtable()?;
                 }
-                wal::Operation::Delete(key) => {
-                    lsm.del(&key)?;
-                    ()
+            }
+
+            if let Some(offset) = reader.torn_tail() {
+                if mode == RecoveryMode::AbsoluteConsistency {
+                    return Err(wal::Error::TornRecord(offset).into());
                 }
+                reader.truncate_torn_tail()?;
+                log::warn!(target: "LSM", "cut off the damaged tail at offset {} of {:?}", offset, reader.path());
+                report.truncated.push((reader.path().to_path_buf(), offset));
             }
         }
 
-        Ok(())
+        Ok(report)
     }
 
-    pub fn set(&mut self, k: Key, v: Value) -> Result<Option<Value>> {
-        self.wal.write(wal::Operation::Set(&k, &v))?;
-        Ok(self.memtable.insert(k, v).and_then(|value| match value {
-            Entry::Val(v) => Some(v),
-            _ => None,
-        }))
+    pub fn set(&self, k: Key, v: Value) -> Result<Option<Value>> {
+        let mut batch = WriteBatch::new();
+        batch.set(k, v);
+        self.commit(PendingWrite {
+            batch,
+            previous: true,
+        })
     }
 
-    pub fn del(&mut self, k: &Key) -> Result<Option<Value>> {
-        self.wal.write(wal::Operation::Delete(k))?;
-        Ok(self.memtable.remove(k).and_then(|value| match value {
-            Entry::Val(v) => Some(v),
-            _ => None,
-        }))
+    pub fn del(&self, k: &Key) -> Result<Option<Value>> {
+        let mut batch = WriteBatch::new();
+        batch.del(k.clone());
+        self.commit(PendingWrite {
+            batch,
+            previous: true,
+        })
+    }
+
+    /// Apply all operations of the `batch` as a single write
+    ///
+    /// The batch is written to the WAL as one record and then applied to the memtable,
+    /// before the memtable might be flushed. Reads either see all of the batch or none of it.
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.commit(PendingWrite {
+            batch,
+            previous: false,
+        })
+        .map(|_| ())
+    }
+
+    /// Apply all operations of the `batch` as a single write, if the `precondition` holds
+    ///
+    /// The precondition is checked while no other write can happen, so the state it reads
+    /// is still the most recent one when the batch is applied. Therefore the batch is
+    /// committed on its own rather than in a group with concurrent writes.
+    /// Returns whether the batch has been applied.
+    pub fn write_if<F>(&self, batch: WriteBatch, precondition: F) -> Result<bool>
+    where
+        F: FnOnce(&Self) -> Result<bool>,
+    {
+        self.commit_queue.exclusive(|| {
+            if !precondition(self)? {
+                return Ok(false);
+            }
+            if !batch.is_empty() {
+                self.commit_group(vec![PendingWrite {
+                    batch,
+                    previous: false,
+                }])?;
+            }
+            Ok(true)
+        })
+    }
+
+    /// Commit the `write`, possibly in a group with concurrent writes
+    ///
+    /// Returns once the write is durable and visible to readers.
+    fn commit(&self, write: PendingWrite) -> Result<Option<Value>> {
+        self.commit_queue
+            .commit(write, |writes| self.commit_group(writes))
+    }
+
+    /// Commit a group of writes, in the given order
+    ///
+    /// Every write is appended to the WAL as a record of its own, but all records are
+    /// committed together. Afterwards the writes are applied to the memtable
+    /// and become visible to readers at once.
+    /// Returns the values that the writes replaced, for those that ask for it.
+    ///
+    /// Everything that can fail happens before the WAL is committed, so a group
+    /// that is durable is always applied and published as well.
+    fn commit_group(&self, writes: Vec<PendingWrite>) -> Result<Vec<Option<Value>>> {
+        let mut writer = self.wal.lock()?;
+        // the memtable couldn't be flushed once it's full
+        self.flusher.check()?;
+        let memtable = self.active_memtable()?;
+        let replaced = self.replaced_values(&memtable, &writes)?;
+
+        let first_seq = self.next_seq();
+        let mut seq = first_seq;
+        for write in &writes {
+            writer.append(seq, write.batch.operations())?;
+            seq += write.batch.len() as SequenceNumber;
+        }
+        writer.commit()?;
+
+        let mut seq = first_seq;
+        for op in writes.into_iter().flat_map(|write| write.batch) {
+            match op {
+                wal::Operation::Set(key, v) => memtable.insert(key, seq, v),
+                wal::Operation::Delete(key) => memtable.remove(&key, seq),
+            };
+            seq += 1;
+        }
+        // all writes of the group become visible at once
+        self.publish(seq - 1);
+
+        // the writes have been applied, so a failed rotation isn't their error.
+        // It's retried by the next group, unless the flusher has failed and rejects it.
+        let rotated = self.memtable_is_full().and_then(|full| {
+            if full {
+                self.rotate_memtable(&mut writer)
+            } else {
+                self.wal_manager.rotate_if_full(&mut writer)?;
+                Ok(())
+            }
+        });
+        if let Err(e) = rotated {
+            log::error!(target: "LSM", "failed to continue with a new WAL segment: {:?}", e);
+        }
+
+        Ok(replaced)
+    }
+
+    /// The values that the `writes` of a group replace, for those that ask for it
+    ///
+    /// A write replaces the value of an earlier write in the same group, or else
+    /// the value in the active `memtable`. A key that is not in the memtable
+    /// might have a value that is older than it, which is looked up as well.
+    fn replaced_values(
+        &self,
+        memtable: &SkipListMemtable,
+        writes: &[PendingWrite],
+    ) -> Result<Vec<Option<Value>>> {
+        if !writes.iter().any(|write| write.previous) {
+            return Ok(vec![None; writes.len()]);
+        }
+        let last_seq = self.next_seq() - 1;
+        let mut written: BTreeMap<&Key, Entry> = BTreeMap::new();
+        let mut replaced = Vec::with_capacity(writes.len());
+        for PendingWrite { batch, previous } in writes {
+            let mut value = None;
+            for op in batch.operations() {
+                let (key, entry) = match op {
+                    wal::Operation::Set(key, v) => (key, Entry::Val(v.clone())),
+                    wal::Operation::Delete(key) => (key, Entry::Tombstone),
+                };
+                if *previous {
+                    let current = written
+                        .get(key)
+                        .cloned()
+                        .or_else(|| memtable.entry(key, SequenceNumber::MAX));
+                    value = match (current, op) {
+                        (None, _) => self.get_at_seq(key, last_seq)?,
+                        (Some(Entry::Val(v)), _) => Some(v),
+                        _ => None,
+                    };
+                }
+                written.insert(key, entry);
+            }
+            replaced.push(value);
+        }
+        Ok(replaced)
     }
 
     pub fn get(&self, k: &Key) -> Result<Option<Value>> {
-        Ok(self.get_c0(k).or(self.get_c1(k)?))
+        self.get_at(k, &self.snapshot()?)
+    }
+
+    /// Lookup the value of the key as it was when the `snapshot` was taken
+    pub fn get_at(&self, k: &Key, snapshot: &Snapshot) -> Result<Option<Value>> {
+        self.get_at_seq(k, snapshot.sequence())
+    }
+
+    /// Take a snapshot of the current state
+    ///
+    /// Reads through the snapshot don't see any writes that happen after it was taken.
+    /// Compactions keep the data that the snapshot needs until it's dropped.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        self.snapshots.acquire_latest(&self.last_seq)
+    }
+
+    /// Iterate over all key value pairs in C0 and C1
+    pub fn iter(&self) -> Result<EngineIterator<'_>> {
+        self.range(..)
+    }
+
+    /// Iterate over all key value pairs as they were when the `snapshot` was taken
+    pub fn iter_at(&self, snapshot: &Snapshot) -> Result<EngineIterator<'_>> {
+        self.range_at(.., snapshot)
+    }
+
+    /// Create a cursor over all key value pairs in C0 and C1
+    ///
+    /// The cursor is exhausted until it's positioned with one of its seek methods.
+    pub fn cursor(&self) -> Result<LiveCursor<'_>> {
+        self.cursor_with(self.snapshot()?)
+    }
+
+    /// Create a cursor over all key value pairs as they were when the `snapshot` was taken
+    pub fn cursor_at(&self, snapshot: &Snapshot) -> Result<LiveCursor<'_>> {
+        self.cursor_with(self.retain(snapshot)?)
+    }
+
+    /// Iterate over the key value pairs with keys in the `range`
+    ///
+    /// Only the SSTables that intersect the range are consulted.
+    pub fn range<R: RangeBounds<Key>>(&self, range: R) -> Result<EngineIterator<'_>> {
+        self.range_with(range, self.snapshot()?)
+    }
+
+    /// Iterate over the key value pairs with keys in the `range` as they were when the `snapshot` was taken
+    pub fn range_at<R: RangeBounds<Key>>(
+        &self,
+        range: R,
+        snapshot: &Snapshot,
+    ) -> Result<EngineIterator<'_>> {
+        self.range_with(range, self.retain(snapshot)?)
+    }
+
+    /// Iterate over the key value pairs with keys that start with `prefix`
+    pub fn scan_prefix(&self, prefix: &Key) -> Result<EngineIterator<'_>> {
+        self.range((Bound::Included(prefix.clone()), prefix_end(prefix)))
     }
 
-    pub fn iter(&self) -> EngineIterator {
-        EngineIterator::new(self.memtable.iter())
+    /// Iterate over the key value pairs with keys that start with `prefix` as they were when the `snapshot` was taken
+    pub fn scan_prefix_at(&self, prefix: &Key, snapshot: &Snapshot) -> Result<EngineIterator<'_>> {
+        self.range_at(
+            (Bound::Included(prefix.clone()), prefix_end(prefix)),
+            snapshot,
+        )
     }
 
-    fn get_c0(&self, k: &Key) -> Option<Value> {
-        self.memtable.get(k).cloned()
+    /// Register another snapshot at the sequence number of `snapshot`,
+    /// which lives as long as the reader that uses it
+    fn retain(&self, snapshot: &Snapshot) -> Result<Snapshot> {
+        self.snapshots.acquire(snapshot.sequence())
     }
 
-    fn get_c1(&self, k: &Key) -> Result<Option<Value>> {
-        let idx = self.slabs.binary_search_by(|f| f.cmp(&k));
+    fn get_at_seq(&self, k: &Key, seq: SequenceNumber) -> Result<Option<Value>> {
+        // the memtables are read before the tables. A flush adds its table before
+        // its memtable leaves the queue, so the entries are found in either of them.
+        for memtable in self.memtables()? {
+            match memtable.entry(k, seq) {
+                Some(Entry::Val(v)) => return Ok(Some(v)),
+                Some(Entry::Tombstone) => return Ok(None),
+                None => continue,
+            }
+        }
+        self.get_c1(k, seq)
+    }
+
+    /// The sequence number of the most recent write of the key, if it has ever been written
+    ///
+    /// Versions that are newer than the oldest live snapshot are never compacted away,
+    /// so a write after a snapshot is always found.
+    pub fn latest_seq(&self, k: &Key) -> Result<Option<SequenceNumber>> {
+        for memtable in self.memtables()? {
+            if let Some(seq) = memtable.latest_seq(k) {
+                return Ok(Some(seq));
+            }
+        }
+        self.tables.latest_seq(k)
+    }
+
+    fn cursor_with(&self, snapshot: Snapshot) -> Result<LiveCursor<'_>> {
+        Ok(LiveCursor {
+            cursor: self.merging_cursor(&.., snapshot.sequence())?,
+            _snapshot: snapshot,
+        })
+    }
+
+    fn range_with<R: RangeBounds<Key>>(
+        &self,
+        range: R,
+        snapshot: Snapshot,
+    ) -> Result<EngineIterator<'_>> {
+        let mut cursor = self.merging_cursor(&range, snapshot.sequence())?;
+        match range.start_bound() {
+            Bound::Included(start) => cursor.seek(start)?,
+            Bound::Excluded(start) => {
+                cursor.seek(start)?;
+                if matches!(cursor.current(), Some((key, _)) if key == start) {
+                    cursor.next()?;
+                }
+            }
+            Bound::Unbounded => cursor.seek_to_first()?,
+        }
 
-        match idx {
-            Ok(idx) => Ok(self.slabs[idx].sstable()?.get(&k)?),
-            Err(_) => Ok(None),
+        Ok(EngineIterator::new(Iter::new(
+            cursor,
+            range.end_bound().cloned(),
+            snapshot,
+        )))
+    }
+
+    /// Merge the entries of the memtables and the SSTables that intersect the `range`,
+    /// which are visible at the sequence number `seq`
+    fn merging_cursor<R: RangeBounds<Key>>(
+        &self,
+        range: &R,
+        seq: SequenceNumber,
+    ) -> Result<MergingCursor<'_>> {
+        let mut sources: Vec<Box<dyn Cursor>> = Vec::new();
+        for memtable in self.memtables()? {
+            sources.push(Box::new(memtable.cursor(seq)));
+        }
+        for table in self.tables.open_in_range(range)? {
+            sources.push(Box::new(table.cursor(seq)));
+        }
+        Ok(MergingCursor::new(sources))
+    }
+
+    /// The number of live SSTables per level
+    pub fn tables_per_level(&self) -> Result<Vec<usize>> {
+        let levels = self.tables.levels()?;
+        Ok((0..levels.depth())
+            .map(|level| levels.level(level as sstable::Level).len())
+            .collect())
+    }
+
+    /// The hit and miss counters of the table and block cache
+    pub fn cache_stats(&self) -> Result<cache::Statistics> {
+        self.tables.cache_stats()
+    }
+
+    /// Check the checksums of all blocks of the live SSTables
+    ///
+    /// Returns the ranges of the blocks that are corrupted, which is empty if all tables are intact.
+    pub fn verify_all(&self) -> Result<Vec<sstable::CorruptedRange>> {
+        self.tables.verify()
+    }
+
+    /// What the recovery from the WALs has done when the LSM started
+    pub fn recovery_report(&self) -> &RecoveryReport {
+        &self.recovery_report
+    }
+
+    /// Apply an operation that is already in the WAL to the memtable
+    fn apply(memtable: &SkipListMemtable, seq: SequenceNumber, op: wal::Operation<Key, Value>) {
+        match op {
+            wal::Operation::Set(key, value) => memtable.insert(key, seq, value),
+            wal::Operation::Delete(key) => memtable.remove(&key, seq),
+        };
+    }
+
+    /// The sequence number of the next write, which must only be used while the WAL is locked
+    fn next_seq(&self) -> SequenceNumber {
+        self.last_seq.load(Ordering::Acquire) + 1
+    }
+
+    /// Make the writes up to the sequence number `seq` visible to readers
+    fn publish(&self, seq: SequenceNumber) {
+        self.last_seq.store(seq, Ordering::Release);
+    }
+
+    /// Flush all memtables to SSTables and wait until they are written
+    pub fn flush(&self) -> Result<()> {
+        self.rotate_memtable(&mut *self.wal.lock()?)?;
+        self.flusher.wait()
+    }
+
+    fn active_memtable(&self) -> Result<Arc<SkipListMemtable>> {
+        Ok(self.memtables.read()?.active.clone())
+    }
+
+    /// All memtables from newest to oldest
+    fn memtables(&self) -> Result<Vec<Arc<SkipListMemtable>>> {
+        Ok(self.memtables.read()?.all())
+    }
+
+    fn memtable_is_full(&self) -> Result<bool> {
+        Ok(self.active_memtable()?.size() as u64 >= self.config.max_memtable_size.as_u64())
+    }
+
+    /// Hand the active memtable over to the background flush and continue with an empty one
+    ///
+    /// The `writer` is the locked WAL, which is synced and continues with a new segment.
+    /// The older segments are removed once the memtable has been flushed.
+    /// This blocks while the queue of immutable memtables is full.
+    /// Nothing changes if the flusher has failed or the new segment can't be created.
+    fn rotate_memtable(&self, writer: &mut WalWriter) -> Result<()> {
+        writer.check()?;
+        self.flusher.check()?;
+        if self.active_memtable()?.is_empty() {
+            return Ok(());
+        }
+        writer.sync_pending()?;
+
+        // only the WAL lock that is held by the caller swaps the active memtable
+        let segment = self.wal_manager.create()?;
+        let full = {
+            let mut memtables = self.memtables.write()?;
+            let full = std::mem::replace(&mut memtables.active, Arc::new(SkipListMemtable::new()));
+            memtables.immutable.push_front(full.clone());
+            full
+        };
+        *writer = segment;
+        self.flusher.schedule(full, self.wal_manager.newest())
+    }
+
+    /// Write the active memtable to an SSTable right away and start with a fresh memtable
+    ///
+    /// This is used during recovery, when there are no concurrent readers or writers yet.
+    fn write_active_memtable(&self) -> Result<()> {
+        let memtable = self.active_memtable()?;
+        if flush::write_memtable(&memtable, &self.tables, &self.snapshots)? {
+            self.compactor.schedule();
+        }
+        self.memtables.write()?.active = Arc::new(SkipListMemtable::new());
+        Ok(())
+    }
+
+    fn get_c1(&self, k: &Key, seq: SequenceNumber) -> Result<Option<Value>> {
+        match self.tables.get(k, seq)? {
+            Some(Entry::Val(v)) => Ok(Some(v)),
+            Some(Entry::Tombstone) | None => Ok(None),
+        }
+    }
+}
+
+/// A write that waits to be committed
+struct PendingWrite {
+    batch: WriteBatch,
+    /// Whether the write returns the value it replaced, which is only used for single operations
+    previous: bool,
+}
+
+/// The bound that ends a scan of all keys starting with `prefix`
+///
+/// That's the smallest key that is greater than all keys with the prefix,
+/// unless the prefix consists of `0xff` bytes only, which leaves the scan unbounded.
+fn prefix_end(prefix: &Key) -> Bound<Key> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Bound::Excluded(Key::new(end));
         }
     }
+    Bound::Unbounded
 }
@@ -0,0 +1,58 @@
+//! Atomic write batches
+//!
+//! A batch collects several set and delete operations, which are applied as one write.
+//! The whole batch is written as a single record to the WAL, so that a crash never
+//! leaves a batch half applied. Readers either see all operations of a batch or none of them.
+use super::wal::Operation;
+use crate::engine::{Key, Value};
+
+/// A batch of set and delete operations that are applied atomically
+///
+/// Operations are applied in the order they have been added to the batch,
+/// so a later operation on the same key wins.
+#[derive(Debug, Default)]
+pub struct WriteBatch {
+    operations: Vec<Operation<Key, Value>>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    /// Add the insertion of a key value pair to the batch
+    pub fn set<K: Into<Key>, V: Into<Value>>(&mut self, key: K, value: V) -> &mut Self {
+        self.operations
+            .push(Operation::Set(key.into(), value.into()));
+        self
+    }
+
+    /// Add the deletion of a key to the batch
+    pub fn del<K: Into<Key>>(&mut self, key: K) -> &mut Self {
+        self.operations.push(Operation::Delete(key.into()));
+        self
+    }
+
+    /// The number of operations in the batch
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    /// The operations of the batch in the order they have been added
+    pub fn operations(&self) -> &[Operation<Key, Value>] {
+        &self.operations
+    }
+}
+
+impl IntoIterator for WriteBatch {
+    type Item = Operation<Key, Value>;
+    type IntoIter = std::vec::IntoIter<Operation<Key, Value>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.operations.into_iter()
+    }
+}
@@ -42,11 +42,7 @@ where
     W: io::Write,
     D: serde::Serialize,
 {
-    let serialized = bincode::serialize(&data).map_err(|e| {
-        error!("serialization of data frame failed: {:?}", e.as_ref());
-        Error::SerializationError
-    })?;
-
+    let serialized = serialize(data)?;
     write_frame(w, &serialized)
 }
 
@@ -58,11 +54,7 @@ where
     let frame_size = read_frame(r, buf)?;
     trace!("read data frame successfully. size: {} bytes", frame_size);
 
-    let value = bincode::deserialize(buf.as_slice()).map_err(|e| {
-        error!("deserialization of data frame failed: {:?}", e.as_ref());
-        Error::SerializationError
-    })?;
-    Ok(value)
+    deserialize(buf.as_slice())
 }
 
 pub fn read_data_owned<R, D>(r: &mut R) -> Result<D>
@@ -73,11 +65,29 @@ where
     let mut buf = Vec::new();
     let frame_size = read_frame(r, &mut buf)?;
     trace!("read data frame successfully. size: {} bytes", frame_size);
-    let value = bincode::deserialize(buf.as_slice()).map_err(|e| {
+    deserialize(buf.as_slice())
+}
+
+/// Serialize the data without framing it
+pub fn serialize<D>(data: D) -> Result<Vec<u8>>
+where
+    D: serde::Serialize,
+{
+    bincode::serialize(&data).map_err(|e| {
+        error!("serialization of data frame failed: {:?}", e.as_ref());
+        Error::SerializationError
+    })
+}
+
+/// Deserialize data that has been read without its frame
+pub fn deserialize<'a, D>(buf: &'a [u8]) -> Result<D>
+where
+    D: serde::de::Deserialize<'a>,
+{
+    bincode::deserialize(buf).map_err(|e| {
         error!("deserialization of data frame failed: {:?}", e.as_ref());
         Error::SerializationError
-    })?;
-    Ok(value)
+    })
 }
 
 pub fn read_frame<R>(reader: &mut R, buf: &mut Vec<u8>) -> Result<usize>
@@ -87,7 +97,11 @@ where
     trace!("read data size tag: {} bytes", LENGTH_TAG_SIZE);
     let size = read_data_size(reader)?;
     trace!("read data frame: {} bytes", size);
-    reader.take(size as u64).read_to_end(buf)?;
+    let read = reader.take(size as u64).read_to_end(buf)?;
+    if read < size as usize {
+        // the frame has been cut off, e.g. by a crash while it was written
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "incomplete data frame").into());
+    }
     Ok((size as i32 + (LENGTH_TAG_SIZE as i32)) as usize)
 }
 
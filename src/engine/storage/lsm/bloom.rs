@@ -0,0 +1,118 @@
+//! Bloom filters for SSTables
+//!
+//! A bloom filter is a compact probabilistic set. It can tell for sure that a key
+//! is not part of an SSTable, which allows lookups to skip the table entirely.
+//! If the filter reports that the key might be in the table, it's wrong at the
+//! configured false positive rate.
+//!
+//! The filter is persisted with the SSTable, so the hash function must never change.
+//! That's why it uses its own hash rather than the one of the standard library.
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    hash_count: u32,
+}
+
+impl BloomFilter {
+    /// Create an empty filter for the `expected_items` with the given `false_positive_rate`
+    pub fn new(expected_items: usize, false_positive_rate: f64) -> Self {
+        let items = expected_items.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let bit_count = (-items * false_positive_rate.ln() / (ln2 * ln2))
+            .ceil()
+            .max(8.0);
+        let hash_count = ((bit_count / items) * ln2).round().max(1.0);
+
+        BloomFilter {
+            bits: vec![0; (bit_count as usize).div_ceil(8)],
+            hash_count: hash_count as u32,
+        }
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        self.insert_hash(hash(key))
+    }
+
+    /// Insert a key by its `hash`, which allows to collect the hashes before the filter is sized
+    pub fn insert_hash(&mut self, hash: u64) {
+        for bit in self.bit_positions(hash) {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    /// Returns false if the key has definitely not been inserted
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.bit_positions(hash(key))
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    /// Double hashing derives all positions from two halves of a single hash
+    fn bit_positions(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let (h1, h2) = (hash as u32, (hash >> 32) as u32);
+        let bit_count = (self.bits.len() * 8) as u64;
+
+        (0..self.hash_count).map(move |i| {
+            let combined = (h1 as u64).wrapping_add((i as u64).wrapping_mul(h2 as u64));
+            (combined % bit_count) as usize
+        })
+    }
+}
+
+impl fmt::Debug for BloomFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BloomFilter")
+            .field("bits", &(self.bits.len() * 8))
+            .field("hash_count", &self.hash_count)
+            .finish()
+    }
+}
+
+/// FNV-1a followed by the splitmix64 finalizer to spread the bits
+pub fn hash(key: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in key {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BloomFilter;
+
+    #[test]
+    fn inserted_keys_are_always_found() {
+        let mut filter = BloomFilter::new(1000, 0.01);
+        for i in 0..1000 {
+            filter.insert(format!("key-{}", i).as_bytes());
+        }
+
+        for i in 0..1000 {
+            assert!(filter.may_contain(format!("key-{}", i).as_bytes()));
+        }
+    }
+
+    #[test]
+    fn false_positive_rate_is_respected() {
+        let mut filter = BloomFilter::new(1000, 0.01);
+        for i in 0..1000 {
+            filter.insert(format!("key-{}", i).as_bytes());
+        }
+
+        let false_positives = (0..10000)
+            .filter(|i| filter.may_contain(format!("other-{}", i).as_bytes()))
+            .count();
+        assert!(
+            false_positives < 200,
+            "too many false positives: {}",
+            false_positives
+        );
+    }
+}
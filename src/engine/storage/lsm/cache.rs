@@ -0,0 +1,275 @@
+//! Caches for SSTable reads
+//!
+//! Every lookup in C1 has to open the candidate tables and read a data block from each of them.
+//! Two caches avoid most of this work for frequently accessed data:
+//!
+//! * The `TableCache` keeps a bounded number of SSTables open, so that their
+//!   trailer and sparse index don't have to be read again for every lookup.
+//! * The `BlockCache` keeps recently read data blocks in memory. It's bounded by the
+//!   total size of the cached blocks and keyed by the file and offset of a block.
+//!
+//! Both caches evict the least recently used entries first and count their hits and misses.
+use super::sstable::{Block, ReadOptions, SSTable, Slab};
+use super::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::path;
+use std::sync::{Arc, Mutex};
+
+/// The number of hits and misses of a cache
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// The statistics of the caches of the LSM
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Statistics {
+    pub table_cache: CacheStats,
+    pub block_cache: CacheStats,
+}
+
+/// A cache that evicts the least recently used entries once its capacity is exceeded
+///
+/// Every entry is inserted with a charge, which is accounted against the capacity.
+/// This allows to bound the cache by the number of entries (a charge of 1 each)
+/// as well as by the size of the entries.
+pub struct LruCache<K, V> {
+    capacity: usize,
+    usage: usize,
+    tick: u64,
+    entries: HashMap<K, CacheEntry<V>>,
+    /// The keys by the time of their last use, least recently used first
+    recency: BTreeMap<u64, K>,
+    stats: CacheStats,
+}
+
+struct CacheEntry<V> {
+    value: V,
+    charge: usize,
+    tick: u64,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> LruCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        LruCache {
+            capacity,
+            usage: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Lookup the value for `k` and mark it as the most recently used
+    pub fn get(&mut self, k: &K) -> Option<V> {
+        self.tick += 1;
+        match self.entries.get_mut(k) {
+            Some(entry) => {
+                self.recency.remove(&entry.tick);
+                self.recency.insert(self.tick, k.clone());
+                entry.tick = self.tick;
+                self.stats.hits += 1;
+                Some(entry.value.clone())
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Lookup the value for `k` without marking it as used or counting the lookup
+    pub fn peek(&self, k: &K) -> Option<V> {
+        self.entries.get(k).map(|entry| entry.value.clone())
+    }
+
+    /// Insert the `value` for `k`, evicting the least recently used entries if needed
+    ///
+    /// Values with a charge that exceeds the capacity are not cached at all.
+    pub fn insert(&mut self, k: K, value: V, charge: usize) {
+        self.remove(&k);
+        if charge > self.capacity {
+            return;
+        }
+
+        while self.usage + charge > self.capacity {
+            match self.recency.keys().next().cloned() {
+                Some(oldest) => {
+                    let key = self.recency[&oldest].clone();
+                    self.remove(&key);
+                }
+                None => break,
+            }
+        }
+
+        self.tick += 1;
+        self.usage += charge;
+        self.recency.insert(self.tick, k.clone());
+        self.entries.insert(
+            k,
+            CacheEntry {
+                value,
+                charge,
+                tick: self.tick,
+            },
+        );
+    }
+
+    pub fn remove(&mut self, k: &K) -> Option<V> {
+        let entry = self.entries.remove(k)?;
+        self.recency.remove(&entry.tick);
+        self.usage -= entry.charge;
+        Some(entry.value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The sum of the charges of all cached entries
+    pub fn usage(&self) -> usize {
+        self.usage
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+}
+
+type BlockKey = (path::PathBuf, usize);
+
+/// Cache of data blocks, bounded by their size in bytes
+///
+/// The cache only holds copies of data that is on disk. That's why a poisoned cache
+/// is bypassed rather than failing the read.
+pub struct BlockCache {
+    blocks: Mutex<LruCache<BlockKey, Arc<Block>>>,
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> Self {
+        BlockCache {
+            blocks: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    /// The block of the table at `path` that starts at `offset`
+    pub fn get(&self, path: &path::Path, offset: usize) -> Option<Arc<Block>> {
+        self.blocks.lock().ok()?.get(&(path.to_path_buf(), offset))
+    }
+
+    pub fn insert(&self, path: &path::Path, offset: usize, block: Arc<Block>, size: usize) {
+        if let Ok(mut blocks) = self.blocks.lock() {
+            blocks.insert((path.to_path_buf(), offset), block, size);
+        }
+    }
+
+    pub fn stats(&self) -> Result<CacheStats> {
+        Ok(self.blocks.lock()?.stats())
+    }
+}
+
+/// Cache of open SSTables, bounded by the number of tables
+///
+/// Tables that are opened by this cache read their blocks through the block cache,
+/// according to the `read_options`. An open table is shared by all reads of it.
+pub struct TableCache {
+    tables: Mutex<LruCache<path::PathBuf, Arc<SSTable>>>,
+    read_options: ReadOptions,
+    block_cache: Arc<BlockCache>,
+}
+
+impl TableCache {
+    pub fn new(capacity: usize, read_options: ReadOptions, block_cache: Arc<BlockCache>) -> Self {
+        TableCache {
+            tables: Mutex::new(LruCache::new(capacity)),
+            read_options,
+            block_cache,
+        }
+    }
+
+    /// The open SSTable of the `slab`, which is opened if it isn't cached yet
+    ///
+    /// The table is opened without holding the lock of the cache, so that lookups
+    /// of other tables don't wait for it. If a concurrent lookup has opened
+    /// the table in the meantime, its table is used.
+    pub fn get(&self, slab: &Slab) -> Result<Arc<SSTable>> {
+        let path = slab.path().to_path_buf();
+        if let Some(table) = self.tables.lock()?.get(&path) {
+            return Ok(table);
+        }
+
+        let table = Arc::new(slab.sstable_with_cache(self.read_options, &self.block_cache)?);
+        let mut tables = self.tables.lock()?;
+        if let Some(opened) = tables.peek(&path) {
+            return Ok(opened);
+        }
+        tables.insert(path, table.clone(), 1);
+        Ok(table)
+    }
+
+    /// Close the table at `path`, which must be done before its file is removed
+    pub fn evict(&self, path: &path::Path) -> Result<()> {
+        self.tables.lock()?.remove(&path.to_path_buf());
+        Ok(())
+    }
+
+    pub fn stats(&self) -> Result<Statistics> {
+        Ok(Statistics {
+            table_cache: self.tables.lock()?.stats(),
+            block_cache: self.block_cache.stats()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CacheStats, LruCache};
+
+    #[test]
+    fn evicts_least_recently_used_entries() {
+        let mut cache = LruCache::new(3);
+        cache.insert("a", 1, 1);
+        cache.insert("b", 2, 1);
+        cache.insert("c", 3, 1);
+
+        // touching "a" makes "b" the least recently used entry
+        assert_eq!(cache.get(&"a"), Some(1));
+        cache.insert("d", 4, 1);
+
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.get(&"a"), Some(1));
+        assert_eq!(cache.get(&"c"), Some(3));
+        assert_eq!(cache.get(&"d"), Some(4));
+        assert_eq!(cache.stats(), CacheStats { hits: 4, misses: 1 });
+    }
+
+    #[test]
+    fn respects_the_charge_of_entries() {
+        let mut cache = LruCache::new(10);
+        cache.insert("a", 1, 4);
+        cache.insert("b", 2, 4);
+        cache.insert("c", 3, 4);
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.usage(), 8);
+        assert_eq!(cache.get(&"a"), None);
+
+        // entries that are bigger than the whole cache are not cached
+        cache.insert("d", 4, 11);
+        assert_eq!(cache.get(&"d"), None);
+        assert_eq!(cache.len(), 2);
+
+        // replacing an entry releases its previous charge
+        cache.insert("b", 5, 2);
+        assert_eq!(cache.usage(), 6);
+        assert_eq!(cache.get(&"b"), Some(5));
+    }
+}
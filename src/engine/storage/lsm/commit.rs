@@ -0,0 +1,213 @@
+//! Group commit of writes
+//!
+//! Appending a record to the WAL is cheap, making it durable is what costs. When several
+//! threads write at the same time, their writes queue up while a commit is in progress.
+//! As soon as that commit is done, one of the waiting writers becomes the leader of the next
+//! group: it takes all pending writes and commits them together, with a single flush of the WAL.
+//! The other writers of the group wait until the leader is done and are then released
+//! with the outcome of their own write.
+//!
+//! Without concurrent writers every group consists of a single write, which
+//! is committed by the writer itself.
+use super::{Error, Result};
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+type Ticket = u64;
+
+/// The queue of writes that wait to be committed
+///
+/// `W` is a write and `R` is the outcome of a committed write.
+pub struct CommitQueue<W, R> {
+    state: Mutex<State<W, R>>,
+    committed: Condvar,
+}
+
+struct State<W, R> {
+    /// The writes that haven't been picked up by a leader yet, in the order they arrived
+    pending: Vec<(Ticket, W)>,
+    next_ticket: Ticket,
+    /// Whether a group is being committed right now
+    leading: bool,
+    /// The outcomes of committed writes, `None` if the commit of the group failed
+    outcomes: HashMap<Ticket, Option<R>>,
+}
+
+impl<W, R> CommitQueue<W, R> {
+    pub fn new() -> Self {
+        CommitQueue {
+            state: Mutex::new(State {
+                pending: Vec::new(),
+                next_ticket: 0,
+                leading: false,
+                outcomes: HashMap::new(),
+            }),
+            committed: Condvar::new(),
+        }
+    }
+
+    /// Commit the `write` together with the writes of other threads
+    ///
+    /// If this thread becomes the leader of a group, it calls `commit` with all writes of
+    /// the group in the order they arrived, which returns their outcomes in the same order.
+    /// Otherwise the `commit` of another thread commits the write.
+    ///
+    /// If the commit of the group fails, the leader gets the error and all other writers
+    /// of the group get `Error::GroupCommitError`.
+    pub fn commit<F>(&self, write: W, commit: F) -> Result<R>
+    where
+        F: FnOnce(Vec<W>) -> Result<Vec<R>>,
+    {
+        let mut state = self.state.lock()?;
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.pending.push((ticket, write));
+
+        while state.leading {
+            state = self.committed.wait(state)?;
+        }
+        if let Some(outcome) = state.outcomes.remove(&ticket) {
+            return outcome.ok_or(Error::GroupCommitError);
+        }
+
+        // the write is still pending, so this thread leads the next group
+        let (tickets, writes): (Vec<Ticket>, Vec<W>) =
+            std::mem::take(&mut state.pending).into_iter().unzip();
+        let mut leadership = Leadership::take(self, state);
+        let result = commit(writes);
+
+        let mut state = leadership.finish()?;
+        match result {
+            Ok(outcomes) => {
+                for (ticket, outcome) in tickets.into_iter().zip(outcomes) {
+                    state.outcomes.insert(ticket, Some(outcome));
+                }
+                state
+                    .outcomes
+                    .remove(&ticket)
+                    .flatten()
+                    .ok_or(Error::GroupCommitError)
+            }
+            Err(e) => {
+                for other in tickets.into_iter().filter(|other| *other != ticket) {
+                    state.outcomes.insert(other, None);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Run `f` while no group is committed and no other group can start
+    ///
+    /// This is used for writes that must not be grouped with others,
+    /// e.g. because they read the state before they write.
+    pub fn exclusive<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T>,
+    {
+        let mut state = self.state.lock()?;
+        while state.leading {
+            state = self.committed.wait(state)?;
+        }
+
+        let mut leadership = Leadership::take(self, state);
+        let result = f();
+        drop(leadership.finish()?);
+        result
+    }
+}
+
+impl<W, R> Default for CommitQueue<W, R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Marks that a thread commits a group, until it's finished or dropped
+///
+/// Waiting writers are woken up when the leadership ends, even if the leader panics.
+struct Leadership<'a, W, R> {
+    queue: &'a CommitQueue<W, R>,
+    finished: bool,
+}
+
+impl<'a, W, R> Leadership<'a, W, R> {
+    fn take(queue: &'a CommitQueue<W, R>, mut state: MutexGuard<'a, State<W, R>>) -> Self {
+        state.leading = true;
+        Leadership {
+            queue,
+            finished: false,
+        }
+    }
+
+    /// End the leadership, the waiting writers are woken up once the returned guard is dropped
+    fn finish(&mut self) -> Result<MutexGuard<'a, State<W, R>>> {
+        let mut state = self.queue.state.lock()?;
+        state.leading = false;
+        self.finished = true;
+        self.queue.committed.notify_all();
+        Ok(state)
+    }
+}
+
+impl<'a, W, R> Drop for Leadership<'a, W, R> {
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Ok(mut state) = self.queue.state.lock() {
+                state.leading = false;
+            }
+            self.queue.committed.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CommitQueue;
+    use crate::engine::storage::lsm::Error;
+    use std::sync::{Barrier, Mutex};
+    use std::thread;
+
+    #[test]
+    fn pending_writes_are_committed_together() {
+        let queue = CommitQueue::<u32, u32>::new();
+        let groups = Mutex::new(Vec::new());
+        let barrier = Barrier::new(8);
+
+        thread::scope(|scope| {
+            for write in 0..8 {
+                let (queue, groups, barrier) = (&queue, &groups, &barrier);
+                scope.spawn(move || {
+                    barrier.wait();
+                    let outcome = queue
+                        .commit(write, |writes| {
+                            groups.lock().unwrap().push(writes.clone());
+                            Ok(writes.iter().map(|w| w * 10).collect())
+                        })
+                        .unwrap();
+                    assert_eq!(write * 10, outcome);
+                });
+            }
+        });
+
+        let groups = groups.into_inner().unwrap();
+        let mut committed: Vec<u32> = groups.iter().flatten().copied().collect();
+        committed.sort_unstable();
+        assert_eq!((0..8).collect::<Vec<_>>(), committed);
+    }
+
+    #[test]
+    fn failed_commits_fail_all_writes_of_the_group() {
+        let queue = CommitQueue::<u32, u32>::new();
+        assert!(matches!(
+            queue.commit(1, |_| Err(Error::LockError)),
+            Err(Error::LockError)
+        ));
+        // the queue keeps working after a failed commit
+        assert_eq!(
+            4,
+            queue.commit(2, |writes| Ok(vec![writes[0] * 2])).unwrap()
+        );
+        assert_eq!(5, queue.exclusive(|| Ok(5)).unwrap());
+    }
+}
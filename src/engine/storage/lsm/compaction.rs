@@ -0,0 +1,439 @@
+//! Compaction of SSTables
+//!
+//! Every memtable flush adds another table to level 0, where tables may overlap.
+//! Without compaction every lookup would have to consult more and more tables over time.
+//! Compaction runs on a background thread and merges tables together.
+//!
+//! Which tables are merged is decided by a `Strategy`. The strategy is selected with the
+//! `compaction_strategy` of the configuration.
+//!
+//! The leveled strategy optimizes for reads and works as follows:
+//! * Level 0 is compacted once it holds `level0_compaction_trigger` tables
+//! * Level N (N > 0) is compacted once it grows beyond its maximum size, which is
+//!   `level_size_base * level_size_multiplier^(N-1)`
+//! * The level with the highest ratio of actual to allowed size is compacted first
+//! * Tables of level N are merged with the overlapping tables of level N+1 into new
+//!   tables on level N+1, which are at most `target_table_size` bytes big.
+//!
+//! The size-tiered strategy optimizes for writes and works as follows:
+//! * All tables stay on level 0
+//! * Consecutive tables (with respect to their age) of similar size form a tier
+//! * A tier is merged into a single table once it holds `size_tiered_min_threshold` tables
+//!
+//! For both strategies older versions of a key are dropped once no live snapshot can see them anymore,
+//! and tombstones are dropped once no older table might contain the key.
+use super::configuration::{CompactionStrategy, Configuration};
+use super::levels::Levels;
+use super::merge::MergingIterator;
+use super::snapshot::Snapshots;
+use super::sstable::{self, Level, SSTable, Slab};
+use super::tables::Tables;
+use super::{Result, SequenceNumber};
+use crate::engine::storage::lsm::memtable::Entry;
+use crate::engine::Key;
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+/// The deepest level is `MAX_LEVELS - 1`
+const MAX_LEVELS: usize = 7;
+
+/// The compactor owns the background thread that runs compactions
+///
+/// Dropping the compactor waits for the currently running compactions to finish.
+pub struct Compactor {
+    sender: Option<mpsc::Sender<()>>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl Compactor {
+    pub fn start(
+        tables: Arc<Tables>,
+        snapshots: Arc<Snapshots>,
+        mut strategy: Box<dyn Strategy>,
+    ) -> Result<Compactor> {
+        let (sender, receiver) = mpsc::channel::<()>();
+
+        let handle = thread::Builder::new()
+            .name("compaction".into())
+            .spawn(move || {
+                while receiver.recv().is_ok() {
+                    // requests that queued up in the meantime are covered by this run
+                    while receiver.try_recv().is_ok() {}
+
+                    if let Err(e) = compact(strategy.as_mut(), &tables, &snapshots) {
+                        log::error!(target: "compaction", "compaction failed: {:?}", e);
+                    }
+                }
+            })?;
+
+        Ok(Compactor {
+            sender: Some(sender),
+            handle: Some(handle),
+        })
+    }
+
+    /// Ask the background thread to check if compaction is needed
+    pub fn schedule(&self) {
+        if let Some(sender) = &self.sender {
+            // the thread only goes away when the compactor is dropped
+            let _ = sender.send(());
+        }
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Create the strategy that is selected in the configuration
+pub fn strategy(config: &Configuration) -> Box<dyn Strategy> {
+    match config.compaction_strategy {
+        CompactionStrategy::Leveled => Box::new(Leveled::new(config)),
+        CompactionStrategy::SizeTiered => Box::new(SizeTiered::new(config)),
+    }
+}
+
+/// A strategy decides which tables are compacted next
+pub trait Strategy: Send {
+    /// Pick the next compaction or `None` if no compaction is needed
+    fn pick(&mut self, levels: &Levels) -> Option<Compaction>;
+}
+
+/// A compaction merges the `inputs` into new tables on the `output_level`
+pub struct Compaction {
+    /// The tables to merge, from newest to oldest
+    inputs: Vec<Slab>,
+    output_level: Level,
+    /// The size at which output tables are split
+    target_table_size: u64,
+    /// All tables that are older than the inputs and might share keys with them
+    older: Levels,
+}
+
+impl Compaction {
+    pub fn new(
+        inputs: Vec<Slab>,
+        output_level: Level,
+        target_table_size: u64,
+        older: Levels,
+    ) -> Self {
+        Compaction {
+            inputs,
+            output_level,
+            target_table_size,
+            older,
+        }
+    }
+}
+
+/// Decides which versions of the keys are still needed when tables are written
+///
+/// The versions have to be passed in the order they're stored, the most recent version of a key first.
+/// A version is dropped if a more recent version of the key is visible to all live snapshots,
+/// since no read can see it anymore. A tombstone that is visible to all live snapshots
+/// is only needed as long as an older value of the key might exist.
+pub struct VersionFilter<'a> {
+    /// The sequence number of the oldest live snapshot, if there is any
+    oldest_snapshot: SequenceNumber,
+    /// All tables that are older than the versions that are filtered
+    older: &'a Levels,
+    current_key: Option<Key>,
+    /// The sequence number of the previous (more recent) version of the current key
+    newer_seq: Option<SequenceNumber>,
+}
+
+impl<'a> VersionFilter<'a> {
+    pub fn new(oldest_snapshot: Option<SequenceNumber>, older: &'a Levels) -> Self {
+        VersionFilter {
+            oldest_snapshot: oldest_snapshot.unwrap_or(SequenceNumber::MAX),
+            older,
+            current_key: None,
+            newer_seq: None,
+        }
+    }
+
+    pub fn keep(&mut self, key: &Key, seq: SequenceNumber, entry: &Entry) -> bool {
+        if self.current_key.as_ref() != Some(key) {
+            self.current_key = Some(key.clone());
+            self.newer_seq = None;
+        }
+        let shadowed = matches!(self.newer_seq, Some(newer) if newer <= self.oldest_snapshot);
+        self.newer_seq = Some(seq);
+
+        if shadowed {
+            return false;
+        }
+        match entry {
+            Entry::Tombstone if seq <= self.oldest_snapshot => self.older.may_contain(key),
+            _ => true,
+        }
+    }
+}
+
+/// Run compactions until the strategy doesn't pick any more
+fn compact(strategy: &mut dyn Strategy, tables: &Tables, snapshots: &Snapshots) -> Result<()> {
+    loop {
+        let compaction = match strategy.pick(&*tables.levels()?) {
+            Some(compaction) => compaction,
+            None => return Ok(()),
+        };
+
+        log::debug!(
+            target: "compaction",
+            "compacting {} tables into level {}",
+            compaction.inputs.len(),
+            compaction.output_level
+        );
+
+        let outputs = merge(tables, &compaction, snapshots.oldest()?)?;
+        tables.replace(&compaction.inputs, outputs)?;
+    }
+}
+
+struct Leveled {
+    level0_compaction_trigger: usize,
+    level_size_base: u64,
+    level_size_multiplier: u64,
+    target_table_size: u64,
+    /// The max key of the last compaction per level, so that compaction rotates through the key space
+    pointers: Vec<Option<Key>>,
+}
+
+impl Leveled {
+    fn new(config: &Configuration) -> Self {
+        Leveled {
+            level0_compaction_trigger: config.level0_compaction_trigger,
+            level_size_base: config.level_size_base.as_u64(),
+            level_size_multiplier: config.level_size_multiplier,
+            target_table_size: config.target_table_size.as_u64(),
+            pointers: vec![None; MAX_LEVELS],
+        }
+    }
+
+    /// The ratio of the actual size of a level to its allowed size
+    fn score(&self, levels: &Levels, level: Level) -> f64 {
+        let tables = levels.level(level);
+
+        if level == 0 {
+            tables.len() as f64 / self.level0_compaction_trigger as f64
+        } else {
+            let size: u64 = tables.iter().map(|slab| slab.size()).sum();
+            size as f64 / self.max_level_size(level) as f64
+        }
+    }
+
+    fn max_level_size(&self, level: Level) -> u64 {
+        self.level_size_base * self.level_size_multiplier.pow(level as u32 - 1)
+    }
+
+    /// All tables of level 0 overlap, so they are compacted together (newest first).
+    /// On deeper levels a single table is picked, continuing after the previously compacted one.
+    fn inputs(&self, levels: &Levels, level: Level) -> Vec<Slab> {
+        let tables = levels.level(level);
+        if level == 0 {
+            return tables.iter().rev().cloned().collect();
+        }
+
+        let next = match &self.pointers[level as usize] {
+            Some(pointer) => tables.iter().find(|slab| slab.min_key() > pointer),
+            None => None,
+        };
+
+        next.or_else(|| tables.first())
+            .cloned()
+            .into_iter()
+            .collect()
+    }
+}
+
+impl Strategy for Leveled {
+    fn pick(&mut self, levels: &Levels) -> Option<Compaction> {
+        let mut picked: Option<(f64, Level)> = None;
+
+        for level in 0..levels.depth().min(MAX_LEVELS - 1) {
+            let score = self.score(levels, level as Level);
+            if score >= 1.0 && !matches!(picked, Some((best, _)) if best >= score) {
+                picked = Some((score, level as Level));
+            }
+        }
+
+        let (_, level) = picked?;
+        let inputs = self.inputs(levels, level);
+        let min_key = inputs.iter().map(|slab| slab.min_key()).min()?.clone();
+        let max_key = inputs.iter().map(|slab| slab.max_key()).max()?.clone();
+
+        let overlapping = levels
+            .level(level + 1)
+            .iter()
+            .filter(|slab| slab.overlaps(&min_key, &max_key))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let deeper = (level as usize + 2..levels.depth())
+            .flat_map(|deeper| levels.level(deeper as Level).iter().cloned())
+            .collect();
+
+        self.pointers[level as usize] = Some(max_key);
+
+        Some(Compaction::new(
+            inputs.into_iter().chain(overlapping).collect(),
+            level + 1,
+            self.target_table_size,
+            Levels::new(deeper),
+        ))
+    }
+}
+
+struct SizeTiered {
+    min_threshold: usize,
+}
+
+impl SizeTiered {
+    /// Tables belong to the same tier if their size is within these bounds
+    /// relative to the average size of the tier
+    const TIER_BOUNDS: (f64, f64) = (0.5, 1.5);
+    /// The maximum number of tables that are merged at once
+    const MAX_THRESHOLD: usize = 32;
+
+    fn new(config: &Configuration) -> Self {
+        SizeTiered {
+            min_threshold: config.size_tiered_min_threshold,
+        }
+    }
+
+    fn fits(tier: &[Slab], slab: &Slab) -> bool {
+        let total: u64 = tier.iter().map(|slab| slab.size()).sum();
+        let average = total as f64 / tier.len() as f64;
+        let (lower, upper) = Self::TIER_BOUNDS;
+        let size = slab.size() as f64;
+
+        size >= average * lower && size <= average * upper
+    }
+}
+
+impl Strategy for SizeTiered {
+    /// Tiers are formed of consecutive tables of level 0, so that the merged table
+    /// can take the place of the tier without changing the order of level 0.
+    fn pick(&mut self, levels: &Levels) -> Option<Compaction> {
+        let tables = levels.level(0);
+        let mut start = 0;
+
+        while start < tables.len() {
+            let mut end = start + 1;
+            while end < tables.len()
+                && end - start < Self::MAX_THRESHOLD
+                && Self::fits(&tables[start..end], &tables[end])
+            {
+                end += 1;
+            }
+
+            if end - start >= self.min_threshold {
+                let deeper = (1..levels.depth())
+                    .flat_map(|deeper| levels.level(deeper as Level).iter().cloned());
+                let older = tables[..start].iter().cloned().chain(deeper).collect();
+
+                return Some(Compaction::new(
+                    tables[start..end].iter().rev().cloned().collect(),
+                    0,
+                    u64::MAX,
+                    Levels::new(older),
+                ));
+            }
+
+            start = end;
+        }
+
+        None
+    }
+}
+
+/// Merge the tables of the compaction into new tables on the output level
+///
+/// The versions of a key are never split across output tables, so that tables on the
+/// same level don't overlap.
+fn merge(
+    tables: &Tables,
+    compaction: &Compaction,
+    oldest_snapshot: Option<SequenceNumber>,
+) -> Result<Vec<Slab>> {
+    let output_level = compaction.output_level;
+    let iterators = compaction
+        .inputs
+        .iter()
+        .map(|slab| slab.sstable().map(SSTable::into_iter))
+        .collect::<std::result::Result<Vec<_>, sstable::Error>>()?;
+
+    let mut outputs = Vec::new();
+    let mut writer: Option<sstable::Writer> = None;
+    let mut filter = VersionFilter::new(oldest_snapshot, &compaction.older);
+    let mut last_key: Option<Key> = None;
+
+    for record in MergingIterator::new(iterators) {
+        let (key, seq, entry) = record?;
+        if !filter.keep(&key, seq, &entry) {
+            continue;
+        }
+
+        if let Some(current) = &mut writer {
+            let is_new_key = last_key.as_ref() != Some(&key);
+            if is_new_key && current.bytes_written() as u64 >= compaction.target_table_size {
+                outputs.push(seal(current, output_level)?);
+                writer = None;
+            }
+        }
+
+        let current = match &mut writer {
+            Some(current) => current,
+            None => writer.insert(tables.create_writer()?),
+        };
+        current.append_entry(&key, seq, &entry)?;
+        last_key = Some(key);
+    }
+
+    if let Some(current) = &mut writer {
+        outputs.push(seal(current, output_level)?);
+    }
+
+    Ok(outputs)
+}
+
+fn seal(writer: &mut sstable::Writer, level: Level) -> Result<Slab> {
+    let mut slab = writer.seal()?;
+    slab.level = level;
+    Ok(slab)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VersionFilter;
+    use crate::engine::storage::lsm::levels::Levels;
+    use crate::engine::storage::lsm::memtable::Entry;
+    use crate::engine::{Key, Value};
+
+    #[test]
+    fn versions_are_kept_while_a_snapshot_needs_them() {
+        let older = Levels::new(Vec::new());
+        let value = Entry::Val(Value::from("value"));
+        let (a, b) = (Key::from("a"), Key::from("b"));
+
+        // without snapshots only the most recent version is needed
+        let mut filter = VersionFilter::new(None, &older);
+        assert!(filter.keep(&a, 9, &value));
+        assert!(!filter.keep(&a, 5, &value));
+        assert!(!filter.keep(&b, 8, &Entry::Tombstone));
+        assert!(!filter.keep(&b, 2, &value));
+
+        // a snapshot at 6 sees version 5 of "a" and version 2 of "b"
+        let mut filter = VersionFilter::new(Some(6), &older);
+        assert!(filter.keep(&a, 9, &value));
+        assert!(filter.keep(&a, 5, &value));
+        assert!(!filter.keep(&a, 3, &value));
+        assert!(filter.keep(&b, 8, &Entry::Tombstone));
+        assert!(filter.keep(&b, 2, &value));
+    }
+}
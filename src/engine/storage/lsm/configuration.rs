@@ -1,3 +1,5 @@
+use super::sstable::{ReadOptions, WriterOptions};
+use super::wal::{self, SyncPolicy};
 use std::path::PathBuf;
 use thiserror::Error;
 use ubyte::{ByteUnit, ToByteUnit};
@@ -12,6 +14,27 @@ pub enum Error {
     InvalidStoragePath(String, PathBuf),
 }
 
+/// The strategy that is used to compact SSTables
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompactionStrategy {
+    /// Optimizes for reads by keeping tables in non-overlapping levels
+    Leveled,
+    /// Optimizes for writes by merging tables of similar size
+    SizeTiered,
+}
+
+/// How the recovery deals with WALs that are damaged
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecoveryMode {
+    /// Fail on any damage, including a torn record at the end of a WAL
+    AbsoluteConsistency,
+    /// Cut off a torn record at the end of a WAL, which is left behind by a crash
+    /// while it was written, but fail on corrupted records before the end
+    TolerateCorruptedTail,
+    /// Skip corrupted records and log them, the writes they hold are lost
+    SkipCorruptedRecords,
+}
+
 #[derive(Debug, Clone)]
 pub struct Configuration {
     /// the path to the main directory for the storage engine
@@ -19,11 +42,81 @@ pub struct Configuration {
     pub storage_path: PathBuf,
     /// memtable size in bytes
     pub max_memtable_size: ByteUnit,
+    /// the number of full memtables that may wait to be flushed before writes block
+    pub max_immutable_memtables: usize,
+    /// when the WAL is synced to disk
+    pub wal_sync_policy: SyncPolicy,
+    /// the size in bytes at which the WAL continues with a new segment
+    pub wal_segment_size: ByteUnit,
+    /// how damaged WALs are recovered
+    pub recovery_mode: RecoveryMode,
+    /// the number of tables on level 0 that trigger a compaction
+    pub level0_compaction_trigger: usize,
+    /// the maximum size of level 1 in bytes
+    pub level_size_base: ByteUnit,
+    /// every level can hold this many times more bytes than the previous one
+    pub level_size_multiplier: u64,
+    /// the size of the tables that are produced by compactions
+    pub target_table_size: ByteUnit,
+    /// the strategy that decides which tables are compacted
+    pub compaction_strategy: CompactionStrategy,
+    /// the number of similarly sized tables that trigger a size-tiered compaction
+    pub size_tiered_min_threshold: usize,
+    /// the false positive rate of the bloom filters of SSTables
+    pub bloom_false_positive_rate: f64,
+    /// the size of the data blocks of SSTables in bytes
+    pub block_size: ByteUnit,
+    /// whether the checksums of SSTable blocks are verified when they are read
+    pub verify_checksums: bool,
+    /// the number of SSTables that are kept open
+    pub table_cache_capacity: usize,
+    /// the total size of the data blocks that are cached in memory
+    pub block_cache_size: ByteUnit,
+}
+
+impl Configuration {
+    /// The options for writing new SSTables
+    pub fn writer_options(&self) -> WriterOptions {
+        WriterOptions {
+            bloom_false_positive_rate: self.bloom_false_positive_rate,
+            block_size: self.block_size.as_u64() as usize,
+        }
+    }
+
+    /// The options for reading SSTables
+    pub fn read_options(&self) -> ReadOptions {
+        ReadOptions {
+            verify_checksums: self.verify_checksums,
+        }
+    }
+
+    /// The options of the WAL
+    pub fn wal_options(&self) -> wal::Options {
+        wal::Options {
+            sync_policy: self.wal_sync_policy,
+            segment_size: self.wal_segment_size.as_u64(),
+        }
+    }
 }
 
 pub struct Builder {
     storage_path: Option<PathBuf>,
     max_memtable_size: Option<ByteUnit>,
+    max_immutable_memtables: Option<usize>,
+    wal_sync_policy: Option<SyncPolicy>,
+    wal_segment_size: Option<ByteUnit>,
+    recovery_mode: Option<RecoveryMode>,
+    level0_compaction_trigger: Option<usize>,
+    level_size_base: Option<ByteUnit>,
+    level_size_multiplier: Option<u64>,
+    target_table_size: Option<ByteUnit>,
+    compaction_strategy: Option<CompactionStrategy>,
+    size_tiered_min_threshold: Option<usize>,
+    bloom_false_positive_rate: Option<f64>,
+    block_size: Option<ByteUnit>,
+    verify_checksums: Option<bool>,
+    table_cache_capacity: Option<usize>,
+    block_cache_size: Option<ByteUnit>,
 }
 
 impl Builder {
@@ -31,6 +124,21 @@ impl Builder {
         Self {
             storage_path: None,
             max_memtable_size: None,
+            max_immutable_memtables: None,
+            wal_sync_policy: None,
+            wal_segment_size: None,
+            recovery_mode: None,
+            level0_compaction_trigger: None,
+            level_size_base: None,
+            level_size_multiplier: None,
+            target_table_size: None,
+            compaction_strategy: None,
+            size_tiered_min_threshold: None,
+            bloom_false_positive_rate: None,
+            block_size: None,
+            verify_checksums: None,
+            table_cache_capacity: None,
+            block_cache_size: None,
         }
     }
 
@@ -38,6 +146,21 @@ impl Builder {
         Ok(Configuration {
             storage_path: self.storage_path.unwrap(),
             max_memtable_size: self.max_memtable_size.unwrap(),
+            max_immutable_memtables: self.max_immutable_memtables.unwrap(),
+            wal_sync_policy: self.wal_sync_policy.unwrap(),
+            wal_segment_size: self.wal_segment_size.unwrap(),
+            recovery_mode: self.recovery_mode.unwrap(),
+            level0_compaction_trigger: self.level0_compaction_trigger.unwrap(),
+            level_size_base: self.level_size_base.unwrap(),
+            level_size_multiplier: self.level_size_multiplier.unwrap(),
+            target_table_size: self.target_table_size.unwrap(),
+            compaction_strategy: self.compaction_strategy.unwrap(),
+            size_tiered_min_threshold: self.size_tiered_min_threshold.unwrap(),
+            bloom_false_positive_rate: self.bloom_false_positive_rate.unwrap(),
+            block_size: self.block_size.unwrap(),
+            verify_checksums: self.verify_checksums.unwrap(),
+            table_cache_capacity: self.table_cache_capacity.unwrap(),
+            block_cache_size: self.block_cache_size.unwrap(),
         })
     }
 
@@ -53,6 +176,150 @@ impl Builder {
         Ok(self)
     }
 
+    /// The number of full memtables that may wait to be flushed in the background.
+    /// Once the queue is full, writes block until a memtable has been flushed.
+    pub fn with_max_immutable_memtables(&mut self, memtables: usize) -> Result<&mut Self> {
+        if memtables < 1 {
+            return Err(Error::OutOfBound(
+                "At least 1 immutable memtable must be allowed".into(),
+            ));
+        }
+        self.max_immutable_memtables = Some(memtables);
+        Ok(self)
+    }
+
+    /// When the WAL is synced to disk. Only with `SyncPolicy::Always` every acknowledged
+    /// write survives a crash of the machine, the other policies trade that for faster writes.
+    pub fn with_wal_sync_policy(&mut self, policy: SyncPolicy) -> Result<&mut Self> {
+        match policy {
+            SyncPolicy::Interval(interval) if interval.is_zero() => Err(Error::OutOfBound(
+                "The sync interval must be positive, use SyncPolicy::Always instead".into(),
+            )),
+            SyncPolicy::Bytes(bytes) if bytes.as_u64() < 1 => Err(Error::OutOfBound(
+                "The sync size must be at least 1 byte, use SyncPolicy::Always instead".into(),
+            )),
+            _ => {
+                self.wal_sync_policy = Some(policy);
+                Ok(self)
+            }
+        }
+    }
+
+    /// The size at which the WAL continues with a new segment. Segments are removed
+    /// once all of their writes have been flushed to SSTables.
+    pub fn with_wal_segment_size<T: Into<ByteUnit>>(&mut self, size: T) -> Result<&mut Self> {
+        let size = size.into();
+        if size.as_u64() < 1 {
+            return Err(Error::OutOfBound(
+                "The WAL segment size must be at least 1 byte".into(),
+            ));
+        }
+        self.wal_segment_size = Some(size);
+        Ok(self)
+    }
+
+    pub fn with_recovery_mode(&mut self, mode: RecoveryMode) -> Result<&mut Self> {
+        self.recovery_mode = Some(mode);
+        Ok(self)
+    }
+
+    pub fn with_level0_compaction_trigger(&mut self, tables: usize) -> Result<&mut Self> {
+        if tables < 1 {
+            return Err(Error::OutOfBound(
+                "The level 0 compaction trigger must be at least 1".into(),
+            ));
+        }
+        self.level0_compaction_trigger = Some(tables);
+        Ok(self)
+    }
+
+    pub fn with_level_size_base<T: Into<ByteUnit>>(&mut self, size: T) -> Result<&mut Self> {
+        let size = size.into();
+        if size.as_u64() < 1 {
+            return Err(Error::OutOfBound(
+                "The level size base must be at least 1 byte".into(),
+            ));
+        }
+        self.level_size_base = Some(size);
+        Ok(self)
+    }
+
+    pub fn with_level_size_multiplier(&mut self, multiplier: u64) -> Result<&mut Self> {
+        if multiplier < 2 {
+            return Err(Error::OutOfBound(
+                "The level size multiplier must be at least 2".into(),
+            ));
+        }
+        self.level_size_multiplier = Some(multiplier);
+        Ok(self)
+    }
+
+    pub fn with_target_table_size<T: Into<ByteUnit>>(&mut self, size: T) -> Result<&mut Self> {
+        let size = size.into();
+        if size.as_u64() < 1 {
+            return Err(Error::OutOfBound(
+                "The target table size must be at least 1 byte".into(),
+            ));
+        }
+        self.target_table_size = Some(size);
+        Ok(self)
+    }
+
+    pub fn with_compaction_strategy(&mut self, strategy: CompactionStrategy) -> Result<&mut Self> {
+        self.compaction_strategy = Some(strategy);
+        Ok(self)
+    }
+
+    pub fn with_size_tiered_min_threshold(&mut self, tables: usize) -> Result<&mut Self> {
+        if tables < 2 {
+            return Err(Error::OutOfBound(
+                "The size-tiered threshold must be at least 2".into(),
+            ));
+        }
+        self.size_tiered_min_threshold = Some(tables);
+        Ok(self)
+    }
+
+    pub fn with_bloom_false_positive_rate(&mut self, rate: f64) -> Result<&mut Self> {
+        if !(rate > 0.0 && rate < 1.0) {
+            return Err(Error::OutOfBound(
+                "The false positive rate must be between 0 and 1 (exclusive)".into(),
+            ));
+        }
+        self.bloom_false_positive_rate = Some(rate);
+        Ok(self)
+    }
+
+    pub fn with_block_size<T: Into<ByteUnit>>(&mut self, size: T) -> Result<&mut Self> {
+        let size = size.into();
+        if size.as_u64() < 1 {
+            return Err(Error::OutOfBound(
+                "The block size must be at least 1 byte".into(),
+            ));
+        }
+        self.block_size = Some(size);
+        Ok(self)
+    }
+
+    /// Whether the checksums of SSTable blocks are verified when they are read.
+    /// Without verification, corrupted blocks are only detected if they can't be decoded.
+    pub fn with_verify_checksums(&mut self, verify: bool) -> Result<&mut Self> {
+        self.verify_checksums = Some(verify);
+        Ok(self)
+    }
+
+    /// The number of open SSTables that are cached. A capacity of 0 disables the cache.
+    pub fn with_table_cache_capacity(&mut self, tables: usize) -> Result<&mut Self> {
+        self.table_cache_capacity = Some(tables);
+        Ok(self)
+    }
+
+    /// The total size of the cached data blocks. A size of 0 disables the cache.
+    pub fn with_block_cache_size<T: Into<ByteUnit>>(&mut self, size: T) -> Result<&mut Self> {
+        self.block_cache_size = Some(size.into());
+        Ok(self)
+    }
+
     fn assert_valid_storage_path(storage_path: &PathBuf) -> Result<()> {
         if !storage_path.is_dir() {
             Err(Error::InvalidStoragePath(
@@ -70,6 +337,49 @@ impl Default for Builder {
         Self {
             storage_path: None,
             max_memtable_size: Some(512.megabytes()),
+            max_immutable_memtables: Some(2),
+            wal_sync_policy: Some(SyncPolicy::Always),
+            wal_segment_size: Some(64.mebibytes()),
+            recovery_mode: Some(RecoveryMode::TolerateCorruptedTail),
+            level0_compaction_trigger: Some(4),
+            level_size_base: Some(10.megabytes()),
+            level_size_multiplier: Some(10),
+            target_table_size: Some(2.megabytes()),
+            compaction_strategy: Some(CompactionStrategy::Leveled),
+            size_tiered_min_threshold: Some(4),
+            bloom_false_positive_rate: Some(0.01),
+            block_size: Some(4.kibibytes()),
+            verify_checksums: Some(true),
+            table_cache_capacity: Some(128),
+            block_cache_size: Some(8.mebibytes()),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Builder, Error};
+
+    #[test]
+    fn builder_rejects_sizes_that_break_compaction() {
+        let mut builder = Builder::default();
+        assert!(matches!(
+            builder.with_level_size_base(0),
+            Err(Error::OutOfBound(_))
+        ));
+        assert!(matches!(
+            builder.with_target_table_size(0),
+            Err(Error::OutOfBound(_))
+        ));
+        for multiplier in [0, 1] {
+            assert!(matches!(
+                builder.with_level_size_multiplier(multiplier),
+                Err(Error::OutOfBound(_))
+            ));
+        }
+
+        assert!(builder.with_level_size_base(1).is_ok());
+        assert!(builder.with_target_table_size(1).is_ok());
+        assert!(builder.with_level_size_multiplier(2).is_ok());
+    }
+}
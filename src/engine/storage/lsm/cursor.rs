@@ -0,0 +1,258 @@
+//! Cursors over sorted runs of entries
+//!
+//! A cursor is a position in a sorted run of entries that can be moved to an arbitrary
+//! key with `seek`. This allows scans to start anywhere instead of walking all entries
+//! from the beginning. Both the memtable and SSTables provide cursors.
+//!
+//! Cursors move in both directions, which allows to scan keys in descending order, too.
+//!
+//! The `MergingCursor` combines the cursors of all the places where the LSM stores
+//! entries. Like the `MergingIterator`, it resolves keys that appear in more than one
+//! source to the entry of the newest source.
+use super::memtable::Entry;
+use super::Result;
+use crate::engine::Key;
+
+pub trait Cursor {
+    /// Position the cursor at the first entry
+    fn seek_to_first(&mut self) -> Result<()>;
+
+    /// Position the cursor at the last entry
+    fn seek_to_last(&mut self) -> Result<()>;
+
+    /// Position the cursor at the first entry with a key that is not smaller than `k`
+    fn seek(&mut self, k: &Key) -> Result<()>;
+
+    /// Position the cursor at the last entry with a key that is not greater than `k`
+    fn seek_for_prev(&mut self, k: &Key) -> Result<()>;
+
+    /// Move the cursor to the following entry
+    fn next(&mut self) -> Result<()>;
+
+    /// Move the cursor to the preceding entry
+    fn prev(&mut self) -> Result<()>;
+
+    /// The entry at the position of the cursor, or `None` if the cursor is exhausted
+    fn current(&self) -> Option<(&Key, &Entry)>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Direction {
+    /// All sources are positioned at or after the current key
+    Forward,
+    /// All sources are positioned at or before the current key
+    Backward,
+}
+
+pub struct MergingCursor<'a> {
+    sources: Vec<Box<dyn Cursor + 'a>>,
+    /// The source that provides the current entry
+    current: Option<usize>,
+    direction: Direction,
+}
+
+impl<'a> MergingCursor<'a> {
+    /// Create a new merging cursor from sources that are ordered from newest to oldest
+    pub fn new(sources: Vec<Box<dyn Cursor + 'a>>) -> Self {
+        MergingCursor {
+            sources,
+            current: None,
+            direction: Direction::Forward,
+        }
+    }
+
+    /// The source with the smallest key becomes current.
+    /// For equal keys the newest source wins.
+    fn find_smallest(&mut self) {
+        self.direction = Direction::Forward;
+        self.current = self.find(|candidate, best| candidate < best);
+    }
+
+    /// The source with the greatest key becomes current.
+    /// For equal keys the newest source wins.
+    fn find_largest(&mut self) {
+        self.direction = Direction::Backward;
+        self.current = self.find(|candidate, best| candidate > best);
+    }
+
+    /// Find the first source whose key is `better` than the keys of all other sources
+    fn find<F: Fn(&Key, &Key) -> bool>(&self, better: F) -> Option<usize> {
+        let mut found: Option<(usize, &Key)> = None;
+        for (idx, source) in self.sources.iter().enumerate() {
+            if let Some((key, _)) = source.current() {
+                if !matches!(found, Some((_, best)) if !better(key, best)) {
+                    found = Some((idx, key));
+                }
+            }
+        }
+        found.map(|(idx, _)| idx)
+    }
+
+    fn current_key(&self) -> Option<Key> {
+        self.current().map(|(key, _)| key.clone())
+    }
+}
+
+impl<'a> Cursor for MergingCursor<'a> {
+    fn seek_to_first(&mut self) -> Result<()> {
+        for source in self.sources.iter_mut() {
+            source.seek_to_first()?;
+        }
+        self.find_smallest();
+        Ok(())
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        for source in self.sources.iter_mut() {
+            source.seek_to_last()?;
+        }
+        self.find_largest();
+        Ok(())
+    }
+
+    fn seek(&mut self, k: &Key) -> Result<()> {
+        for source in self.sources.iter_mut() {
+            source.seek(k)?;
+        }
+        self.find_smallest();
+        Ok(())
+    }
+
+    fn seek_for_prev(&mut self, k: &Key) -> Result<()> {
+        for source in self.sources.iter_mut() {
+            source.seek_for_prev(k)?;
+        }
+        self.find_largest();
+        Ok(())
+    }
+
+    /// All sources move past the current key, so that older entries of the key are skipped.
+    /// After moving backwards, the sources first have to be positioned after the current key.
+    fn next(&mut self) -> Result<()> {
+        let key = match self.current_key() {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+
+        for source in self.sources.iter_mut() {
+            if self.direction == Direction::Backward {
+                source.seek(&key)?;
+            }
+            if matches!(source.current(), Some((current, _)) if current == &key) {
+                source.next()?;
+            }
+        }
+        self.find_smallest();
+        Ok(())
+    }
+
+    /// The mirror image of `next`
+    fn prev(&mut self) -> Result<()> {
+        let key = match self.current_key() {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+
+        for source in self.sources.iter_mut() {
+            if self.direction == Direction::Forward {
+                source.seek_for_prev(&key)?;
+            }
+            if matches!(source.current(), Some((current, _)) if current == &key) {
+                source.prev()?;
+            }
+        }
+        self.find_largest();
+        Ok(())
+    }
+
+    fn current(&self) -> Option<(&Key, &Entry)> {
+        self.sources[self.current?].current()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cursor, MergingCursor};
+    use crate::engine::storage::lsm::memtable::{Entry, SkipListMemtable};
+    use crate::engine::storage::lsm::SequenceNumber;
+    use crate::engine::{Key, Value};
+    use std::sync::Arc;
+
+    const LATEST: SequenceNumber = SequenceNumber::MAX;
+
+    fn memtable(records: &[(&str, Option<&str>)]) -> Arc<SkipListMemtable> {
+        let memtable = Arc::new(SkipListMemtable::new());
+        for (seq, (k, v)) in (1..).zip(records) {
+            match v {
+                Some(v) => memtable.insert(Key::from(*k), seq, Value::from(*v)),
+                None => memtable.remove(&Key::from(*k), seq),
+            };
+        }
+        memtable
+    }
+
+    fn collect(cursor: &mut MergingCursor) -> Vec<(Key, Entry)> {
+        let mut records = Vec::new();
+        while let Some((key, entry)) = cursor.current() {
+            records.push((key.clone(), entry.clone()));
+            cursor.next().unwrap();
+        }
+        records
+    }
+
+    #[test]
+    fn seek_positions_all_sources_and_newest_entry_wins() {
+        let newest = memtable(&[("b", Some("new")), ("d", None)]);
+        let oldest = memtable(&[("a", Some("old")), ("b", Some("old")), ("d", Some("old"))]);
+        let mut cursor = MergingCursor::new(vec![
+            Box::new(newest.cursor(LATEST)),
+            Box::new(oldest.cursor(LATEST)),
+        ]);
+
+        cursor.seek(&Key::from("aa")).unwrap();
+        assert_eq!(
+            vec![
+                (Key::from("b"), Entry::Val(Value::from("new"))),
+                (Key::from("d"), Entry::Tombstone),
+            ],
+            collect(&mut cursor)
+        );
+
+        cursor.seek_to_first().unwrap();
+        assert_eq!(3, collect(&mut cursor).len());
+    }
+
+    #[test]
+    fn moves_back_and_forth_across_sources() {
+        let newest = memtable(&[("b", Some("new")), ("e", Some("new"))]);
+        let oldest = memtable(&[("a", Some("old")), ("b", Some("old")), ("d", Some("old"))]);
+        let mut cursor = MergingCursor::new(vec![
+            Box::new(newest.cursor(LATEST)),
+            Box::new(oldest.cursor(LATEST)),
+        ]);
+        let key = |cursor: &MergingCursor| cursor.current().map(|(key, _)| key.clone());
+
+        cursor.seek_for_prev(&Key::from("c")).unwrap();
+        assert_eq!(
+            Some((&Key::from("b"), &Entry::Val(Value::from("new")))),
+            cursor.current()
+        );
+
+        cursor.prev().unwrap();
+        assert_eq!(Some(Key::from("a")), key(&cursor));
+        cursor.next().unwrap();
+        assert_eq!(
+            Some((&Key::from("b"), &Entry::Val(Value::from("new")))),
+            cursor.current()
+        );
+        cursor.next().unwrap();
+        assert_eq!(Some(Key::from("d")), key(&cursor));
+        cursor.prev().unwrap();
+        assert_eq!(Some(Key::from("b")), key(&cursor));
+
+        cursor.seek_to_last().unwrap();
+        assert_eq!(Some(Key::from("e")), key(&cursor));
+        cursor.next().unwrap();
+        assert_eq!(None, key(&cursor));
+    }
+}
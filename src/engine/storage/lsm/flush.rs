@@ -0,0 +1,228 @@
+//! Background flushes of memtables
+//!
+//! Once the memtable is full, it's moved into a queue of immutable memtables and an empty
+//! memtable takes its place, so that writes continue right away. A background thread writes
+//! the queued memtables to SSTables, from oldest to newest. Readers consult the immutable
+//! memtables until their tables have been added to the LSM.
+//!
+//! When a memtable is queued, the WAL continues with a new segment. The older segments hold
+//! the writes of the queued memtable and are removed once it has been persisted. If the flushes
+//! fall behind, the queue fills up and writes block until a memtable has been flushed.
+//!
+//! A failed flush stops the background thread for good, since younger memtables must not be
+//! flushed before older ones. The flusher is failed from then on, and the LSM rejects all writes.
+use super::compaction::{Compactor, VersionFilter};
+use super::memtable::SkipListMemtable;
+use super::snapshot::Snapshots;
+use super::sstable;
+use super::tables::Tables;
+use super::wal::WalManager;
+use super::{Error, Result};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, RwLock};
+use std::thread;
+
+/// The memtables of the LSM
+pub struct Memtables {
+    /// The memtable that receives the writes
+    pub active: Arc<SkipListMemtable>,
+    /// The full memtables that wait to be flushed, from newest to oldest
+    pub immutable: VecDeque<Arc<SkipListMemtable>>,
+}
+
+impl Memtables {
+    pub fn new() -> Self {
+        Memtables {
+            active: Arc::new(SkipListMemtable::new()),
+            immutable: VecDeque::new(),
+        }
+    }
+
+    /// All memtables from newest to oldest
+    pub fn all(&self) -> Vec<Arc<SkipListMemtable>> {
+        std::iter::once(&self.active)
+            .chain(self.immutable.iter())
+            .cloned()
+            .collect()
+    }
+}
+
+impl Default for Memtables {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+enum Job {
+    /// Write the memtable to an SSTable and remove the WAL segments
+    /// older than the given one afterwards
+    Flush(Arc<SkipListMemtable>, u64),
+    /// Signal that all jobs before it are done
+    Barrier(mpsc::Sender<()>),
+}
+
+/// The flusher owns the background thread that writes immutable memtables to SSTables
+///
+/// Dropping the flusher waits until all queued memtables have been flushed.
+pub struct Flusher {
+    sender: Option<mpsc::SyncSender<Job>>,
+    handle: Option<thread::JoinHandle<()>>,
+    /// Set by the background thread when a flush has failed
+    failed: Arc<AtomicBool>,
+}
+
+impl Flusher {
+    /// Start the background thread, which accepts up to `queue_length` memtables
+    /// on top of the one that it's flushing
+    pub fn start(
+        memtables: Arc<RwLock<Memtables>>,
+        tables: Arc<Tables>,
+        snapshots: Arc<Snapshots>,
+        compactor: Arc<Compactor>,
+        wal_manager: Arc<WalManager>,
+        queue_length: usize,
+    ) -> Result<Flusher> {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue_length);
+        let failed = Arc::new(AtomicBool::new(false));
+        let failed_flush = failed.clone();
+
+        let handle = thread::Builder::new().name("flush".into()).spawn(move || {
+            for job in receiver {
+                match job {
+                    Job::Flush(memtable, segment) => {
+                        if let Err(e) = flush(
+                            &memtable,
+                            segment,
+                            &memtables,
+                            &tables,
+                            &snapshots,
+                            &compactor,
+                            &wal_manager,
+                        ) {
+                            // younger memtables must not be flushed before this one, since
+                            // their tombstones might be dropped. The data is still in the WAL.
+                            log::error!(target: "flush", "flush failed, stopping flushes: {:?}", e);
+                            failed_flush.store(true, Ordering::SeqCst);
+                            return;
+                        }
+                    }
+                    Job::Barrier(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        })?;
+
+        Ok(Flusher {
+            sender: Some(sender),
+            handle: Some(handle),
+            failed,
+        })
+    }
+
+    /// Fail with `Error::FlushError` if a flush has failed, after which no memtable is flushed anymore
+    pub fn check(&self) -> Result<()> {
+        if self.failed.load(Ordering::SeqCst) {
+            return Err(Error::FlushError);
+        }
+        Ok(())
+    }
+
+    /// Queue the `memtable` to be flushed, its writes are held by the WAL
+    /// segments that are older than `segment`
+    ///
+    /// Blocks while the queue is full.
+    pub fn schedule(&self, memtable: Arc<SkipListMemtable>, segment: u64) -> Result<()> {
+        self.send(Job::Flush(memtable, segment))
+    }
+
+    /// Wait until all memtables that have been queued so far are flushed
+    pub fn wait(&self) -> Result<()> {
+        let (done, finished) = mpsc::channel();
+        self.send(Job::Barrier(done))?;
+        finished.recv().map_err(|_| Error::FlushError)
+    }
+
+    fn send(&self, job: Job) -> Result<()> {
+        match &self.sender {
+            Some(sender) => sender.send(job).map_err(|_| Error::FlushError),
+            None => Err(Error::FlushError),
+        }
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Persist the `memtable` and retire it together with the WAL segments before `segment`
+///
+/// The table is added before the memtable leaves the queue, so that readers
+/// find the data in either of them.
+fn flush(
+    memtable: &Arc<SkipListMemtable>,
+    segment: u64,
+    memtables: &RwLock<Memtables>,
+    tables: &Tables,
+    snapshots: &Snapshots,
+    compactor: &Compactor,
+    wal_manager: &WalManager,
+) -> Result<()> {
+    if write_memtable(memtable, tables, snapshots)? {
+        compactor.schedule();
+    }
+
+    memtables
+        .write()?
+        .immutable
+        .retain(|queued| !Arc::ptr_eq(queued, memtable));
+    wal_manager.remove_before(segment)?;
+    Ok(())
+}
+
+/// Write the `memtable` to a new SSTable and add it to the `tables`
+///
+/// Returns whether a table has been added, which isn't the case if
+/// none of the versions in the memtable are needed anymore.
+pub fn write_memtable(
+    memtable: &SkipListMemtable,
+    tables: &Tables,
+    snapshots: &Snapshots,
+) -> Result<bool> {
+    if memtable.is_empty() {
+        return Ok(false);
+    }
+
+    let mut writer = tables.create_writer()?;
+    log::debug!(target: "flush", "flushing memtable of size {} to {:?}", memtable.size(), writer.path());
+
+    {
+        let levels = tables.levels()?;
+        // versions that no snapshot can see anymore are dropped, just like
+        // tombstones that have no older value to shadow
+        let mut filter = VersionFilter::new(snapshots.oldest()?, &levels);
+        for (key, seq, entry) in memtable.iter() {
+            if filter.keep(&key, seq, &entry) {
+                writer.append_entry(&key, seq, &entry)?;
+            }
+        }
+    }
+
+    match writer.seal() {
+        Ok(slab) => {
+            tables.add(slab)?;
+            Ok(true)
+        }
+        Err(sstable::Error::EmptyTable) => {
+            std::fs::remove_file(writer.path())?;
+            Ok(false)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
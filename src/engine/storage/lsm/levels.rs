@@ -0,0 +1,209 @@
+//! The levels organize the live SSTables of the LSM
+//!
+//! SSTables on level 0 are the result of memtable flushes. They may overlap
+//! with each other, which means that a key can be found in several of them.
+//! The most recent table always wins.
+//!
+//! SSTables on all deeper levels are the result of compaction. The tables of such a
+//! level never overlap, which means that there is at most one table per level
+//! that can contain a given key, and it can be found by a binary search.
+use super::sstable::{Level, Slab};
+use crate::engine::Key;
+
+#[derive(Debug, Default)]
+pub struct Levels {
+    levels: Vec<Vec<Slab>>,
+}
+
+impl Levels {
+    /// Create the levels from the given slabs.
+    ///
+    /// Slabs must be provided in the order they have been created,
+    /// so that the order of overlapping tables on level 0 is retained.
+    pub fn new(slabs: Vec<Slab>) -> Self {
+        let mut levels = Levels::default();
+        for slab in slabs {
+            levels.add(slab);
+        }
+        levels
+    }
+
+    /// Add the slab to its level.
+    ///
+    /// Slabs on level 0 are considered to be newer than every other slab on this level.
+    pub fn add(&mut self, slab: Slab) {
+        let level = slab.level as usize;
+        if self.levels.len() <= level {
+            self.levels.resize_with(level + 1, Vec::new);
+        }
+
+        if level == 0 {
+            self.levels[0].push(slab);
+        } else {
+            let tables = &mut self.levels[level];
+            let idx = tables.partition_point(|other| other.min_key() < slab.min_key());
+            tables.insert(idx, slab);
+        }
+    }
+
+    /// Remove the slab from its level
+    pub fn remove(&mut self, slab: &Slab) {
+        if let Some(tables) = self.levels.get_mut(slab.level as usize) {
+            tables.retain(|other| other.path() != slab.path());
+        }
+    }
+
+    /// Replace the `obsolete` slabs with the `added` slabs
+    ///
+    /// Added slabs of level 0 take the place of the oldest obsolete slab of level 0,
+    /// which retains the order of level 0 when a run of its tables is merged.
+    /// If no slab of level 0 is obsolete, they are added as the newest tables.
+    pub fn replace(&mut self, obsolete: &[Slab], added: Vec<Slab>) {
+        let position = self
+            .level(0)
+            .iter()
+            .position(|slab| obsolete.contains(slab));
+
+        for slab in obsolete {
+            self.remove(slab);
+        }
+
+        let (level0, deeper): (Vec<Slab>, Vec<Slab>) =
+            added.into_iter().partition(|slab| slab.level == 0);
+
+        match position {
+            Some(position) if !level0.is_empty() => {
+                self.levels[0].splice(position..position, level0);
+            }
+            _ => level0.into_iter().for_each(|slab| self.add(slab)),
+        }
+        deeper.into_iter().for_each(|slab| self.add(slab));
+    }
+
+    /// The slabs on the provided level
+    pub fn level(&self, level: Level) -> &[Slab] {
+        self.levels
+            .get(level as usize)
+            .map_or(&[], |tables| tables.as_slice())
+    }
+
+    /// The number of levels that exist, including empty ones
+    pub fn depth(&self) -> usize {
+        self.levels.len()
+    }
+
+    /// All slabs, level by level
+    pub fn iter(&self) -> impl Iterator<Item = &Slab> {
+        self.levels.iter().flatten()
+    }
+
+    /// The slabs that might contain the key, in the order they must be consulted.
+    ///
+    /// These are all matching slabs of level 0 from newest to oldest,
+    /// followed by at most one slab of each deeper level.
+    /// Slabs whose bloom filter rules out the key are skipped.
+    pub fn candidates<'a>(&'a self, k: &'a Key) -> impl Iterator<Item = &'a Slab> + 'a {
+        let level0 = self
+            .level(0)
+            .iter()
+            .rev()
+            .filter(move |slab| slab.may_contain(k));
+
+        let deeper = self.levels.iter().skip(1).filter_map(move |tables| {
+            tables
+                .binary_search_by(|slab| slab.cmp(k))
+                .ok()
+                .map(|idx| &tables[idx])
+                .filter(|slab| slab.may_contain(k))
+        });
+
+        level0.chain(deeper)
+    }
+
+    /// Check if any slab might hold a value for the key
+    pub fn may_contain(&self, k: &Key) -> bool {
+        self.candidates(k).next().is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Levels;
+    use crate::engine::storage::lsm::sstable::Slab;
+    use crate::engine::Key;
+    use std::path;
+
+    fn slab(level: u8, name: &str, min: &str, max: &str) -> Slab {
+        Slab::new(
+            level,
+            path::Path::new(name),
+            Key::from(min),
+            Key::from(max),
+            0,
+        )
+    }
+
+    fn candidates<'a>(levels: &'a Levels, k: &'a Key) -> Vec<&'a str> {
+        levels
+            .candidates(k)
+            .map(|slab| slab.path().to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn candidates_are_ordered_newest_first() {
+        let levels = Levels::new(vec![
+            slab(1, "l1-c", "m", "p"),
+            slab(0, "l0-old", "a", "k"),
+            slab(1, "l1-a", "a", "f"),
+            slab(2, "l2-a", "a", "z"),
+            slab(0, "l0-new", "c", "z"),
+            slab(1, "l1-b", "g", "l"),
+        ]);
+
+        let key = Key::from("d");
+        assert_eq!(
+            vec!["l0-new", "l0-old", "l1-a", "l2-a"],
+            candidates(&levels, &key)
+        );
+
+        let key = Key::from("h");
+        assert_eq!(
+            vec!["l0-new", "l0-old", "l1-b", "l2-a"],
+            candidates(&levels, &key)
+        );
+
+        let key = Key::from("q");
+        assert_eq!(vec!["l0-new", "l2-a"], candidates(&levels, &key));
+    }
+
+    #[test]
+    fn replaced_slabs_of_level0_retain_their_position() {
+        let oldest = slab(0, "l0-oldest", "a", "z");
+        let old = slab(0, "l0-old", "a", "z");
+        let new = slab(0, "l0-new", "a", "z");
+        let mut levels = Levels::new(vec![
+            oldest.clone(),
+            old.clone(),
+            new.clone(),
+            slab(0, "l0-newest", "a", "z"),
+        ]);
+        levels.replace(&[old, new], vec![slab(0, "l0-merged", "a", "z")]);
+
+        let key = Key::from("d");
+        assert_eq!(
+            vec!["l0-newest", "l0-merged", "l0-oldest"],
+            candidates(&levels, &key)
+        );
+    }
+
+    #[test]
+    fn removed_slabs_are_no_candidates() {
+        let old = slab(0, "l0-old", "a", "k");
+        let mut levels = Levels::new(vec![old.clone(), slab(0, "l0-new", "c", "z")]);
+        levels.remove(&old);
+
+        let key = Key::from("d");
+        assert_eq!(vec!["l0-new"], candidates(&levels, &key));
+    }
+}
@@ -0,0 +1,323 @@
+//! The manifest keeps track of the live SSTables of the LSM
+//!
+//! It's an append only log of version edits, where every edit either
+//! adds or removes a `Slab`. Edits that belong together (e.g. the result of a compaction)
+//! are written as a single record, so that they're applied all or nothing.
+//! Replaying the log from the beginning yields
+//! the set of SSTables that make up the C1 system, which allows the LSM
+//! to pick up all its tables again after a restart.
+//!
+//! Whenever the manifest is opened it is compacted, that is the replayed
+//! state is written to a fresh log which then atomically replaces the old one.
+//! This keeps the manifest from growing without bounds.
+use super::binary_io as binio;
+use super::sstable::Slab;
+use super::wal::serialization::FileHeader;
+use log::{info, trace, warn};
+use serde::{Deserialize, Serialize};
+use std::io::{BufWriter, Write};
+use std::{fs, io, path};
+use thiserror::Error;
+
+const MANIFEST_FILE_NAME: &str = "MANIFEST";
+const MANIFEST_TMP_FILE_NAME: &str = "MANIFEST.tmp";
+const VERSION: u8 = 2;
+const STANZA: &str = "r2d2::manifest";
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("IoError: {0}")]
+    IoError(#[from] io::Error),
+    #[error(transparent)]
+    BinIoError(#[from] binio::Error),
+    #[error("NotAManifest: {0:?} is not a manifest")]
+    NotAManifest(path::PathBuf),
+    #[error("UnsupportedVersion: version {0} of the manifest format is not supported")]
+    UnsupportedVersion(u8),
+    #[error("CorruptedRecord: the record at offset {0} of the manifest can't be decoded")]
+    CorruptedRecord(u64),
+}
+
+/// A single change to the set of live SSTables
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum VersionEdit {
+    /// A new SSTable has been sealed and is now live
+    AddSlab(Slab),
+    /// The SSTable at the given path is obsolete and must not be used anymore
+    RemoveSlab(path::PathBuf),
+}
+
+pub struct Manifest {
+    file: BufWriter<fs::File>,
+}
+
+impl Manifest {
+    /// Open the manifest in the provided `storage_path`
+    ///
+    /// If a manifest exists, its edits are replayed and the resulting live slabs
+    /// are returned in the order they have been added.
+    /// If no manifest exists, a new empty one is created.
+    pub fn open(storage_path: &path::Path) -> Result<(Manifest, Vec<Slab>)> {
+        let manifest_path = storage_path.join(MANIFEST_FILE_NAME);
+        let slabs = if manifest_path.exists() {
+            Self::replay(&manifest_path)?
+        } else {
+            Vec::new()
+        };
+
+        info!(target: "manifest", "manifest loaded with {} live sstables", slabs.len());
+
+        let tmp_path = storage_path.join(MANIFEST_TMP_FILE_NAME);
+        let mut manifest = Self::create(&tmp_path)?;
+        let edits: Vec<VersionEdit> = slabs
+            .iter()
+            .map(|slab| VersionEdit::AddSlab(slab.clone()))
+            .collect();
+        manifest.apply(&edits)?;
+        fs::rename(&tmp_path, &manifest_path)?;
+        fs::File::open(storage_path)?.sync_all()?;
+
+        Ok((manifest, slabs))
+    }
+
+    /// Record that the given `slab` is live
+    pub fn add(&mut self, slab: &Slab) -> Result<()> {
+        self.apply(&[VersionEdit::AddSlab(slab.clone())])
+    }
+
+    /// Record that the given `slab` is obsolete
+    pub fn remove(&mut self, slab: &Slab) -> Result<()> {
+        self.apply(&[VersionEdit::RemoveSlab(slab.path().to_path_buf())])
+    }
+
+    /// Atomically record all the provided edits
+    ///
+    /// Edits are rare but precious, so they are synced right away.
+    pub fn apply(&mut self, edits: &[VersionEdit]) -> Result<()> {
+        binio::write_data(&mut self.file, edits)?;
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    fn create(path: &path::Path) -> Result<Manifest> {
+        let mut file = BufWriter::new(
+            fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(path)?,
+        );
+        binio::write_data(&mut file, FileHeader::new(STANZA, VERSION))?;
+        file.flush()?;
+
+        Ok(Manifest { file })
+    }
+
+    /// Replay the edits of the manifest at `path`
+    ///
+    /// A record that has been cut off at the end of the manifest has never been applied,
+    /// since its write didn't complete. Any other record that can't be decoded fails
+    /// the replay, because the edits after it would be lost.
+    fn replay(path: &path::Path) -> Result<Vec<Slab>> {
+        let content = fs::read(path)?;
+        let mut file = content.as_slice();
+        Self::read_header(&mut file, path)?;
+        let mut slabs: Vec<Slab> = Vec::new();
+
+        while !file.is_empty() {
+            let offset = (content.len() - file.len()) as u64;
+            let edits: Vec<VersionEdit> = match binio::read_data_owned(&mut file) {
+                Ok(edits) => edits,
+                Err(binio::Error::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    warn!(target: "manifest", "ignoring incomplete record at offset {} at the end of the manifest", offset);
+                    break;
+                }
+                Err(binio::Error::SerializationError) => {
+                    return Err(Error::CorruptedRecord(offset))
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            // added slabs take the place of the first removed slab, which retains
+            // the order of level 0 if a run of its tables is replaced.
+            let mut added = Vec::new();
+            let mut position = None;
+            for edit in edits {
+                match edit {
+                    VersionEdit::AddSlab(slab) => {
+                        trace!(target: "manifest", "replay add {:?}", slab);
+                        added.push(slab)
+                    }
+                    VersionEdit::RemoveSlab(path) => {
+                        trace!(target: "manifest", "replay remove {:?}", path);
+                        if let Some(idx) = slabs.iter().position(|slab| slab.path() == path) {
+                            slabs.remove(idx);
+                            position = Some(position.map_or(idx, |p: usize| p.min(idx)));
+                        }
+                    }
+                }
+            }
+            let position = position.unwrap_or(slabs.len());
+            slabs.splice(position..position, added);
+        }
+
+        Ok(slabs)
+    }
+
+    /// Check that the file is a manifest of the current version
+    ///
+    /// The edits of other versions can't be read reliably. Misreading them would
+    /// drop live tables, which are removed as unreferenced then.
+    fn read_header<R: io::Read>(r: &mut R, path: &path::Path) -> Result<()> {
+        let header: FileHeader = match binio::read_data_owned(r) {
+            Ok(header) => header,
+            Err(binio::Error::SerializationError) => {
+                return Err(Error::NotAManifest(path.to_path_buf()))
+            }
+            Err(binio::Error::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(Error::NotAManifest(path.to_path_buf()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        if header.stanza != STANZA.as_bytes() {
+            return Err(Error::NotAManifest(path.to_path_buf()));
+        }
+        if header.version != VERSION {
+            return Err(Error::UnsupportedVersion(header.version));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{binio, Error, FileHeader, Manifest, VersionEdit};
+    use super::{MANIFEST_FILE_NAME, STANZA, VERSION};
+    use crate::engine::storage::lsm::sstable::Slab;
+    use crate::engine::Key;
+    use tempfile::tempdir;
+
+    #[test]
+    fn replays_added_and_removed_slabs() {
+        let storage_dir = tempdir().unwrap();
+        let first = Slab::new(
+            0,
+            &storage_dir.path().join("first"),
+            Key::from("a"),
+            Key::from("f"),
+            0,
+        );
+        let second = Slab::new(
+            0,
+            &storage_dir.path().join("second"),
+            Key::from("c"),
+            Key::from("z"),
+            0,
+        );
+
+        {
+            let (mut manifest, slabs) = Manifest::open(storage_dir.path()).unwrap();
+            assert!(slabs.is_empty());
+
+            manifest.add(&first).unwrap();
+            manifest.add(&second).unwrap();
+            manifest.remove(&first).unwrap();
+        }
+
+        let (_, slabs) = Manifest::open(storage_dir.path()).unwrap();
+        assert_eq!(vec![second.clone()], slabs);
+
+        // opening the manifest again compacts it without losing any state
+        let (_, slabs) = Manifest::open(storage_dir.path()).unwrap();
+        assert_eq!(vec![second], slabs);
+    }
+
+    #[test]
+    fn rejects_other_versions_and_files() {
+        let storage_dir = tempdir().unwrap();
+        let manifest_path = storage_dir.path().join(MANIFEST_FILE_NAME);
+
+        let mut content = Vec::new();
+        binio::write_data(&mut content, FileHeader::new(STANZA, VERSION - 1)).unwrap();
+        std::fs::write(&manifest_path, &content).unwrap();
+        assert!(matches!(
+            Manifest::open(storage_dir.path()),
+            Err(Error::UnsupportedVersion(v)) if v == VERSION - 1
+        ));
+
+        let mut content = Vec::new();
+        binio::write_data(&mut content, FileHeader::new("r2d2::wal", VERSION)).unwrap();
+        std::fs::write(&manifest_path, &content).unwrap();
+        assert!(matches!(
+            Manifest::open(storage_dir.path()),
+            Err(Error::NotAManifest(_))
+        ));
+
+        std::fs::write(&manifest_path, b"this is not a manifest").unwrap();
+        assert!(matches!(
+            Manifest::open(storage_dir.path()),
+            Err(Error::NotAManifest(_))
+        ));
+    }
+
+    /// Write a manifest that adds the `first` and then the `second` slab
+    fn write_two_slabs(storage_dir: &std::path::Path) -> (Slab, Slab) {
+        let first = Slab::new(
+            0,
+            &storage_dir.join("first"),
+            Key::from("a"),
+            Key::from("f"),
+            0,
+        );
+        let second = Slab::new(
+            0,
+            &storage_dir.join("second"),
+            Key::from("c"),
+            Key::from("z"),
+            0,
+        );
+        let (mut manifest, _) = Manifest::open(storage_dir).unwrap();
+        manifest.add(&first).unwrap();
+        manifest.add(&second).unwrap();
+        (first, second)
+    }
+
+    #[test]
+    fn ignores_an_incomplete_record_at_the_end() {
+        let storage_dir = tempdir().unwrap();
+        let (first, _) = write_two_slabs(storage_dir.path());
+
+        let manifest_path = storage_dir.path().join(MANIFEST_FILE_NAME);
+        let content = std::fs::read(&manifest_path).unwrap();
+        std::fs::write(&manifest_path, &content[..content.len() - 3]).unwrap();
+
+        let (_, slabs) = Manifest::open(storage_dir.path()).unwrap();
+        assert_eq!(vec![first], slabs);
+    }
+
+    #[test]
+    fn fails_on_corrupted_records() {
+        let storage_dir = tempdir().unwrap();
+        write_two_slabs(storage_dir.path());
+
+        // the manifest starts with the compacted edits, which are none at first.
+        // The edits of a record are preceded by the frame length and the number of edits.
+        let mut start = Vec::new();
+        binio::write_data(&mut start, FileHeader::new(STANZA, VERSION)).unwrap();
+        binio::write_data(&mut start, Vec::<VersionEdit>::new()).unwrap();
+        let variant = start.len() + 4 + 8;
+        let manifest_path = storage_dir.path().join(MANIFEST_FILE_NAME);
+        let mut content = std::fs::read(&manifest_path).unwrap();
+        content[variant] = 42;
+        std::fs::write(&manifest_path, &content).unwrap();
+
+        assert!(matches!(
+            Manifest::open(storage_dir.path()),
+            Err(Error::CorruptedRecord(offset)) if offset == start.len() as u64
+        ));
+    }
+}
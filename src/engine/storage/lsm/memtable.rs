@@ -1,45 +1,311 @@
-use std::collections::BTreeMap;
+use crossbeam_skiplist::{map, SkipMap};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
+use super::sstable::Record;
+use super::{cursor, SequenceNumber};
 use crate::engine::{Key, Value};
 
 /// The memtable is the fast C0 system in the LSM.
 /// It has two main properties:
 /// 1. fast key based operations (lookup and insertion)
 /// 2. sorted iteration over keys (to dump to SSTables)
+///
+/// The memtable keeps track of the (approximate) amount of bytes it holds,
+/// which the LSM uses to decide when it's time to flush it to disk.
+///
+/// Deleted keys are kept as tombstones, so that they shadow
+/// older values of the key that might still exist in C1.
+///
+/// Every write is kept as a separate version of its key, tagged with the sequence
+/// number of the write. Reads are done at a sequence number and only see the most recent
+/// version of a key that isn't newer than that, which is what makes snapshots work.
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Entry {
     Tombstone,
     Val(Value),
 }
 
-pub struct BTreeMemtable(BTreeMap<Key, Entry>);
-pub type Iter<'a> = std::collections::btree_map::Iter<'a, Key, Entry>;
+impl Entry {
+    fn size(&self) -> usize {
+        match self {
+            Entry::Tombstone => 0,
+            Entry::Val(value) => value.len(),
+        }
+    }
+}
+
+/// A version of a key in the memtable
+///
+/// The versions are ordered by key and then by descending sequence number,
+/// so that the most recent version of a key comes first.
+type InternalKey = (Key, Reverse<SequenceNumber>);
+
+/// A memtable based on a concurrent skiplist
+///
+/// All operations work on a shared reference, so that readers don't block writers
+/// and vice versa. The memtable doesn't order concurrent writers though, the LSM
+/// takes care of that by writing one batch after the other.
+pub struct SkipListMemtable {
+    entries: SkipMap<InternalKey, Entry>,
+    size: AtomicUsize,
+}
+
+/// Iterator over all versions of all keys, in the order they are stored in SSTables
+pub struct Iter<'a> {
+    entries: map::Iter<'a, InternalKey, Entry>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Record;
 
-impl BTreeMemtable {
+    fn next(&mut self) -> Option<Self::Item> {
+        self.entries.next().map(|entry| {
+            let (key, Reverse(seq)) = entry.key();
+            (key.clone(), *seq, entry.value().clone())
+        })
+    }
+}
+
+impl Default for SkipListMemtable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SkipListMemtable {
     pub fn new() -> Self {
-        BTreeMemtable(BTreeMap::new())
+        SkipListMemtable {
+            entries: SkipMap::new(),
+            size: AtomicUsize::new(0),
+        }
     }
 
-    pub fn remove(&mut self, key: &Key) -> Option<Entry> {
-        self.0.remove(key)
+    /// Mark the key as deleted by adding a tombstone as its most recent version
+    ///
+    /// Returns the previously most recent entry of the key.
+    pub fn remove(&self, key: &Key, seq: SequenceNumber) -> Option<Entry> {
+        self.put(key.clone(), seq, Entry::Tombstone)
     }
 
-    pub fn insert(&mut self, key: Key, value: Value) -> Option<Entry> {
-        self.0.insert(key, Entry::Val(value))
+    /// Add the value as the most recent version of the key
+    ///
+    /// Returns the previously most recent entry of the key.
+    pub fn insert(&self, key: Key, seq: SequenceNumber, value: Value) -> Option<Entry> {
+        self.put(key, seq, Entry::Val(value))
     }
 
-    pub fn get(&self, key: &Key) -> Option<&Value> {
-        match self.0.get(key) {
+    fn put(&self, key: Key, seq: SequenceNumber, entry: Entry) -> Option<Entry> {
+        let previous = self.entry(&key, SequenceNumber::MAX);
+
+        let version_size = key.len() + std::mem::size_of::<SequenceNumber>();
+        let internal_key = (key, Reverse(seq));
+        if let Some(replaced) = self.entries.get(&internal_key) {
+            self.size
+                .fetch_sub(version_size + replaced.value().size(), Ordering::Relaxed);
+        }
+        self.size
+            .fetch_add(version_size + entry.size(), Ordering::Relaxed);
+        self.entries.insert(internal_key, entry);
+        previous
+    }
+
+    /// The most recent value of the key that is visible at the sequence number `seq`
+    pub fn get(&self, key: &Key, seq: SequenceNumber) -> Option<Value> {
+        match self.entry(key, seq) {
             Some(Entry::Val(value)) => Some(value),
             _ => None,
         }
     }
 
-    pub fn clear(&mut self) {
-        self.0.clear()
+    /// Find the most recent entry for the key that is visible at the sequence number `seq`,
+    /// which might be a tombstone
+    pub fn entry(&self, key: &Key, seq: SequenceNumber) -> Option<Entry> {
+        self.entries
+            .lower_bound(Bound::Included(&(key.clone(), Reverse(seq))))
+            .filter(|entry| &entry.key().0 == key)
+            .map(|entry| entry.value().clone())
+    }
+
+    /// The sequence number of the most recent version of the key, if there is any
+    pub fn latest_seq(&self, key: &Key) -> Option<SequenceNumber> {
+        self.entries
+            .lower_bound(Bound::Included(&(
+                key.clone(),
+                Reverse(SequenceNumber::MAX),
+            )))
+            .filter(|entry| &entry.key().0 == key)
+            .map(|entry| (entry.key().1).0)
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            entries: self.entries.iter(),
+        }
+    }
+
+    /// Create a cursor over the entries that are visible at the sequence number `seq`
+    ///
+    /// The cursor keeps the memtable alive, so that it can still be read
+    /// after the memtable has been flushed. It is exhausted until it's positioned.
+    pub fn cursor(self: &Arc<Self>, seq: SequenceNumber) -> Cursor {
+        Cursor {
+            memtable: self.clone(),
+            seq,
+            current: None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The approximate size of all keys and values in bytes
+    pub fn size(&self) -> usize {
+        self.size.load(Ordering::Relaxed)
+    }
+}
+
+/// A seekable position in the memtable
+///
+/// The cursor is positioned on the most recent version of a key that is visible
+/// at its sequence number. Keys without a visible version are skipped.
+///
+/// Versions that are added while the cursor moves are only seen if they are visible
+/// at the sequence number of the cursor.
+pub struct Cursor {
+    memtable: Arc<SkipListMemtable>,
+    seq: SequenceNumber,
+    current: Option<(Key, Entry)>,
+}
+
+impl Cursor {
+    /// The most recent visible version of the first key at or after `start`
+    fn visible_from(&self, start: Bound<InternalKey>) -> Option<(Key, Entry)> {
+        self.memtable
+            .entries
+            .range((start, Bound::Unbounded))
+            .find(|entry| (entry.key().1).0 <= self.seq)
+            .map(|entry| (entry.key().0.clone(), entry.value().clone()))
+    }
+
+    /// The most recent visible version of the last key before `end`
+    fn visible_before(&self, end: Bound<InternalKey>) -> Option<(Key, Entry)> {
+        // going backwards, the versions of a key show up oldest first. If the oldest
+        // version of a key isn't visible, none of its versions are.
+        let oldest = self
+            .memtable
+            .entries
+            .range((Bound::Unbounded, end))
+            .rev()
+            .find(|entry| (entry.key().1).0 <= self.seq)?;
+        self.visible_from(Bound::Included((oldest.key().0.clone(), Reverse(self.seq))))
+    }
+}
+
+impl cursor::Cursor for Cursor {
+    fn seek_to_first(&mut self) -> super::Result<()> {
+        self.current = self.visible_from(Bound::Unbounded);
+        Ok(())
+    }
+
+    fn seek_to_last(&mut self) -> super::Result<()> {
+        self.current = self.visible_before(Bound::Unbounded);
+        Ok(())
+    }
+
+    fn seek(&mut self, k: &Key) -> super::Result<()> {
+        self.current =
+            self.visible_from(Bound::Included((k.clone(), Reverse(SequenceNumber::MAX))));
+        Ok(())
+    }
+
+    fn seek_for_prev(&mut self, k: &Key) -> super::Result<()> {
+        self.current = self.visible_before(Bound::Included((k.clone(), Reverse(0))));
+        Ok(())
+    }
+
+    fn next(&mut self) -> super::Result<()> {
+        if let Some((key, _)) = self.current.take() {
+            self.current = self.visible_from(Bound::Excluded((key, Reverse(0))));
+        }
+        Ok(())
+    }
+
+    fn prev(&mut self) -> super::Result<()> {
+        if let Some((key, _)) = self.current.take() {
+            self.current =
+                self.visible_before(Bound::Excluded((key, Reverse(SequenceNumber::MAX))));
+        }
+        Ok(())
+    }
+
+    fn current(&self) -> Option<(&Key, &Entry)> {
+        self.current.as_ref().map(|(key, entry)| (key, entry))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Entry, SkipListMemtable};
+    use crate::engine::storage::lsm::cursor::Cursor;
+    use crate::engine::{Key, Value};
+    use std::sync::Arc;
+
+    #[test]
+    fn reads_see_the_most_recent_version_at_their_sequence_number() {
+        let memtable = SkipListMemtable::new();
+        memtable.insert(Key::from("a"), 1, Value::from("first"));
+        memtable.insert(Key::from("b"), 2, Value::from("first"));
+        memtable.insert(Key::from("a"), 3, Value::from("second"));
+        memtable.remove(&Key::from("b"), 4);
+
+        assert_eq!(None, memtable.get(&Key::from("a"), 0));
+        assert_eq!(Some(Value::from("first")), memtable.get(&Key::from("a"), 2));
+        assert_eq!(
+            Some(Value::from("second")),
+            memtable.get(&Key::from("a"), 4)
+        );
+        assert_eq!(Some(Entry::Tombstone), memtable.entry(&Key::from("b"), 4));
+        assert_eq!(Some(Value::from("first")), memtable.get(&Key::from("b"), 3));
     }
 
-    pub fn iter(&self) -> Iter {
-        self.0.iter()
+    #[test]
+    fn cursor_skips_versions_that_are_not_visible() {
+        let memtable = Arc::new(SkipListMemtable::new());
+        memtable.insert(Key::from("a"), 1, Value::from("a1"));
+        memtable.insert(Key::from("b"), 3, Value::from("b3"));
+        memtable.insert(Key::from("c"), 2, Value::from("c2"));
+        memtable.insert(Key::from("c"), 4, Value::from("c4"));
+
+        let visible =
+            |cursor: &super::Cursor| cursor.current().map(|(k, e)| (k.clone(), e.clone()));
+        let entry = |k: &str, v: &str| Some((Key::from(k), Entry::Val(Value::from(v))));
+
+        let mut cursor = memtable.cursor(2);
+        cursor.seek_to_first().unwrap();
+        assert_eq!(entry("a", "a1"), visible(&cursor));
+        cursor.next().unwrap();
+        assert_eq!(entry("c", "c2"), visible(&cursor));
+        cursor.next().unwrap();
+        assert_eq!(None, visible(&cursor));
+
+        cursor.seek_to_last().unwrap();
+        assert_eq!(entry("c", "c2"), visible(&cursor));
+        cursor.prev().unwrap();
+        assert_eq!(entry("a", "a1"), visible(&cursor));
+
+        cursor.seek_for_prev(&Key::from("b")).unwrap();
+        assert_eq!(entry("a", "a1"), visible(&cursor));
+
+        let mut cursor = memtable.cursor(4);
+        cursor.seek(&Key::from("b")).unwrap();
+        assert_eq!(entry("b", "b3"), visible(&cursor));
+        cursor.next().unwrap();
+        assert_eq!(entry("c", "c4"), visible(&cursor));
     }
 }
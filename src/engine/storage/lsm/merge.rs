@@ -0,0 +1,160 @@
+//! Merging of sorted runs of entries
+//!
+//! The LSM stores entries for the same key in several places (the memtable and
+//! possibly many SSTables). The merging iterator combines several sorted sources
+//! into a single sorted stream. All versions of a key are kept, ordered from the
+//! most recent to the oldest one, so that the caller can decide which versions are still needed.
+//! If the same version of a key exists in more than one source, the entry of the source that
+//! comes first wins. Sources must thus be provided from newest to oldest.
+use super::memtable::Entry;
+use super::sstable::Record;
+use super::SequenceNumber;
+use crate::engine::Key;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+/// The position of a source in the heap, ordered like the records of an SSTable
+type Head = (Key, Reverse<SequenceNumber>, usize);
+
+pub struct MergingIterator<I> {
+    sources: Vec<I>,
+    heads: Vec<Option<Entry>>,
+    heap: BinaryHeap<Reverse<Head>>,
+    initialized: bool,
+}
+
+impl<I, E> MergingIterator<I>
+where
+    I: Iterator<Item = Result<Record, E>>,
+{
+    /// Create a new merging iterator from sources that are ordered from newest to oldest
+    pub fn new(sources: Vec<I>) -> Self {
+        let heads = sources.iter().map(|_| None).collect();
+
+        MergingIterator {
+            sources,
+            heads,
+            heap: BinaryHeap::new(),
+            initialized: false,
+        }
+    }
+
+    fn advance(&mut self, source: usize) -> Result<(), E> {
+        match self.sources[source].next() {
+            Some(Ok((key, seq, entry))) => {
+                self.heads[source] = Some(entry);
+                self.heap.push(Reverse((key, Reverse(seq), source)));
+            }
+            Some(Err(e)) => return Err(e),
+            None => self.heads[source] = None,
+        }
+        Ok(())
+    }
+
+    fn initialize(&mut self) -> Result<(), E> {
+        self.initialized = true;
+        for source in 0..self.sources.len() {
+            self.advance(source)?;
+        }
+        Ok(())
+    }
+
+    fn next_record(&mut self) -> Result<Option<Record>, E> {
+        if !self.initialized {
+            self.initialize()?;
+        }
+
+        let Reverse((key, Reverse(seq), source)) = match self.heap.pop() {
+            Some(head) => head,
+            None => return Ok(None),
+        };
+        let entry = self.heads[source].take().unwrap();
+        self.advance(source)?;
+
+        // skip copies of the same version in older sources
+        while let Some(Reverse((next_key, Reverse(next_seq), _))) = self.heap.peek() {
+            if next_key != &key || *next_seq != seq {
+                break;
+            }
+            let Reverse((_, _, older)) = self.heap.pop().unwrap();
+            self.advance(older)?;
+        }
+
+        Ok(Some((key, seq, entry)))
+    }
+}
+
+impl<I, E> Iterator for MergingIterator<I>
+where
+    I: Iterator<Item = Result<Record, E>>,
+{
+    type Item = Result<Record, E>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MergingIterator;
+    use crate::engine::storage::lsm::memtable::Entry;
+    use crate::engine::storage::lsm::sstable::Record;
+    use crate::engine::{Key, Value};
+
+    type Version<'a> = (&'a str, u64, Option<&'a str>);
+
+    fn source(records: &[Version]) -> std::vec::IntoIter<Result<Record, ()>> {
+        records
+            .iter()
+            .map(|(k, seq, v)| {
+                let entry = v.map_or(Entry::Tombstone, |v| Entry::Val(Value::from(v)));
+                Ok((Key::from(*k), *seq, entry))
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn versions_are_ordered_from_newest_to_oldest() {
+        let newest = source(&[("b", 5, Some("new")), ("d", 6, None)]);
+        let oldest = source(&[
+            ("a", 1, Some("old")),
+            ("b", 2, Some("old")),
+            ("d", 3, Some("old")),
+        ]);
+
+        let merged: Vec<Record> = MergingIterator::new(vec![newest, oldest])
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(
+            vec![
+                (Key::from("a"), 1, Entry::Val(Value::from("old"))),
+                (Key::from("b"), 5, Entry::Val(Value::from("new"))),
+                (Key::from("b"), 2, Entry::Val(Value::from("old"))),
+                (Key::from("d"), 6, Entry::Tombstone),
+                (Key::from("d"), 3, Entry::Val(Value::from("old"))),
+            ],
+            merged
+        );
+    }
+
+    #[test]
+    fn copies_of_a_version_are_merged() {
+        let newest = source(&[("a", 1, Some("a")), ("b", 2, Some("b"))]);
+        let oldest = source(&[("a", 1, Some("a"))]);
+
+        let merged: Vec<Record> = MergingIterator::new(vec![newest, oldest])
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(
+            vec![
+                (Key::from("a"), 1, Entry::Val(Value::from("a"))),
+                (Key::from("b"), 2, Entry::Val(Value::from("b"))),
+            ],
+            merged
+        );
+    }
+}
@@ -0,0 +1,39 @@
+//! The outcome of the recovery from the WALs
+//!
+//! When the LSM starts after a crash, it replays the WALs that are left behind.
+//! Depending on the `RecoveryMode`, damaged WALs either fail the recovery or the
+//! damaged parts are dropped. The report tells what has been replayed and what has been lost.
+use std::fmt;
+use std::path::PathBuf;
+
+/// What the recovery has replayed from the WALs and what it has dropped
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RecoveryReport {
+    /// The number of operations that have been replayed
+    pub replayed: u64,
+    /// The corrupted records that have been skipped, as the WAL and the offset of the record
+    ///
+    /// The number of operations in a corrupted record is unknown.
+    pub skipped: Vec<(PathBuf, u64)>,
+    /// The damaged tails that have been cut off, as the WAL and the offset it's been cut at
+    pub truncated: Vec<(PathBuf, u64)>,
+}
+
+impl RecoveryReport {
+    /// Whether all records of the WALs have been replayed
+    pub fn is_complete(&self) -> bool {
+        self.skipped.is_empty() && self.truncated.is_empty()
+    }
+}
+
+impl fmt::Display for RecoveryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "replayed {} operations, skipped {} corrupted records, truncated {} WALs",
+            self.replayed,
+            self.skipped.len(),
+            self.truncated.len()
+        )
+    }
+}
@@ -0,0 +1,114 @@
+//! Consistent read snapshots
+//!
+//! Every write of the LSM is tagged with a sequence number, which grows monotonically.
+//! A snapshot is nothing but the sequence number of the most recent write at the time
+//! the snapshot has been taken. Reads through a snapshot ignore all versions of a key
+//! with a greater sequence number, so they see the state as it was when the snapshot was taken.
+//!
+//! Older versions of a key are usually discarded when tables are compacted.
+//! That's why the live snapshots are registered here, so that the compaction can keep
+//! all versions that a live snapshot still needs.
+use super::{Result, SequenceNumber};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The registry of live snapshots
+#[derive(Default)]
+pub struct Snapshots {
+    /// The number of live snapshots per sequence number
+    live: Mutex<BTreeMap<SequenceNumber, usize>>,
+}
+
+impl Snapshots {
+    pub fn new() -> Self {
+        Snapshots::default()
+    }
+
+    /// Take a snapshot at the sequence number `seq`, which stays registered until it's dropped
+    pub fn acquire(self: &Arc<Self>, seq: SequenceNumber) -> Result<Snapshot> {
+        *self.live.lock()?.entry(seq).or_insert(0) += 1;
+
+        Ok(Snapshot {
+            seq,
+            snapshots: self.clone(),
+        })
+    }
+
+    /// Take a snapshot at the most recent sequence number that has been published in `last_seq`
+    ///
+    /// The sequence number is read while the registry is locked. A compaction picks its tables
+    /// before it looks up the oldest snapshot, so either it sees the snapshot or the snapshot
+    /// is newer than all the data that is compacted.
+    pub fn acquire_latest(self: &Arc<Self>, last_seq: &AtomicU64) -> Result<Snapshot> {
+        let mut live = self.live.lock()?;
+        let seq = last_seq.load(Ordering::Acquire);
+        *live.entry(seq).or_insert(0) += 1;
+
+        Ok(Snapshot {
+            seq,
+            snapshots: self.clone(),
+        })
+    }
+
+    /// The sequence number of the oldest live snapshot, if there is any
+    pub fn oldest(&self) -> Result<Option<SequenceNumber>> {
+        Ok(self.live.lock()?.keys().next().copied())
+    }
+
+    fn release(&self, seq: SequenceNumber) {
+        if let Ok(mut live) = self.live.lock() {
+            if let Some(count) = live.get_mut(&seq) {
+                *count -= 1;
+                if *count == 0 {
+                    live.remove(&seq);
+                }
+            }
+        }
+    }
+}
+
+/// A handle to a frozen view of the LSM
+///
+/// The view stays consistent for as long as the handle lives.
+pub struct Snapshot {
+    seq: SequenceNumber,
+    snapshots: Arc<Snapshots>,
+}
+
+impl Snapshot {
+    /// The sequence number of the most recent write that is visible through the snapshot
+    pub fn sequence(&self) -> SequenceNumber {
+        self.seq
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.snapshots.release(self.seq);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Snapshots;
+    use std::sync::Arc;
+
+    #[test]
+    fn oldest_snapshot_is_tracked_until_dropped() {
+        let snapshots = Arc::new(Snapshots::new());
+        assert_eq!(None, snapshots.oldest().unwrap());
+
+        let first = snapshots.acquire(5).unwrap();
+        let second = snapshots.acquire(5).unwrap();
+        let third = snapshots.acquire(9).unwrap();
+        assert_eq!(Some(5), snapshots.oldest().unwrap());
+
+        drop(first);
+        assert_eq!(Some(5), snapshots.oldest().unwrap());
+        drop(second);
+        assert_eq!(Some(9), snapshots.oldest().unwrap());
+        drop(third);
+        assert_eq!(None, snapshots.oldest().unwrap());
+    }
+}
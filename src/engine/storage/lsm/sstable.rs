@@ -4,30 +4,63 @@
 //! of key-value pairs. In the LSM architecture SSTables are created as a result
 //! of flushing the current in-memory table to disk.
 //!
-//! SSTables maintain and index which is used to access data on disk faster.
+//! The key value pairs of an SSTable are grouped into data blocks of roughly equal size.
+//! SSTables maintain a sparse index with one entry per block, which is used to access
+//! data on disk faster. Since the index only grows with the number of blocks rather
+//! than the number of keys, it stays small enough to be kept in memory.
 //! Once an SSTable has been written it is immutable and must not be changed anymore.
 //!
-//! This module also provides functionality to run compaction on the SSTables and thus
-//! merge intermediate tables together.
+//! Every block carries a CRC32C checksum, so that silent corruption on disk is detected
+//! when the block is read, rather than returning wrong data. `SSTable::verify` checks
+//! all blocks of a table at once and reports the ones that are corrupted.
+//!
+//! Merging tables together is the job of the `compaction` module, which uses
+//! the sequential access to all entries of a table that this module provides.
 //!
 use super::binary_io as binio;
+use super::bloom::{self, BloomFilter};
+use super::cache::BlockCache;
+use super::cursor;
+use super::memtable::Entry;
+use super::SequenceNumber;
 use crate::engine::{Key, Value};
+use byteorder::{ByteOrder, LittleEndian};
+use crc::{Crc, CRC_32_ISCSI};
 use log::{info, trace};
 use serde::{Deserialize, Serialize};
-use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::cmp::{Ordering, Reverse};
+use std::fmt;
 use std::fs;
 use std::fs::OpenOptions;
 use std::io;
 use std::io::{Seek, SeekFrom, Write};
+use std::ops::{Bound, RangeBounds};
+use std::os::unix::fs::FileExt;
 use std::path;
+use std::sync::Arc;
 use thiserror::Error;
 
 type Offset = usize;
-type Level = u8;
+pub type Level = u8;
 type Result<T> = std::result::Result<T, Error>;
 
 const STANZA: &str = "r2d2::sstable";
+const VERSION: u8 = 0x8;
+/// The version of the initial release, which can't be read anymore
+///
+/// The initial release never wrote tables through the LSM, all of its data is in the WAL.
+/// Tables that have been written with its `Writer` directly have to be written again
+/// with the current `Writer` from their key-value pairs.
+const INITIAL_VERSION: u8 = 0x1;
+/// The size of the footer: the trailer offset, the version and the stanza
+const FOOTER_SIZE: usize = 8 + 1 + STANZA.len();
+/// Tables of the initial version end with the version and the stanza of their trailer,
+/// followed by the trailer offset
+const INITIAL_FOOTER_SIZE: usize = 1 + 8 + STANZA.len() + 4;
+const FILE_EXTENSION: &str = "sst";
+/// The checksum of the blocks is CRC32C
+const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+const CHECKSUM_SIZE: usize = 4;
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -39,6 +72,62 @@ pub enum Error {
     EmptyTable,
     #[error("SealedTableError")]
     SealedTableError,
+    #[error("UnorderedKeyError: keys must be appended in ascending order, versions of a key from newest to oldest")]
+    UnorderedKey,
+    #[error("NotAnSSTable: {0:?} is not an SSTable")]
+    NotAnSSTable(path::PathBuf),
+    #[error("UnsupportedVersion: version {0} of the SSTable format is not supported")]
+    UnsupportedVersion(u8),
+    #[error("CorruptedBlock: {0}")]
+    CorruptedBlock(CorruptedRange),
+}
+
+/// A range of an SSTable file that doesn't match its checksum
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorruptedRange {
+    pub path: path::PathBuf,
+    pub offset: u64,
+    /// The size of the range in bytes
+    pub size: u64,
+}
+
+impl fmt::Display for CorruptedRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the {} bytes at offset {} of {:?} don't match their checksum",
+            self.size, self.offset, self.path
+        )
+    }
+}
+
+/// Options that control how an `SSTable` is read
+#[derive(Debug, Clone, Copy)]
+pub struct ReadOptions {
+    /// Whether the checksums of blocks are verified when they are read
+    pub verify_checksums: bool,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        ReadOptions {
+            verify_checksums: true,
+        }
+    }
+}
+
+/// The path of the SSTable file with the given `id` inside of `dir`
+pub fn table_path(dir: &path::Path, id: u64) -> path::PathBuf {
+    dir.join(format!("{:08}.{}", id, FILE_EXTENSION))
+}
+
+/// Extract the id from the path of an SSTable file.
+/// Returns `None` if the path doesn't point to an SSTable file.
+pub fn table_id(path: &path::Path) -> Option<u64> {
+    match path.extension() {
+        Some(ext) if ext == FILE_EXTENSION => path.file_stem()?.to_str()?.parse().ok(),
+        _ => None,
+    }
 }
 
 /// A Slab is meta information about an existing SSTable
@@ -47,7 +136,7 @@ pub enum Error {
 /// those to find the SSTable that might contain the key that
 /// it's looking for. If the slab covers the key it gives access
 /// to the associated SSTable which can then be used to do the lookup.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Slab {
     /// The level of the slab and its associated SSTable
     pub level: Level,
@@ -57,6 +146,13 @@ pub struct Slab {
     max_key: Key,
     /// The path to the SSTable file
     path: path::PathBuf,
+    /// The size of the SSTable file in bytes
+    size: u64,
+    /// The greatest sequence number of the records in the SSTable
+    max_seq: SequenceNumber,
+    /// The bloom filter of the SSTable, which is kept in memory but stored with the table
+    #[serde(skip)]
+    bloom_filter: Option<Arc<BloomFilter>>,
 }
 
 impl PartialOrd for Slab {
@@ -66,12 +162,15 @@ impl PartialOrd for Slab {
 }
 
 impl Slab {
-    pub fn new(level: Level, path: &path::Path, min_key: Key, max_key: Key) -> Slab {
+    pub fn new(level: Level, path: &path::Path, min_key: Key, max_key: Key, size: u64) -> Slab {
         Slab {
             level,
             path: path.to_owned(),
             min_key,
             max_key,
+            size,
+            max_seq: 0,
+            bloom_filter: None,
         }
     }
 
@@ -88,16 +187,93 @@ impl Slab {
         }
     }
 
+    /// Check if the provided `key` is within the key range of the associated `SSTable`.
+    pub fn covers(&self, k: &Key) -> bool {
+        k >= &self.min_key && k <= &self.max_key
+    }
+
     /// Check if the provided `key` might be found in the associated `SSTable`.
     /// If this function returns false, the key is definitely not in the `SSTable`.
     /// If this function returns true, the key might be in the `SSTable`.
-    pub fn covers(&self, k: &Key) -> bool {
-        k >= &self.min_key && k <= &self.max_key
+    ///
+    /// In addition to the key range, this consults the bloom filter if it has been loaded.
+    pub fn may_contain(&self, k: &Key) -> bool {
+        self.covers(k)
+            && match &self.bloom_filter {
+                Some(filter) => filter.may_contain(k),
+                None => true,
+            }
+    }
+
+    /// Read the bloom filter of the associated `SSTable` into memory
+    pub fn load_bloom_filter(&mut self) -> Result<()> {
+        let reader = Reader::open(&self.path, ReadOptions::default())?;
+        self.bloom_filter = Some(Arc::new(reader.read_bloom_filter()?));
+        Ok(())
+    }
+
+    /// The smallest key that is stored in this slab
+    pub fn min_key(&self) -> &Key {
+        &self.min_key
+    }
+
+    /// The greatest key that is stored in this slab
+    pub fn max_key(&self) -> &Key {
+        &self.max_key
+    }
+
+    /// The path to the associated SSTable file
+    pub fn path(&self) -> &path::Path {
+        &self.path
+    }
+
+    /// The size of the associated SSTable file in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The greatest sequence number of the records in the associated `SSTable`
+    pub fn max_seq(&self) -> SequenceNumber {
+        self.max_seq
+    }
+
+    /// Check if the slab's key range intersects the range from `min_key` to `max_key`
+    pub fn overlaps(&self, min_key: &Key, max_key: &Key) -> bool {
+        &self.min_key <= max_key && &self.max_key >= min_key
     }
 
-    /// Open the associated `SSTable`
+    /// Check if the slab's key range intersects the provided `range`
+    pub fn intersects<R: RangeBounds<Key>>(&self, range: &R) -> bool {
+        let after_start = match range.start_bound() {
+            Bound::Included(k) => &self.max_key >= k,
+            Bound::Excluded(k) => &self.max_key > k,
+            Bound::Unbounded => true,
+        };
+        let before_end = match range.end_bound() {
+            Bound::Included(k) => &self.min_key <= k,
+            Bound::Excluded(k) => &self.min_key < k,
+            Bound::Unbounded => true,
+        };
+        after_start && before_end
+    }
+
+    /// Open the associated `SSTable`, which verifies the checksums of the blocks it reads
     pub fn sstable(&self) -> Result<SSTable> {
-        SSTable::open(&self.path)
+        self.sstable_with_options(ReadOptions::default())
+    }
+
+    /// Open the associated `SSTable`, using the provided `options`
+    pub fn sstable_with_options(&self, options: ReadOptions) -> Result<SSTable> {
+        SSTable::open(&self.path, options, None)
+    }
+
+    /// Open the associated `SSTable`, which reads its data blocks through the `block_cache`
+    pub fn sstable_with_cache(
+        &self,
+        options: ReadOptions,
+        block_cache: &Arc<BlockCache>,
+    ) -> Result<SSTable> {
+        SSTable::open(&self.path, options, Some(block_cache.clone()))
     }
 }
 
@@ -108,55 +284,442 @@ impl Slab {
 ///
 /// You can open an SSTable by calling the `sstable()` method of a `Slab`.
 pub struct SSTable {
-    index: HashMap<Key, Offset>,
+    index: Vec<BlockHandle>,
     path: path::PathBuf,
     reader: Reader,
+    block_cache: Option<Arc<BlockCache>>,
 }
 
 impl SSTable {
-    /// Lookup the value for the provided `Key` `k`
+    /// Lookup the most recent entry for the provided `Key` `k` that is visible at the sequence number `seq`
     ///
     /// This method performs a lookup in the file that backs the SSTable
-    /// returning the value that is associated with the provided key, if
-    /// it exists.
-    pub fn get(&mut self, k: &Key) -> Result<Option<Value>> {
-        match self.index.get(k) {
-            Some(offset) => {
-                trace!("found key {:?} at offset: {}", k, offset);
-                let record = self.reader.read_record(*offset)?;
-                Ok(Some(record))
+    /// returning the entry that is associated with the provided key, if
+    /// it exists. The entry is a tombstone if the key has been deleted.
+    ///
+    /// The sparse index identifies the first block that might contain the key,
+    /// which is then read and searched. Only if the key has so many versions that they
+    /// continue in the following blocks, those are read as well.
+    pub fn get(&self, k: &Key, seq: SequenceNumber) -> Result<Option<Entry>> {
+        Ok(self.find(k, seq)?.map(|(_, entry)| entry))
+    }
+
+    /// The sequence number of the most recent version of the key in the table, if there is any
+    pub fn latest_seq(&self, k: &Key) -> Result<Option<SequenceNumber>> {
+        Ok(self.find(k, SequenceNumber::MAX)?.map(|(seq, _)| seq))
+    }
+
+    /// Find the most recent version of the key that is visible at the sequence number `seq`
+    fn find(&self, k: &Key, seq: SequenceNumber) -> Result<Option<(SequenceNumber, Entry)>> {
+        let mut block = self.index.partition_point(|handle| &handle.last_key < k);
+        while block < self.index.len() {
+            trace!("key {:?} might be in block {}", k, block);
+            let data = self.read_block(block)?;
+            let position = data
+                .records
+                .partition_point(|record| precedes(record, k, seq));
+            if let Some((key, found, entry)) = data.records.get(position) {
+                return Ok(if key == k {
+                    Some((*found, entry.clone()))
+                } else {
+                    None
+                });
             }
-            None => {
-                trace!("ket {:?} not found", k);
-                Ok(None)
+
+            // all versions of the key in this block are too recent,
+            // the older ones might continue in the next block
+            if &self.index[block].last_key != k {
+                break;
+            }
+            block += 1;
+        }
+
+        trace!("key {:?} is not in the table at sequence number {}", k, seq);
+        Ok(None)
+    }
+
+    /// Create a cursor over the entries of the table that are visible at the sequence number `seq`
+    ///
+    /// The cursor is exhausted until it's positioned with one of its seek methods.
+    pub fn cursor(self, seq: SequenceNumber) -> Cursor {
+        let block = self.index.len();
+        Cursor {
+            table: self,
+            seq,
+            block,
+            records: Vec::new(),
+            position: 0,
+        }
+    }
+
+    /// The path of the file that backs the table
+    pub fn path(&self) -> &path::Path {
+        &self.path
+    }
+
+    /// The number of data blocks in the table
+    pub fn block_count(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Check the checksums of all blocks of the table, no matter the read options
+    ///
+    /// Returns the ranges of the blocks that are corrupted, which is empty for an intact table.
+    /// The blocks are read from disk directly, bypassing the block cache.
+    /// Tables that have been written without checksums have nothing to verify.
+    pub fn verify(&self) -> Result<Vec<CorruptedRange>> {
+        let data = self.index.iter().map(|handle| (handle.offset, handle.size));
+        let extents: Vec<_> = data.chain(self.reader.control_blocks()).collect();
+
+        let mut corrupted = Vec::new();
+        for (offset, size) in extents {
+            match self.reader.read_frame(offset, size, true) {
+                Ok(_) => (),
+                Err(Error::CorruptedBlock(range)) => corrupted.push(range),
+                Err(e) => return Err(e),
             }
         }
+        Ok(corrupted)
     }
 
-    fn open(path: &path::Path) -> Result<SSTable> {
-        let mut reader = Reader::open(path)?;
-        let mut index: HashMap<Key, Offset> = HashMap::new();
-        reader.read_index_into(&mut index)?;
+    fn open(
+        path: &path::Path,
+        options: ReadOptions,
+        block_cache: Option<Arc<BlockCache>>,
+    ) -> Result<SSTable> {
+        let reader = Reader::open(path, options)?;
+        let index = reader.read_index()?;
 
         Ok(SSTable {
             index,
             path: path.to_path_buf(),
             reader,
+            block_cache,
         })
     }
+
+    /// The records of the `block`, which are read through the block cache if there is one
+    fn read_block(&self, block: usize) -> Result<Arc<Block>> {
+        let handle = &self.index[block];
+        let block = match &self.block_cache {
+            Some(cache) => match cache.get(&self.path, handle.offset) {
+                Some(block) => block,
+                None => {
+                    let block = Arc::new(self.reader.read_block(handle)?);
+                    cache.insert(&self.path, handle.offset, block.clone(), handle.size);
+                    block
+                }
+            },
+            None => Arc::new(self.reader.read_block(handle)?),
+        };
+        Ok(block)
+    }
+}
+
+/// Check if the `record` is stored before the most recent version of `k` that is visible at `seq`
+fn precedes((key, seq, _): &Record, k: &Key, visible: SequenceNumber) -> bool {
+    (key, Reverse(*seq)) < (k, Reverse(visible))
+}
+
+/// A cursor is a seekable position in an `SSTable`
+///
+/// It holds the data block of its current position in memory and moves to the
+/// neighbouring blocks in both directions. Like iteration, the cursor reads blocks
+/// from disk directly.
+///
+/// The cursor is positioned on the most recent version of a key that is visible at
+/// its sequence number. Keys without a visible version are skipped.
+pub struct Cursor {
+    table: SSTable,
+    seq: SequenceNumber,
+    /// The index of the current block, which is the number of blocks if the cursor is exhausted
+    block: usize,
+    records: Vec<Record>,
+    position: usize,
+}
+
+impl cursor::Cursor for Cursor {
+    fn seek_to_first(&mut self) -> super::Result<()> {
+        self.load_block(0)?;
+        Ok(self.skip_invisible_forward()?)
+    }
+
+    fn seek_to_last(&mut self) -> super::Result<()> {
+        self.seek_to_last_record()?;
+        Ok(self.skip_invisible_backward()?)
+    }
+
+    fn seek(&mut self, k: &Key) -> super::Result<()> {
+        self.seek_version(k, SequenceNumber::MAX)?;
+        Ok(self.skip_invisible_forward()?)
+    }
+
+    fn seek_for_prev(&mut self, k: &Key) -> super::Result<()> {
+        // find the first record after all versions of `k` and step back from there
+        let block = self
+            .table
+            .index
+            .partition_point(|handle| &handle.last_key <= k);
+        self.load_block(block)?;
+        self.position = self.records.partition_point(|(key, _, _)| key <= k);
+        if self.position < self.records.len() {
+            self.step_back()?;
+        } else {
+            self.seek_to_last_record()?;
+        }
+        Ok(self.skip_invisible_backward()?)
+    }
+
+    fn next(&mut self) -> super::Result<()> {
+        let key = match self.record() {
+            Some((key, _, _)) => key.clone(),
+            None => return Ok(()),
+        };
+
+        // skip the older versions of the current key
+        while matches!(self.record(), Some((k, _, _)) if k == &key) {
+            self.step_forward()?;
+        }
+        Ok(self.skip_invisible_forward()?)
+    }
+
+    /// Moving back from the first entry exhausts the cursor
+    fn prev(&mut self) -> super::Result<()> {
+        let key = match self.record() {
+            Some((key, _, _)) => key.clone(),
+            None => return Ok(()),
+        };
+
+        // skip the more recent versions of the current key
+        while matches!(self.record(), Some((k, _, _)) if k == &key) {
+            self.step_back()?;
+        }
+        Ok(self.skip_invisible_backward()?)
+    }
+
+    fn current(&self) -> Option<(&Key, &Entry)> {
+        self.record().map(|(key, _, entry)| (key, entry))
+    }
+}
+
+impl Cursor {
+    fn record(&self) -> Option<&Record> {
+        self.records.get(self.position)
+    }
+
+    /// Move forward until the cursor is positioned on a visible record
+    ///
+    /// Versions of a key are stored from newest to oldest, so the first visible
+    /// record of a key is its most recent visible version.
+    fn skip_invisible_forward(&mut self) -> Result<()> {
+        while matches!(self.record(), Some((_, seq, _)) if *seq > self.seq) {
+            self.step_forward()?;
+        }
+        Ok(())
+    }
+
+    /// Move backward from the oldest version of a key until the cursor is positioned
+    /// on the most recent visible version of a key
+    fn skip_invisible_backward(&mut self) -> Result<()> {
+        while let Some((key, seq, _)) = self.record() {
+            let key = key.clone();
+            if *seq <= self.seq {
+                // the oldest version of the key is visible, so there is a most recent visible one
+                return self.seek_version(&key, self.seq);
+            }
+
+            // if the oldest version isn't visible, none of the versions of the key are
+            while matches!(self.record(), Some((k, _, _)) if k == &key) {
+                self.step_back()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Position the cursor at the first record that doesn't precede the version `seq` of `k`
+    fn seek_version(&mut self, k: &Key, seq: SequenceNumber) -> Result<()> {
+        let block = self
+            .table
+            .index
+            .partition_point(|handle| &handle.last_key < k);
+        self.load_block(block)?;
+        loop {
+            self.position = self
+                .records
+                .partition_point(|record| precedes(record, k, seq));
+            if self.position < self.records.len() || self.block >= self.table.index.len() {
+                return Ok(());
+            }
+            // the versions of the key continue in the next block
+            self.load_block(self.block + 1)?;
+        }
+    }
+
+    fn seek_to_last_record(&mut self) -> Result<()> {
+        match self.table.index.len() {
+            0 => self.load_block(0),
+            blocks => self.load_last_record(blocks - 1),
+        }
+    }
+
+    fn step_forward(&mut self) -> Result<()> {
+        self.position += 1;
+        if self.position >= self.records.len() {
+            self.load_block(self.block + 1)?;
+        }
+        Ok(())
+    }
+
+    fn step_back(&mut self) -> Result<()> {
+        if self.position > 0 {
+            self.position -= 1;
+            Ok(())
+        } else if self.block > 0 {
+            self.load_last_record(self.block - 1)
+        } else {
+            self.load_block(self.table.index.len())
+        }
+    }
+
+    fn load_last_record(&mut self, block: usize) -> Result<()> {
+        self.load_block(block)?;
+        self.position = self.records.len().saturating_sub(1);
+        Ok(())
+    }
+
+    fn load_block(&mut self, block: usize) -> Result<()> {
+        let block = block.min(self.table.index.len());
+        self.position = 0;
+        if block == self.block && !self.records.is_empty() {
+            return Ok(());
+        }
+
+        self.block = block;
+        self.records = match self.table.index.get(self.block) {
+            Some(handle) => self.table.reader.read_block(handle)?.records,
+            None => Vec::new(),
+        };
+        Ok(())
+    }
+}
+
+/// Iterate over all entries of the table in ascending key order,
+/// with all versions of a key from the most recent to the oldest one
+///
+/// The blocks are read from disk directly, so that a full scan doesn't evict
+/// the frequently used blocks from the block cache.
+impl IntoIterator for SSTable {
+    type Item = Result<Record>;
+    type IntoIter = Iter;
+
+    fn into_iter(self) -> Iter {
+        Iter {
+            table: self,
+            next_block: 0,
+            records: Vec::new().into_iter(),
+        }
+    }
+}
+
+/// Iterator over the entries of an `SSTable`
+///
+/// The iterator owns the table, which keeps the file open even if the table
+/// is removed by a compaction in the meantime. The data blocks are read one after another.
+pub struct Iter {
+    table: SSTable,
+    next_block: usize,
+    records: std::vec::IntoIter<Record>,
+}
+
+impl Iterator for Iter {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.records.next() {
+                return Some(Ok(record));
+            }
+
+            let handle = self.table.index.get(self.next_block)?;
+            self.next_block += 1;
+
+            match self.table.reader.read_block(handle) {
+                Ok(block) => self.records = block.records.into_iter(),
+                Err(e) => {
+                    // don't continue after errors
+                    self.next_block = self.table.index.len();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
 }
 
 ////////////////////////////////////////////////////////////
 // SSTable on disk layout
 ////////////////////////////////////////////////////////////
 // DATA_BLOCK
-//   key_size key value_length value
-//   ...
+//   block_size records
+//   (records are triples of key, sequence number and entry, where the entry is either a value or a tombstone)
+// ...
 // META_BLOCK
 //   meta_size data
+// BLOOM_BLOCK
+//   bloom_size data
 // INDEX_BLOCK
+//   index_size handles
+//   (one handle with the last key, offset and size per data block)
 // TRAILER
-// TRAILER_OFFSET
+//   the offsets of the meta, bloom and index block
+// FOOTER
+//   trailer offset (u64), version and stanza
+//
+// Every block, including the trailer, is a length tagged frame that is followed by the
+// CRC32C of the frame. The footer has a fixed size and is read first, so that the
+// layout of the trailer can change with the version.
+//
+// Tables of the initial version end with their trailer, followed by its offset.
+// They are only recognized to be rejected with `Error::UnsupportedVersion`.
+
+/// A record as it's stored in a data block
+pub type Record = (Key, SequenceNumber, Entry);
+
+/// A data block holds a run of records in ascending key order
+///
+/// The versions of a key are ordered by descending sequence number and may span several blocks.
+/// Blocks are the unit of IO: they are always read and written as a whole.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Block {
+    records: Vec<Record>,
+}
+
+/// The sparse index holds one handle per data block
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct BlockHandle {
+    /// The greatest key of the block
+    last_key: Key,
+    offset: Offset,
+    /// The size of the block on disk, including its checksum
+    size: usize,
+}
+
+/// Options that control how the `Writer` creates an SSTable
+#[derive(Debug, Clone, Copy)]
+pub struct WriterOptions {
+    /// The false positive rate of the bloom filter
+    pub bloom_false_positive_rate: f64,
+    /// The size in bytes at which a data block is finished
+    pub block_size: usize,
+}
+
+impl Default for WriterOptions {
+    fn default() -> Self {
+        WriterOptions {
+            bloom_false_positive_rate: 0.01,
+            block_size: 4096,
+        }
+    }
+}
 
 /// SSTable Writer is used to flush a memtable to disk
 ///
@@ -164,47 +727,164 @@ impl SSTable {
 /// where it can be used to retrieve data again later.
 ///
 /// The writer stores the sorted strings and control data in a file.
+/// Records are buffered until they fill a block, which is then written as a whole.
 /// Once all key value pairs have been written, callers have to seal the table
 /// which finishes it of and returns a `Slab`.
 pub struct Writer {
     file: io::BufWriter<fs::File>,
     data_bytes_written: usize,
     data_count: usize,
-    index: Vec<(Key, usize)>,
+    block: Block,
+    block_bytes: usize,
+    index: Vec<BlockHandle>,
+    min_key: Option<Key>,
+    last_key: Option<Key>,
+    last_seq: SequenceNumber,
+    max_seq: SequenceNumber,
+    key_hashes: Vec<u64>,
     path: path::PathBuf,
+    options: WriterOptions,
     sealed: bool,
 }
 
 impl Writer {
     /// Create a new on disk SSTable with this writer
     pub fn create(path: &path::Path) -> Result<Self> {
-        let file = io::BufWriter::new(OpenOptions::new().create(true).write(true).open(path)?);
+        Self::create_with_options(path, WriterOptions::default())
+    }
+
+    /// Create a new on disk SSTable with this writer, using the provided `options`
+    pub fn create_with_options(path: &path::Path, options: WriterOptions) -> Result<Self> {
+        let file = io::BufWriter::new(
+            OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(path)?,
+        );
 
         Ok(Writer {
             file,
             data_bytes_written: 0,
             data_count: 0,
+            block: Block::default(),
+            block_bytes: 0,
             index: Vec::new(),
+            min_key: None,
+            last_key: None,
+            last_seq: 0,
+            max_seq: 0,
+            key_hashes: Vec::new(),
             path: path.to_owned(),
+            options,
             sealed: false,
         })
     }
 
-    /// Append a new key value pair to the SSTable
+    /// Append a new key value pair, written with the sequence number `seq`, to the SSTable
     ///
     /// The caller _must_ make that keys are added in _ascending_ order.
-    pub fn append(&mut self, k: &Key, v: &Value) -> Result<()> {
-        trace!("append key {:?} offset: {}", k, self.data_bytes_written);
+    /// Several versions of a key are added from the most recent to the oldest one.
+    pub fn append(&mut self, k: &Key, seq: SequenceNumber, v: &Value) -> Result<()> {
+        self.append_record(k, seq, Some(v))
+    }
+
+    /// Append a tombstone for the key to the SSTable
+    ///
+    /// The tombstone shadows older versions of the key.
+    /// The same ordering constraints as for `append` apply.
+    pub fn append_tombstone(&mut self, k: &Key, seq: SequenceNumber) -> Result<()> {
+        self.append_record(k, seq, None)
+    }
+
+    /// Append either a value or a tombstone, depending on the provided `entry`
+    pub fn append_entry(&mut self, k: &Key, seq: SequenceNumber, entry: &Entry) -> Result<()> {
+        match entry {
+            Entry::Val(v) => self.append(k, seq, v),
+            Entry::Tombstone => self.append_tombstone(k, seq),
+        }
+    }
+
+    /// The path of the SSTable file that is written
+    pub fn path(&self) -> &path::Path {
+        &self.path
+    }
+
+    /// The number of bytes of key value data that has been written so far,
+    /// including the records of the block that hasn't been finished yet
+    pub fn bytes_written(&self) -> usize {
+        self.data_bytes_written + self.block_bytes
+    }
 
-        self.data_bytes_written += binio::write_data(&mut self.file, &k)?;
-        self.index.push((k.clone(), self.data_bytes_written));
+    fn append_record(&mut self, k: &Key, seq: SequenceNumber, v: Option<&Value>) -> Result<()> {
+        if self.sealed {
+            return Err(Error::SealedTableError);
+        }
 
-        trace!("append value offset: {}", self.data_bytes_written);
+        let is_new_key = match &self.last_key {
+            Some(last) if (last, Reverse(self.last_seq)) >= (k, Reverse(seq)) => {
+                return Err(Error::UnorderedKey)
+            }
+            Some(last) => last != k,
+            None => true,
+        };
 
-        self.data_bytes_written += binio::write_data(&mut self.file, &v)?;
+        trace!(
+            "append key {:?} to block at offset: {}",
+            k,
+            self.data_bytes_written
+        );
+
+        if self.min_key.is_none() {
+            self.min_key = Some(k.clone());
+        }
+        if is_new_key {
+            self.last_key = Some(k.clone());
+            self.key_hashes.push(bloom::hash(k));
+        }
+        self.last_seq = seq;
+        self.max_seq = self.max_seq.max(seq);
+
+        // the length tags of key and value are accounted for as well
+        self.block_bytes += k.len()
+            + std::mem::size_of::<SequenceNumber>()
+            + v.map_or(0, |v| v.len())
+            + 2 * binio::LENGTH_TAG_SIZE as usize;
+        let entry = v.cloned().map_or(Entry::Tombstone, Entry::Val);
+        self.block.records.push((k.clone(), seq, entry));
         self.data_count += 1;
 
-        trace!("append finished offset: {}", self.data_bytes_written);
+        if self.block_bytes >= self.options.block_size {
+            self.finish_block()?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the buffered records as a block and add it to the index
+    fn finish_block(&mut self) -> Result<()> {
+        let last_key = match self.block.records.last() {
+            Some((key, _, _)) => key.clone(),
+            None => return Ok(()),
+        };
+
+        let offset = self.data_bytes_written;
+        let size = write_block(&mut self.file, &self.block)?;
+        trace!(
+            "finished block of {} records offset: {} size: {}",
+            self.block.records.len(),
+            offset,
+            size
+        );
+
+        self.data_bytes_written += size;
+        self.index.push(BlockHandle {
+            last_key,
+            offset,
+            size,
+        });
+        self.block.records.clear();
+        self.block_bytes = 0;
 
         Ok(())
     }
@@ -213,57 +893,104 @@ impl Writer {
     /// Once this operation finishes the on disk SSTable is finalized,
     /// which means:
     ///
-    /// * All data items are written
-    /// * An index has been written
+    /// * All data blocks are written
+    /// * A sparse index of the blocks has been written
     /// * Meta data has been written which allows to read the table back in
-    /// * All data has been flushed
+    /// * A bloom filter of all keys has been written
+    /// * All data has been flushed and synced to disk
     pub fn seal(&mut self) -> Result<Slab> {
         if self.sealed {
             return Err(Error::SealedTableError);
         }
 
+        let (min_key, max_key) = match (&self.min_key, &self.last_key) {
+            (Some(min_key), Some(max_key)) => (min_key.clone(), max_key.clone()),
+            _ => return Err(Error::EmptyTable),
+        };
+
+        self.finish_block()?;
         let meta_offset = self.write_meta()?;
+        let bloom_filter = self.bloom_filter();
+        let bloom_offset = self.write_bloom_filter(&bloom_filter)?;
         let index_offset = self.write_index()?;
-        let _trailer_offset = self.write_trailer(meta_offset, index_offset)?;
+        let _trailer_offset = self.write_trailer(meta_offset, bloom_offset, index_offset)?;
         self.file.flush()?;
+        self.file.get_ref().sync_all()?;
         self.sealed = true;
-
-        let idx = &self.index;
-        let (min_key, _) = idx.first().unwrap();
-        let (max_key, _) = idx.last().unwrap();
+        let size = self.pos()? as u64;
 
         info!("sstable finished and sealed {:?}", self.path);
 
         Ok(Slab {
             level: 0,
             path: self.path.to_owned(),
-            min_key: min_key.clone(),
-            max_key: max_key.clone(),
+            min_key,
+            max_key,
+            size,
+            max_seq: self.max_seq,
+            bloom_filter: Some(Arc::new(bloom_filter)),
         })
     }
 
+    fn bloom_filter(&self) -> BloomFilter {
+        let mut filter = BloomFilter::new(
+            self.key_hashes.len(),
+            self.options.bloom_false_positive_rate,
+        );
+        for hash in &self.key_hashes {
+            filter.insert_hash(*hash);
+        }
+        filter
+    }
+
+    fn write_bloom_filter(&mut self, filter: &BloomFilter) -> Result<Offset> {
+        let bloom_offset = self.pos()?;
+        trace!(
+            "writing bloom filter: {:?} offset: {}",
+            filter,
+            bloom_offset
+        );
+
+        write_block(&mut self.file, filter)?;
+        Ok(bloom_offset)
+    }
+
     fn write_meta(&mut self) -> Result<Offset> {
         let meta_offset = self.pos()?;
         let meta = Meta {
-            data_block_count: self.data_count,
+            data_block_count: self.index.len(),
             data_size: self.data_bytes_written,
-            index_size: self.index.len(),
+            entry_count: self.data_count,
         };
 
         trace!("writing meta data: {:?} offset: {}", meta, meta_offset);
 
-        binio::write_data(&mut self.file, &meta)?;
+        write_block(&mut self.file, &meta)?;
         Ok(meta_offset)
     }
 
-    fn write_trailer(&mut self, meta_offset: Offset, index_offset: Offset) -> Result<Offset> {
+    fn write_trailer(
+        &mut self,
+        meta_offset: Offset,
+        bloom_offset: Offset,
+        index_offset: Offset,
+    ) -> Result<Offset> {
         let trailer_offset = self.pos()?;
-        let trailer = Trailer::new(meta_offset, index_offset);
+        let trailer = Trailer {
+            meta_offset,
+            bloom_offset,
+            index_offset,
+        };
 
         trace!("writing trailer: {:?} offset: {}", trailer, trailer_offset);
 
-        binio::write_data(&mut self.file, &trailer)?;
-        binio::write_data_size(&mut self.file, trailer_offset)?;
+        write_block(&mut self.file, &trailer)?;
+        // tables of size-tiered compactions aren't limited in size, so the offset may exceed 4 GiB
+        let mut offset = [0; 8];
+        LittleEndian::write_u64(&mut offset, trailer_offset as u64);
+        self.file.write_all(&offset)?;
+        self.file.write_all(&[VERSION])?;
+        self.file.write_all(STANZA.as_bytes())?;
 
         Ok(trailer_offset)
     }
@@ -272,16 +999,12 @@ impl Writer {
         let index_offset = self.pos()?;
 
         trace!(
-            "writing index of size {} offset: {}",
+            "writing index of {} blocks offset: {}",
             &self.index.len(),
             index_offset
         );
 
-        for (key, offset) in &self.index {
-            binio::write_data(&mut self.file, key)?;
-            binio::write_data(&mut self.file, *offset)?;
-        }
-
+        write_block(&mut self.file, &self.index)?;
         Ok(index_offset as Offset)
     }
 
@@ -291,87 +1014,206 @@ impl Writer {
     }
 }
 
-// Reader is an internal API that allows to read on disk SSTable data
-type ReaderStorage = io::BufReader<fs::File>;
+/// Write the `data` as a frame that is followed by its checksum
+///
+/// Returns the number of bytes that have been written.
+fn write_block<W: Write, D: Serialize>(w: &mut W, data: D) -> Result<usize> {
+    let mut block = Vec::new();
+    binio::write_data(&mut block, data)?;
+    let mut checksum = [0; CHECKSUM_SIZE];
+    LittleEndian::write_u32(&mut checksum, CRC.checksum(&block));
+    block.extend_from_slice(&checksum);
 
+    w.write_all(&block)?;
+    Ok(block.len())
+}
+
+// Reader is an internal API that allows to read on disk SSTable data
+//
+// Blocks are read with positional reads, so that the reader can be shared by concurrent reads.
 struct Reader {
-    file: ReaderStorage,
+    file: fs::File,
+    path: path::PathBuf,
+    options: ReadOptions,
     meta: Meta,
     trailer: Trailer,
+    trailer_offset: Offset,
 }
 
 impl Reader {
-    fn open(path: &path::Path) -> Result<Self> {
-        let mut file = io::BufReader::new(OpenOptions::new().read(true).open(path)?);
-        let (meta, trailer) = Reader::read_control_data(&mut file)?;
+    fn open(path: &path::Path, options: ReadOptions) -> Result<Self> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        let footer = Reader::read_footer(&file, path)?;
 
-        Ok(Reader {
+        let mut reader = Reader {
             file,
-            meta,
-            trailer,
-        })
+            path: path.to_path_buf(),
+            options,
+            meta: Meta::default(),
+            trailer: Trailer::default(),
+            trailer_offset: footer.trailer_offset,
+        };
+        reader.trailer = reader.read_trailer(footer.trailer_end)?;
+        let (meta_offset, meta_size) = reader.control_blocks()[0];
+        reader.meta = reader.read_data(meta_offset, meta_size)?;
+        trace!("read meta {:?} offset: {}", reader.meta, meta_offset);
+
+        Ok(reader)
     }
 
-    fn read_record(&mut self, offset: Offset) -> Result<Value> {
-        self.file.seek(SeekFrom::Start(offset as u64))?;
-        let value = binio::read_data_owned(&mut self.file)?;
-        Ok(value)
+    fn read_block(&self, handle: &BlockHandle) -> Result<Block> {
+        self.read_data(handle.offset, handle.size)
     }
 
-    fn read_index_into(&mut self, index: &mut HashMap<Key, Offset>) -> Result<()> {
-        self.file
-            .seek(SeekFrom::Start(self.trailer.index_offset as u64))?;
+    fn read_bloom_filter(&self) -> Result<BloomFilter> {
+        let (offset, size) = self.control_blocks()[1];
+        self.read_data(offset, size)
+    }
 
-        for _ in 0..self.meta.index_size {
-            index.insert(
-                binio::read_data_owned(&mut self.file)?,
-                binio::read_data_owned(&mut self.file)?,
-            );
-        }
+    fn read_index(&self) -> Result<Vec<BlockHandle>> {
+        let (offset, size) = self.control_blocks()[2];
+        let index: Vec<BlockHandle> = self.read_data(offset, size)?;
+        trace!(
+            "read index of {} blocks with {} entries",
+            index.len(),
+            self.meta.entry_count
+        );
+        Ok(index)
+    }
 
-        Ok(())
+    /// The offsets and sizes of the meta, bloom and index block, in this order
+    fn control_blocks(&self) -> [(Offset, usize); 3] {
+        let trailer = &self.trailer;
+        [
+            (
+                trailer.meta_offset,
+                trailer.bloom_offset - trailer.meta_offset,
+            ),
+            (
+                trailer.bloom_offset,
+                trailer.index_offset - trailer.bloom_offset,
+            ),
+            (
+                trailer.index_offset,
+                self.trailer_offset - trailer.index_offset,
+            ),
+        ]
     }
 
-    fn read_control_data(file: &mut ReaderStorage) -> Result<(Meta, Trailer)> {
-        file.seek(SeekFrom::End((binio::LENGTH_TAG_SIZE * -1) as i64))?;
-        let trailer_offset = binio::read_data_size(file)?;
-        file.seek(SeekFrom::Start(trailer_offset as u64))?;
+    /// Read the block of `size` bytes at `offset` and deserialize its data
+    ///
+    /// The checksum of the block is verified, unless the read options turn that off.
+    fn read_data<D: serde::de::DeserializeOwned>(&self, offset: Offset, size: usize) -> Result<D> {
+        let frame = self.read_frame(offset, size, self.options.verify_checksums)?;
+        Ok(binio::read_data_owned(&mut frame.as_slice())?)
+    }
 
-        let trailer: Trailer = binio::read_data_owned(file)?;
-        trace!("read trailer {:?} offset: {}", trailer, trailer_offset);
+    /// Read the block of `size` bytes at `offset` and return its frame without the checksum
+    ///
+    /// If `verify` is set, a block that doesn't match its checksum fails with `Error::CorruptedBlock`.
+    fn read_frame(&self, offset: Offset, size: usize, verify: bool) -> Result<Vec<u8>> {
+        let mut block = vec![0; size];
+        self.file.read_exact_at(&mut block, offset as u64)?;
 
-        file.seek(SeekFrom::Start(trailer.meta_offset as u64))?;
-        let meta: Meta = binio::read_data_owned(file)?;
-        trace!("read meta {:?} offset: {}", meta, trailer.meta_offset);
+        let corrupted = || {
+            Error::CorruptedBlock(CorruptedRange {
+                path: self.path.clone(),
+                offset: offset as u64,
+                size: size as u64,
+            })
+        };
+        let frame_size = size.checked_sub(CHECKSUM_SIZE).ok_or_else(corrupted)?;
+        let checksum = LittleEndian::read_u32(&block[frame_size..]);
+        if verify && CRC.checksum(&block[..frame_size]) != checksum {
+            return Err(corrupted());
+        }
+        block.truncate(frame_size);
+        Ok(block)
+    }
+
+    /// Read the footer at the end of the table, which tells where its trailer is
+    ///
+    /// The footer identifies the file as an SSTable and tells the version of its format.
+    /// Tables of versions that can't be read with this version are rejected.
+    fn read_footer(file: &fs::File, path: &path::Path) -> Result<Footer> {
+        let not_an_sstable = || Error::NotAnSSTable(path.to_path_buf());
+        let length = file.metadata()?.len() as usize;
+        let tail_size = length.min(INITIAL_FOOTER_SIZE);
+        let mut tail = vec![0; tail_size];
+        file.read_exact_at(&mut tail, (length - tail_size) as u64)?;
 
-        Ok((meta, trailer))
+        let stanza = STANZA.as_bytes();
+        if tail.len() < FOOTER_SIZE || !tail.ends_with(stanza) {
+            // the version is followed by the length of the stanza in the initial trailer
+            if tail.len() == INITIAL_FOOTER_SIZE
+                && tail[0] == INITIAL_VERSION
+                && &tail[9..9 + stanza.len()] == stanza
+            {
+                return Err(Error::UnsupportedVersion(INITIAL_VERSION));
+            }
+            return Err(not_an_sstable());
+        }
+
+        let footer = &tail[tail.len() - FOOTER_SIZE..];
+        let version = footer[8];
+        if version != VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let footer = Footer {
+            trailer_offset: LittleEndian::read_u64(&footer[..8]) as Offset,
+            trailer_end: length - FOOTER_SIZE,
+        };
+        trace!("read footer {:?}", footer);
+
+        if footer.trailer_offset > footer.trailer_end {
+            return Err(not_an_sstable());
+        }
+        Ok(footer)
+    }
+
+    /// Read the trailer that ends at `trailer_end`
+    fn read_trailer(&self, trailer_end: Offset) -> Result<Trailer> {
+        let not_an_sstable = || Error::NotAnSSTable(self.path.clone());
+        let size = trailer_end - self.trailer_offset;
+        let frame = self.read_frame(self.trailer_offset, size, true)?;
+        let trailer: Trailer =
+            binio::read_data_owned(&mut frame.as_slice()).map_err(|_| not_an_sstable())?;
+        trace!("read trailer {:?} offset: {}", trailer, self.trailer_offset);
+
+        // the blocks are stored in this order, which the sizes of the control blocks rely on
+        let offsets = [
+            trailer.meta_offset,
+            trailer.bloom_offset,
+            trailer.index_offset,
+            self.trailer_offset,
+        ];
+        if offsets.windows(2).any(|pair| pair[0] > pair[1]) {
+            return Err(not_an_sstable());
+        }
+        Ok(trailer)
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Where the trailer of a table is
+#[derive(Debug)]
+struct Footer {
+    trailer_offset: Offset,
+    /// The offset right after the trailer
+    trailer_end: Offset,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
 struct Trailer {
     meta_offset: Offset,
+    bloom_offset: Offset,
     index_offset: Offset,
-    version: u8,
-    stanza: Vec<u8>,
-}
-
-impl Trailer {
-    fn new(meta_offset: Offset, index_offset: Offset) -> Trailer {
-        Trailer {
-            meta_offset,
-            index_offset,
-            version: 0x1,
-            stanza: STANZA.as_bytes().to_vec(),
-        }
-    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 struct Meta {
     data_size: usize,
     data_block_count: usize,
-    index_size: usize,
+    entry_count: usize,
 }
 
 #[cfg(test)]
@@ -387,6 +1229,7 @@ mod tests {
             path::Path::new("/tmp"),
             Key::from("alpha"),
             Key::from("gamma"),
+            0,
         );
 
         for k in ["alpha", "beta", "gamma"] {
@@ -0,0 +1,218 @@
+//! The tables are the C1 system of the LSM
+//!
+//! This module owns the live SSTables, organized in `Levels`, as well as the
+//! `Manifest` that makes the set of live tables durable.
+//! The tables are shared between the LSM, which adds flushed memtables and does lookups,
+//! and the compaction, which replaces tables with their merged versions.
+//!
+//! Lookups go through the `TableCache`, so that frequently used tables stay open.
+use super::cache::{Statistics, TableCache};
+use super::levels::Levels;
+use super::manifest::{Manifest, VersionEdit};
+use super::memtable::Entry;
+use super::sstable::{self, CorruptedRange, Level, SSTable, Slab, WriterOptions};
+use super::{Result, SequenceNumber};
+use crate::engine::Key;
+use std::collections::HashSet;
+use std::ops::RangeBounds;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock, RwLockReadGuard};
+use std::{fs, path};
+
+const SSTABLE_DIR: &str = "sstables";
+
+pub struct Tables {
+    dir: path::PathBuf,
+    writer_options: WriterOptions,
+    next_table_id: AtomicU64,
+    levels: RwLock<Levels>,
+    manifest: Mutex<Manifest>,
+    table_cache: TableCache,
+}
+
+impl Tables {
+    /// Open the tables that are stored beneath the `storage_path`
+    ///
+    /// The live tables are restored from the manifest. SSTable files that are
+    /// not referenced by the manifest (e.g. left behind by an interrupted compaction)
+    /// are removed.
+    ///
+    /// New tables are written with the provided `writer_options`.
+    /// Lookups open the tables through the `table_cache`.
+    pub fn open(
+        storage_path: &path::Path,
+        writer_options: WriterOptions,
+        table_cache: TableCache,
+    ) -> Result<Tables> {
+        let dir = storage_path.join(SSTABLE_DIR);
+        fs::create_dir_all(&dir)?;
+
+        let (manifest, mut slabs) = Manifest::open(storage_path)?;
+        for slab in slabs.iter_mut() {
+            slab.load_bloom_filter()?;
+        }
+        let live: HashSet<_> = slabs.iter().map(|slab| slab.path().file_name()).collect();
+
+        let mut next_table_id = 0;
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if let Some(id) = sstable::table_id(&path) {
+                next_table_id = next_table_id.max(id + 1);
+
+                if !live.contains(&path.file_name()) {
+                    log::info!(target: "LSM", "removing unreferenced sstable {:?}", path);
+                    fs::remove_file(&path)?;
+                }
+            }
+        }
+
+        Ok(Tables {
+            dir,
+            writer_options,
+            next_table_id: AtomicU64::new(next_table_id),
+            levels: RwLock::new(Levels::new(slabs)),
+            manifest: Mutex::new(manifest),
+            table_cache,
+        })
+    }
+
+    /// Create a writer for a new SSTable
+    ///
+    /// The table gets a path that is guaranteed to not be used by any other table.
+    pub fn create_writer(&self) -> Result<sstable::Writer> {
+        let id = self.next_table_id.fetch_add(1, Ordering::SeqCst);
+        let path = sstable::table_path(&self.dir, id);
+        Ok(sstable::Writer::create_with_options(
+            &path,
+            self.writer_options,
+        )?)
+    }
+
+    /// Make a freshly sealed table live
+    pub fn add(&self, slab: Slab) -> Result<()> {
+        self.replace(&[], vec![slab])
+    }
+
+    /// Atomically replace the `obsolete` slabs with the `added` slabs
+    ///
+    /// Once the new set of tables is live, the files of the obsolete tables are removed.
+    pub fn replace(&self, obsolete: &[Slab], added: Vec<Slab>) -> Result<()> {
+        fs::File::open(&self.dir)?.sync_all()?;
+
+        let edits: Vec<VersionEdit> = added
+            .iter()
+            .map(|slab| VersionEdit::AddSlab(slab.clone()))
+            .chain(
+                obsolete
+                    .iter()
+                    .map(|slab| VersionEdit::RemoveSlab(slab.path().to_path_buf())),
+            )
+            .collect();
+
+        {
+            let mut manifest = self.manifest.lock()?;
+            let mut levels = self.levels.write()?;
+
+            manifest.apply(&edits)?;
+            levels.replace(obsolete, added);
+        }
+
+        for slab in obsolete {
+            self.table_cache.evict(slab.path())?;
+            fs::remove_file(slab.path())?;
+        }
+
+        Ok(())
+    }
+
+    /// Read access to the live tables
+    ///
+    /// While the guard is held, none of the tables will be removed.
+    pub fn levels(&self) -> Result<RwLockReadGuard<'_, Levels>> {
+        Ok(self.levels.read()?)
+    }
+
+    /// Lookup the most recent entry of the key in the SSTables that is visible at the sequence number `seq`
+    ///
+    /// Tables are consulted from newest to oldest, so that the first entry
+    /// that is found is the most recent one.
+    pub fn get(&self, k: &Key, seq: SequenceNumber) -> Result<Option<Entry>> {
+        let levels = self.levels()?;
+
+        for slab in levels.candidates(k) {
+            let table = self.table_cache.get(slab)?;
+            let entry = table.get(k, seq)?;
+            if let Some(entry) = entry {
+                return Ok(Some(entry));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// The sequence number of the most recent version of the key in the SSTables, if there is any
+    pub fn latest_seq(&self, k: &Key) -> Result<Option<SequenceNumber>> {
+        let levels = self.levels()?;
+
+        for slab in levels.candidates(k) {
+            let table = self.table_cache.get(slab)?;
+            let seq = table.latest_seq(k)?;
+            if seq.is_some() {
+                return Ok(seq);
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// The greatest sequence number that is stored in any of the live tables
+    pub fn last_sequence(&self) -> Result<SequenceNumber> {
+        let levels = self.levels()?;
+        Ok((0..levels.depth())
+            .flat_map(|level| levels.level(level as Level).iter())
+            .map(Slab::max_seq)
+            .max()
+            .unwrap_or(0))
+    }
+
+    /// The hit and miss counters of the table and block cache
+    pub fn cache_stats(&self) -> Result<Statistics> {
+        self.table_cache.stats()
+    }
+
+    /// Check the checksums of all blocks of the live tables
+    ///
+    /// Returns the ranges of the blocks that are corrupted, which is empty if all tables are intact.
+    /// Like for scans, the tables are opened while the live tables can't change.
+    pub fn verify(&self) -> Result<Vec<CorruptedRange>> {
+        let opened: Vec<_> = self.levels()?.iter().map(|slab| slab.sstable()).collect();
+
+        let mut corrupted = Vec::new();
+        for table in opened {
+            match table {
+                Ok(table) => corrupted.extend(table.verify()?),
+                // the blocks that are read when the table is opened might be corrupted as well
+                Err(sstable::Error::CorruptedBlock(range)) => corrupted.push(range),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(corrupted)
+    }
+
+    /// Open the live tables that might contain keys of the `range`, from newest to oldest
+    ///
+    /// The tables are opened while the live tables can't change, so that none of them
+    /// is removed before it's open. Once open, a table can be read even after it has been
+    /// compacted away.
+    pub fn open_in_range<R: RangeBounds<Key>>(&self, range: &R) -> Result<Vec<SSTable>> {
+        let levels = self.levels()?;
+        let level0 = levels.level(0).iter().rev();
+        let deeper = (1..levels.depth()).flat_map(|level| levels.level(level as Level).iter());
+
+        Ok(level0
+            .chain(deeper)
+            .filter(|slab| slab.intersects(range))
+            .map(|slab| slab.sstable())
+            .collect::<std::result::Result<_, sstable::Error>>()?)
+    }
+}
@@ -3,23 +3,29 @@
 /// This module provides the funcitonality for a simple append only wal.
 /// Every write is first written to this log before any further action is taken.
 /// In case of a crash the wal can be used to reconstruct the state prior to the
-/// crash.
-/// **Note** that the log writes to the filesystem without flushing, thus leaving
-/// the ultimate control over when the write happens to the OS at the benefit of a faster
-/// write through the FS cache.
+/// crash. Every record carries a checksum, so that torn writes and corruption are detected.
+/// **Note** that a write only survives a crash of the machine once the log has been synced
+/// to disk. When that happens is decided by the `SyncPolicy`, which trades durability
+/// for the speed of writes that go through the FS cache.
 pub mod reader;
 pub mod serialization;
 pub mod writer;
-extern crate crc;
 use super::binary_io as binio;
+use super::SequenceNumber;
 use crate::engine::storage::lsm::wal::reader::WalReader;
 use serde::{self, Deserialize, Serialize};
 use std::convert::From;
 use std::path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 use thiserror::Error;
+use ubyte::ByteUnit;
 use writer::WalWriter;
 
-const WAL_FILE_NAME: &str = "wal.log";
+const SEGMENT_PREFIX: &str = "wal-";
+const SEGMENT_EXTENSION: &str = "log";
+/// The single WAL file of the initial release, which is recovered as the first segment
+const INITIAL_WAL_FILE_NAME: &str = "wal.log";
 
 type Result<T> = std::result::Result<T, Error>;
 
@@ -29,6 +35,16 @@ pub enum Error {
     IoError(#[from] std::io::Error),
     #[error(transparent)]
     BinIoError(#[from] binio::Error),
+    #[error("NotAWalFile: {0:?} is not a WAL file")]
+    NotAWalFile(path::PathBuf),
+    #[error("UnsupportedVersion: version {0} of the WAL format is not supported")]
+    UnsupportedVersion(u8),
+    #[error("TornRecord: the write of the last record at offset {0} has been interrupted")]
+    TornRecord(u64),
+    #[error("CorruptedRecord: the record at offset {0} doesn't match its checksum")]
+    CorruptedRecord(u64),
+    #[error("Poisoned: the WAL doesn't accept writes anymore after a failed write")]
+    Poisoned,
     #[error("LockError")]
     LockError,
 }
@@ -48,9 +64,91 @@ pub enum Operation<K, V> {
     Delete(K),
 }
 
+/// When the WAL is synced to disk
+///
+/// Writes that haven't been synced are in the FS cache and get lost if the machine crashes,
+/// even though they survive a crash of the process.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncPolicy {
+    /// Sync every commit, so that writes are durable once they are acknowledged
+    Always,
+    /// Sync once the given time has passed since the last sync
+    ///
+    /// Commits sync when the time has passed, and the LSM syncs in the background,
+    /// so that the last commits before a pause are synced in time as well.
+    Interval(Duration),
+    /// Sync on commit, once the given number of bytes has been written since the last sync
+    Bytes(ByteUnit),
+    /// Never sync, the OS decides when the writes reach the disk
+    Os,
+}
+
+/// A record of the WAL holds the operations of one write, which are applied all or nothing
+///
+/// The operations are tagged with consecutive sequence numbers,
+/// starting with the sequence number of the record.
+pub type Record<K, V> = (SequenceNumber, Vec<Operation<K, V>>);
+
+/// The options of the WAL
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Options {
+    /// When the WAL is synced to disk
+    pub sync_policy: SyncPolicy,
+    /// The size in bytes at which a segment is full and a new one is started
+    pub segment_size: u64,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            sync_policy: SyncPolicy::Always,
+            segment_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
 /// Representation of the Write Ahead Log
+///
+/// The WAL is split into numbered segments. Writes go to the newest segment, until it reaches
+/// the segment size or the memtable is handed over to be flushed. Then a new segment is started.
+/// The older segments are kept around until all of their operations have been persisted
+/// in SSTables. Recovery replays the segments from oldest to newest.
+///
+/// The WAL file of the initial release is segment 0, so that its writes are recovered
+/// before any newer ones. Like every recovered segment, it's removed after the recovery.
 pub struct WalManager {
-    active_file: path::PathBuf,
+    wal_path: path::PathBuf,
+    options: Options,
+    /// The number of the next segment that is created
+    next_segment: AtomicU64,
+}
+
+/// A segment of the WAL
+#[derive(Debug)]
+pub struct Segment {
+    number: u64,
+    path: path::PathBuf,
+}
+
+impl Segment {
+    /// The segments are numbered in the order they have been created
+    pub fn number(&self) -> u64 {
+        self.number
+    }
+
+    pub fn path(&self) -> &path::Path {
+        &self.path
+    }
+
+    pub fn open(&self) -> Result<WalReader> {
+        WalReader::open(&self.path)
+    }
+
+    /// Remove the segment, once its writes have been persisted elsewhere
+    pub fn remove(self) -> Result<()> {
+        std::fs::remove_file(&self.path)?;
+        Ok(())
+    }
 }
 
 impl WalManager {
@@ -60,42 +158,93 @@ impl WalManager {
     /// allow the WAL to work properly.
     ///
     /// It is safe to call this method multiple times.
-    pub fn init(storage_path: &path::Path) -> Result<WalManager> {
+    /// All writers of the manager sync the WAL according to the `options`.
+    pub fn init(storage_path: &path::Path, options: Options) -> Result<WalManager> {
         let wal_path = storage_path.join("wal");
-        let wal_file_name = wal_path.join(WAL_FILE_NAME);
         std::fs::create_dir_all(&wal_path)?;
 
+        let next_segment = Self::find_segments(&wal_path)?
+            .last()
+            .map_or(0, |segment| segment.number + 1);
+
         Ok(WalManager {
-            active_file: wal_file_name,
+            wal_path,
+            options,
+            next_segment: AtomicU64::new(next_segment),
         })
     }
 
     /// Uses the state in WAL directory to determine if a recovery is needed
-    pub fn recovery_needed(&self) -> bool {
-        self.active_file.exists()
+    pub fn recovery_needed(&self) -> Result<bool> {
+        Ok(!self.segments()?.is_empty())
     }
 
-    /// Create a *new* WAL file and returns a `WalWriter`, which
+    /// Create a *new* segment and returns a `WalWriter`, which
     /// can be used to add records to the WAL.
     ///
-    /// If the file already exists it will be *truncated*.
-    /// If you don't want that use the `resume` method instead.
+    /// The older segments are kept, their records are still recovered after a crash
+    /// until they are removed with `remove_before`.
     pub fn create(&self) -> Result<WalWriter> {
-        WalWriter::create(&self.active_file)
+        let number = self.next_segment.fetch_add(1, Ordering::SeqCst);
+        WalWriter::create(&self.segment_path(number), self.options.sync_policy)
     }
 
-    /// Resume writes to an existing WAL file.
+    /// Resume writes to the newest segment.
     ///
-    /// Contrary to `create` this will open the file in append mode.
+    /// Contrary to `create` this will open the segment in append mode.
+    /// If there is no segment yet, the first one is created.
     pub fn resume(&self) -> Result<WalWriter> {
-        WalWriter::resume(&self.active_file)
+        match self.segments()?.pop() {
+            Some(segment) => WalWriter::resume(&segment.path, self.options.sync_policy),
+            None => self.create(),
+        }
+    }
+
+    /// Continue with a new segment, if the segment of the `writer` is full
+    ///
+    /// The full segment is synced before the writes continue in the new one.
+    /// Returns whether a new segment has been started.
+    pub fn rotate_if_full(&self, writer: &mut WalWriter) -> Result<bool> {
+        writer.check()?;
+        if writer.size() < self.options.segment_size {
+            return Ok(false);
+        }
+        writer.sync_pending()?;
+        *writer = self.create()?;
+        Ok(true)
+    }
+
+    /// The number of the segment that has been created last
+    ///
+    /// Once all writes to this and older segments have been persisted elsewhere
+    /// (e.g. in an SSTable), they can be removed with `remove_before`.
+    pub fn newest(&self) -> u64 {
+        self.next_segment.load(Ordering::SeqCst).saturating_sub(1)
     }
 
-    /// Opens an existing WAL for reading
+    /// Remove all segments older than the segment with the given `number`
+    ///
+    /// This must only be called once all operations in these segments
+    /// have been made durable elsewhere (e.g. in an SSTable), since they are gone afterwards.
+    pub fn remove_before(&self, number: u64) -> Result<()> {
+        for segment in self.segments()? {
+            if segment.number < number {
+                segment.remove()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The segments of the WAL, from oldest to newest
+    pub fn segments(&self) -> Result<Vec<Segment>> {
+        Self::find_segments(&self.wal_path)
+    }
+
+    /// Opens all segments with records that need to be played back, from oldest to newest
     ///
     /// This is used when recovery is needed and the records need to be played back.
-    pub fn open(&self) -> Result<WalReader> {
-        WalReader::open(&self.active_file)
+    pub fn open(&self) -> Result<Vec<WalReader>> {
+        self.segments()?.iter().map(Segment::open).collect()
     }
 
     /// A null WAL will accept writes but will never actually write anything.
@@ -103,12 +252,43 @@ impl WalManager {
     pub fn null(&self) -> Result<WalWriter> {
         WalWriter::null()
     }
+
+    fn segment_path(&self, number: u64) -> path::PathBuf {
+        self.wal_path.join(format!(
+            "{}{:08}.{}",
+            SEGMENT_PREFIX, number, SEGMENT_EXTENSION
+        ))
+    }
+
+    fn find_segments(wal_path: &path::Path) -> Result<Vec<Segment>> {
+        let mut segments = Vec::new();
+        for entry in std::fs::read_dir(wal_path)? {
+            let path = entry?.path();
+            if path.file_name().and_then(|name| name.to_str()) == Some(INITIAL_WAL_FILE_NAME) {
+                segments.push(Segment { number: 0, path });
+                continue;
+            }
+            if path.extension().and_then(|ext| ext.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            let number = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.strip_prefix(SEGMENT_PREFIX))
+                .and_then(|number| number.parse().ok());
+            if let Some(number) = number {
+                segments.push(Segment { number, path });
+            }
+        }
+        segments.sort_by_key(|segment| segment.number);
+        Ok(segments)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::binio;
-    use super::Operation;
+    use super::{Operation, Record};
     use std::io;
 
     #[test]
@@ -117,12 +297,22 @@ mod tests {
         let foo = "foo".as_bytes();
         let bar = "bar".as_bytes();
 
-        assert!(binio::write_data(&mut writer, Operation::Set(foo, bar)).is_ok());
+        let ops = [Operation::Set(foo, bar), Operation::Delete(bar)];
+        assert!(binio::write_data(&mut writer, (7u64, &ops[..])).is_ok());
 
         let mut reader = io::Cursor::new(writer.into_inner());
 
-        let op: Operation<Vec<u8>, Vec<u8>> = binio::read_data_owned(&mut reader).unwrap();
+        let record: Record<Vec<u8>, Vec<u8>> = binio::read_data_owned(&mut reader).unwrap();
 
-        assert_eq!(Operation::Set(foo.to_vec(), bar.to_vec()), op)
+        assert_eq!(
+            (
+                7,
+                vec![
+                    Operation::Set(foo.to_vec(), bar.to_vec()),
+                    Operation::Delete(bar.to_vec())
+                ]
+            ),
+            record
+        )
     }
 }
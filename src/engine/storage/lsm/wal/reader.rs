@@ -1,56 +1,201 @@
 use super::binio;
-use super::serialization::FileHeader;
+use super::serialization::{self, FileHeader, Frame, RECORD_HEADER_SIZE};
+use super::serialization::{INITIAL_VERSION, STANZA, VERSION};
 /// A WalReader that gives access to committed operations in a convenient manner.
 ///
 /// Use the reader to replay committed operations. It provides an iterator
-/// to the underlying `Operation`, which is assumed to be enough to
+/// to the underlying `Operation`s and their sequence numbers, which is assumed to be enough to
 /// restore state from the WAL.
 ///
+/// Every record is verified against its checksum. A record at the end of the WAL that
+/// has been cut off or doesn't match its checksum is a torn write: the iterator stops there
+/// and `truncate_torn_tail` removes it. A corrupted record before the end of the WAL
+/// is reported as `Error::CorruptedRecord` instead. The iteration can continue after it.
+/// If the header of the record is corrupted, the end of the record is unknown: the iteration
+/// continues with the next record that matches its checksums. Only if there is none,
+/// the damage is at the end of the WAL and treated like a torn tail.
+///
+/// The reader fails to open files that aren't WALs or whose version isn't supported.
+/// WALs of the initial version are read as well. Their records hold a single operation
+/// without sequence number, so the records are numbered consecutively from 1 on.
+///
 use super::Result;
-use crate::engine::storage::lsm::wal::{Error, Operation};
+use crate::engine::storage::lsm::wal::{Error, Operation, Record};
+use crate::engine::storage::lsm::SequenceNumber;
 use crate::engine::{Key, Value};
-use std::io::BufReader;
+use std::io::{BufReader, Read, Seek, SeekFrom};
 use std::{fs, io, path};
 
 pub struct WalReader {
     header: FileHeader,
+    path: path::PathBuf,
     file: io::BufReader<fs::File>,
+    /// The offset of the next record
+    position: u64,
+    /// The size of the WAL when it has been opened
+    length: u64,
+    /// The offset from which on the WAL can't be read, once it has been reached
+    torn_tail: Option<u64>,
+    /// The sequence number of the next record of a WAL of the initial version
+    next_seq: SequenceNumber,
 }
 
 impl WalReader {
     pub fn open(path: &path::Path) -> Result<Self> {
         let mut reader = fs::OpenOptions::new().read(true).open(path)?;
-        let header: FileHeader = binio::read_data_owned(&mut reader)?;
+        let length = reader.metadata()?.len();
+        let header = if length == 0 {
+            // a crash right after the file has been created leaves an empty WAL behind
+            FileHeader::new(STANZA, VERSION)
+        } else {
+            serialization::read_header(&mut reader, path)?
+        };
+        let position = reader.stream_position()?;
 
         log::trace!("wal successfully opened. version = {}", header.version);
 
         Ok(WalReader {
             header,
+            path: path.to_path_buf(),
             file: BufReader::new(reader),
+            position,
+            length,
+            torn_tail: None,
+            next_seq: 1,
         })
     }
 
+    pub fn path(&self) -> &path::Path {
+        &self.path
+    }
+
     /// Reads the next committed operation from the WAL
     ///
     /// Use this to implement you own logic if you can't use the provided Iterator implementation.
-    pub fn read(&mut self) -> Result<Operation<Key, Value>> {
-        let data = binio::read_data_owned(&mut self.file)?;
-        Ok(data)
+    /// Fails with an `UnexpectedEof` error at the end of the WAL and with
+    /// `Error::TornRecord` if the write of the last record has been interrupted.
+    pub fn read(&mut self) -> Result<Record<Key, Value>> {
+        match self.read_next()? {
+            Some(record) => Ok(record),
+            None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+        }
+    }
+
+    /// The offset of the torn record at the end of the WAL, if the iteration stopped there
+    ///
+    /// This is also where a record with a corrupted header starts, if no intact record follows it.
+    pub fn torn_tail(&self) -> Option<u64> {
+        self.torn_tail
+    }
+
+    /// Cut the torn record off the end of the WAL, so that new records can follow
+    /// the last complete one. Returns whether there was a torn record.
+    pub fn truncate_torn_tail(&self) -> Result<bool> {
+        match self.torn_tail {
+            Some(offset) => {
+                let file = fs::OpenOptions::new().write(true).open(&self.path)?;
+                file.set_len(offset)?;
+                file.sync_all()?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn read_next(&mut self) -> Result<Option<Record<Key, Value>>> {
+        match self.header.version {
+            INITIAL_VERSION => self.read_initial(),
+            _ => self.read_checked(),
+        }
+    }
+
+    /// Read a record together with its checksums
+    fn read_checked(&mut self) -> Result<Option<Record<Key, Value>>> {
+        let offset = self.position;
+        match serialization::read_record(&mut self.file)? {
+            Frame::Record(data) => {
+                self.position += RECORD_HEADER_SIZE + data.len() as u64;
+                // the data matches its checksum, so it has been written like this
+                let record =
+                    binio::deserialize(&data).map_err(|_| Error::CorruptedRecord(offset))?;
+                Ok(Some(record))
+            }
+            Frame::End => Ok(None),
+            Frame::Incomplete => Err(Error::TornRecord(offset)),
+            Frame::CorruptedData(size) => {
+                self.position += size;
+                if self.position >= self.length {
+                    Err(Error::TornRecord(offset))
+                } else {
+                    Err(Error::CorruptedRecord(offset))
+                }
+            }
+            Frame::CorruptedHeader => match self.next_intact_record(offset)? {
+                Some(next) => {
+                    self.file.seek(SeekFrom::Start(next))?;
+                    self.position = next;
+                    Err(Error::CorruptedRecord(offset))
+                }
+                // this covers the zeros that the file system might fill the rest of the file with
+                None => Err(Error::TornRecord(offset)),
+            },
+        }
+    }
+
+    /// Read a record of the initial version, where corruption can't be detected
+    fn read_initial(&mut self) -> Result<Option<Record<Key, Value>>> {
+        let offset = self.position;
+        if offset >= self.length {
+            return Ok(None);
+        }
+
+        let mut data = Vec::new();
+        match binio::read_frame(&mut self.file, &mut data) {
+            Ok(size) => {
+                self.position += size as u64;
+                let seq = self.next_seq;
+                self.next_seq += 1;
+                let op: Operation<Key, Value> =
+                    binio::deserialize(&data).map_err(|_| Error::CorruptedRecord(offset))?;
+                Ok(Some((seq, vec![op])))
+            }
+            Err(binio::Error::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                Err(Error::TornRecord(offset))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The offset of the first record after the `offset` that matches its checksums, if there is any
+    fn next_intact_record(&mut self, offset: u64) -> Result<Option<u64>> {
+        self.file.seek(SeekFrom::Start(offset + 1))?;
+        let mut rest = Vec::new();
+        self.file.read_to_end(&mut rest)?;
+
+        for start in 0..rest.len() {
+            if let Frame::Record(_) = serialization::read_record(&mut &rest[start..])? {
+                return Ok(Some(offset + 1 + start as u64));
+            }
+        }
+        Ok(None)
     }
 }
 
 impl Iterator for WalReader {
-    type Item = Result<Operation<Key, Value>>;
+    type Item = Result<Record<Key, Value>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.read() {
-            Err(Error::BinIoError(binio::Error::IoError(io_error)))
-                if io_error.kind() == io::ErrorKind::UnexpectedEof =>
-            {
+        if self.torn_tail.is_some() {
+            return None;
+        }
+        match self.read_next() {
+            Ok(record) => record.map(Ok),
+            Err(Error::TornRecord(offset)) => {
+                log::warn!(target: "wal", "the last record of {:?} at offset {} is torn", self.path, offset);
+                self.torn_tail = Some(offset);
                 None
             }
             Err(e) => Some(Err(e)),
-            ok => Some(ok),
         }
     }
 }
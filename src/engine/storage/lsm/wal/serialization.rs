@@ -1,4 +1,28 @@
+use super::{binio, Error, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use crc::{Crc, CRC_32_ISCSI};
 use serde::{Deserialize, Serialize};
+use std::io::{self, Read};
+use std::path;
+
+pub(crate) const STANZA: &str = "r2d2::wal";
+/// The version of the WAL format that is written
+pub(crate) const VERSION: u8 = 4;
+/// The version of the initial release, which can still be read
+///
+/// Its records are single operations without sequence numbers and checksums.
+pub(crate) const INITIAL_VERSION: u8 = 1;
+
+/// The checksum of the records is CRC32C
+const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+
+/// The size of the header that precedes the data of every record
+///
+/// | data length: u32 | data checksum: u32 | header checksum: u32 |
+///
+/// The header checksum covers the length and the data checksum, so that a corrupted
+/// length is detected before it's used to find the end of the record.
+pub(crate) const RECORD_HEADER_SIZE: u64 = 12;
 
 #[derive(Deserialize, Serialize, PartialEq)]
 pub(crate) struct FileHeader {
@@ -14,3 +38,143 @@ impl FileHeader {
         }
     }
 }
+
+/// Read the header at the start of a WAL file and check that it's a WAL in a supported version
+pub(crate) fn read_header<R: io::Read>(r: &mut R, path: &path::Path) -> Result<FileHeader> {
+    let header: FileHeader = match binio::read_data_owned(r) {
+        Ok(header) => header,
+        Err(binio::Error::SerializationError) => return Err(Error::NotAWalFile(path.into())),
+        Err(binio::Error::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            return Err(Error::NotAWalFile(path.into()))
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    if header.stanza != STANZA.as_bytes() {
+        return Err(Error::NotAWalFile(path.into()));
+    }
+    match header.version {
+        VERSION | INITIAL_VERSION => Ok(header),
+        version => Err(Error::UnsupportedVersion(version)),
+    }
+}
+
+/// A record frame as it has been read from the WAL
+#[derive(Debug, PartialEq)]
+pub(crate) enum Frame {
+    /// The data of a record whose checksums match
+    Record(Vec<u8>),
+    /// The WAL ends before the record
+    End,
+    /// The WAL ends within the record, so its write has been interrupted
+    Incomplete,
+    /// The header doesn't match its checksum, so the end of the record is unknown
+    CorruptedHeader,
+    /// The data doesn't match its checksum, the record has the given size including its header
+    CorruptedData(u64),
+}
+
+/// Write the `data` of a record together with its header
+pub(crate) fn write_record<W: io::Write>(w: &mut W, data: &[u8]) -> io::Result<usize> {
+    let mut header = [0u8; RECORD_HEADER_SIZE as usize];
+    LittleEndian::write_u32(&mut header[0..4], data.len() as u32);
+    LittleEndian::write_u32(&mut header[4..8], CRC.checksum(data));
+    let header_checksum = CRC.checksum(&header[0..8]);
+    LittleEndian::write_u32(&mut header[8..12], header_checksum);
+
+    w.write_all(&header)?;
+    w.write_all(data)?;
+    Ok(header.len() + data.len())
+}
+
+/// Read the next record and verify its checksums
+pub(crate) fn read_record<R: io::Read>(r: &mut R) -> io::Result<Frame> {
+    let mut header = [0u8; RECORD_HEADER_SIZE as usize];
+    let read = read_up_to(r, &mut header)?;
+    if read == 0 {
+        return Ok(Frame::End);
+    } else if read < header.len() {
+        return Ok(Frame::Incomplete);
+    }
+    if CRC.checksum(&header[0..8]) != LittleEndian::read_u32(&header[8..12]) {
+        return Ok(Frame::CorruptedHeader);
+    }
+
+    let length = LittleEndian::read_u32(&header[0..4]) as u64;
+    let mut data = Vec::with_capacity(length as usize);
+    r.take(length).read_to_end(&mut data)?;
+    if (data.len() as u64) < length {
+        Ok(Frame::Incomplete)
+    } else if CRC.checksum(&data) != LittleEndian::read_u32(&header[4..8]) {
+        Ok(Frame::CorruptedData(RECORD_HEADER_SIZE + length))
+    } else {
+        Ok(Frame::Record(data))
+    }
+}
+
+/// Fill the `buf` unless the reader ends before, returns the number of bytes read
+fn read_up_to<R: io::Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match r.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_record, write_record, Frame};
+    use std::io;
+
+    fn written(records: &[&[u8]]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for data in records {
+            write_record(&mut buf, data).unwrap();
+        }
+        buf
+    }
+
+    #[test]
+    fn records_are_read_back() {
+        let mut reader = io::Cursor::new(written(&[b"foo", b"", b"bar"]));
+        assert_eq!(
+            Frame::Record(b"foo".to_vec()),
+            read_record(&mut reader).unwrap()
+        );
+        assert_eq!(Frame::Record(vec![]), read_record(&mut reader).unwrap());
+        assert_eq!(
+            Frame::Record(b"bar".to_vec()),
+            read_record(&mut reader).unwrap()
+        );
+        assert_eq!(Frame::End, read_record(&mut reader).unwrap());
+    }
+
+    #[test]
+    fn cut_off_records_are_incomplete() {
+        let buf = written(&[b"foobar"]);
+        for length in 1..buf.len() {
+            let mut reader = io::Cursor::new(&buf[..length]);
+            assert_eq!(Frame::Incomplete, read_record(&mut reader).unwrap());
+        }
+    }
+
+    #[test]
+    fn flipped_bits_are_detected() {
+        let buf = written(&[b"foobar"]);
+        for byte in 0..buf.len() {
+            let mut corrupted = buf.clone();
+            corrupted[byte] ^= 0x10;
+            let frame = read_record(&mut io::Cursor::new(corrupted)).unwrap();
+            if byte < 12 {
+                assert_eq!(Frame::CorruptedHeader, frame);
+            } else {
+                assert_eq!(Frame::CorruptedData(buf.len() as u64), frame);
+            }
+        }
+    }
+}
@@ -1,58 +1,300 @@
 use super::binio;
-use super::Result;
-use crate::engine::storage::lsm::wal::serialization::FileHeader;
-use crate::engine::storage::lsm::wal::Operation;
-use crate::engine::{Key, Value};
+use super::{Error, Result};
+use crate::engine::storage::lsm::wal::serialization::{self, FileHeader, STANZA, VERSION};
+use crate::engine::storage::lsm::wal::{Operation, SyncPolicy};
+use crate::engine::storage::lsm::SequenceNumber;
+use serde::Serialize;
 use std::io::{BufWriter, Write};
-use std::{fs, io, path};
-
-const VERSION: u8 = 1;
-const STANZA: &str = "r2d2::wal";
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{fs, io, path, thread};
 
 /// The WalWriter is the main interface you will interact with.
+///
+/// Committed records are synced to disk according to the `SyncPolicy`.
+/// Once a write to the file has failed, the writer is poisoned and rejects all further writes,
+/// since the file might end with a partial record that later records must not follow.
 pub struct WalWriter {
     file: io::BufWriter<fs::File>,
+    sync_policy: SyncPolicy,
+    last_sync: Instant,
+    /// The number of bytes that have been written since the last sync
+    unsynced: u64,
+    /// The size of the file, including the buffered bytes
+    size: u64,
+    poisoned: bool,
 }
 
 impl WalWriter {
-    pub fn resume(path: &path::Path) -> Result<Self> {
-        let writer = fs::OpenOptions::new()
+    /// Continue to write to the WAL at `path`, which is created if it doesn't exist
+    ///
+    /// Only WALs of the current version can be continued, older versions can only be read.
+    pub fn resume(path: &path::Path, sync_policy: SyncPolicy) -> Result<Self> {
+        let mut writer = fs::OpenOptions::new()
             .create(true)
+            .read(true)
             .append(true)
-            .write(true)
             .open(path)?;
+        if writer.metadata()?.len() == 0 {
+            binio::write_data(&mut writer, FileHeader::new(STANZA, VERSION))?;
+            writer.sync_all()?;
+        } else {
+            let header = serialization::read_header(&mut writer, path)?;
+            if header.version != VERSION {
+                return Err(Error::UnsupportedVersion(header.version));
+            }
+        }
+        sync_directory(path)?;
 
-        Ok(WalWriter {
-            file: BufWriter::new(writer),
-        })
+        let size = writer.metadata()?.len();
+        Ok(Self::new(writer, sync_policy, size))
     }
 
     pub fn null() -> Result<Self> {
         let writer = fs::OpenOptions::new()
             .append(true)
-            .write(true)
             .open(path::Path::new("/dev/null"))?;
 
-        Ok(WalWriter {
-            file: BufWriter::new(writer),
-        })
+        Ok(Self::new(writer, SyncPolicy::Os, 0))
     }
 
-    pub fn create(path: &path::Path) -> Result<WalWriter> {
-        let mut writer = fs::OpenOptions::new().create(true).write(true).open(path)?;
+    /// Create the WAL file, which is synced to disk together with its directory entry
+    pub fn create(path: &path::Path, sync_policy: SyncPolicy) -> Result<WalWriter> {
+        let mut writer = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
         let header = FileHeader::new(STANZA, VERSION);
 
-        binio::write_data(&mut writer, header)?;
+        let size = binio::write_data(&mut writer, header)?;
+        writer.sync_all()?;
+        sync_directory(path)?;
 
-        Ok(WalWriter {
-            file: BufWriter::new(writer),
-        })
+        Ok(Self::new(writer, sync_policy, size as u64))
+    }
+
+    fn new(file: fs::File, sync_policy: SyncPolicy, size: u64) -> Self {
+        WalWriter {
+            file: BufWriter::new(file),
+            sync_policy,
+            last_sync: Instant::now(),
+            unsynced: 0,
+            size,
+            poisoned: false,
+        }
+    }
+
+    /// The size of the WAL file in bytes, including the records that haven't been committed yet
+    pub fn size(&self) -> u64 {
+        self.size
     }
 
-    pub fn write(&mut self, op: Operation<&Key, &Value>) -> Result<usize> {
-        let size = binio::write_data(&mut self.file, op)?;
-        self.file.flush()?;
+    /// Append the operations of a write to the WAL and commit it right away
+    ///
+    /// The operations are written as a single record, so that they are recovered
+    /// all or nothing. They get consecutive sequence numbers starting with `seq`.
+    pub fn write<K: Serialize, V: Serialize>(
+        &mut self,
+        seq: SequenceNumber,
+        ops: &[Operation<K, V>],
+    ) -> Result<usize> {
+        let size = self.append(seq, ops)?;
+        self.commit()?;
 
         Ok(size)
     }
+
+    /// Append the operations of a write as a single record, without committing it
+    ///
+    /// The record is only buffered, it is written together with all other appended
+    /// records by the next `commit`. This allows group commits of concurrent writes.
+    pub fn append<K: Serialize, V: Serialize>(
+        &mut self,
+        seq: SequenceNumber,
+        ops: &[Operation<K, V>],
+    ) -> Result<usize> {
+        self.check()?;
+        let data = binio::serialize((seq, ops))?;
+        let written = serialization::write_record(&mut self.file, &data);
+        let size = self.poison_on_error(written)?;
+        self.unsynced += size as u64;
+        self.size += size as u64;
+        Ok(size)
+    }
+
+    /// Write all appended records to the file and sync it, if the `SyncPolicy` says so
+    pub fn commit(&mut self) -> Result<()> {
+        self.check()?;
+        let flushed = self.file.flush();
+        self.poison_on_error(flushed)?;
+
+        let sync_due = match self.sync_policy {
+            SyncPolicy::Always => true,
+            SyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+            SyncPolicy::Bytes(bytes) => self.unsynced >= bytes.as_u64(),
+            SyncPolicy::Os => false,
+        };
+        if sync_due {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Sync all committed records to disk, regardless of the `SyncPolicy`
+    pub fn sync(&mut self) -> Result<()> {
+        self.check()?;
+        let synced = self.file.get_ref().sync_data();
+        self.poison_on_error(synced)?;
+        self.last_sync = Instant::now();
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// Sync the records that have been committed since the last sync,
+    /// unless the `SyncPolicy` leaves the syncs to the OS
+    pub fn sync_pending(&mut self) -> Result<()> {
+        if self.unsynced == 0 || self.sync_policy == SyncPolicy::Os {
+            return Ok(());
+        }
+        self.sync()
+    }
+
+    /// Fail with `Error::Poisoned` if a previous write to the file has failed
+    pub fn check(&self) -> Result<()> {
+        if self.poisoned {
+            return Err(Error::Poisoned);
+        }
+        Ok(())
+    }
+
+    fn poison_on_error<T>(&mut self, result: io::Result<T>) -> Result<T> {
+        if result.is_err() {
+            self.poisoned = true;
+        }
+        Ok(result?)
+    }
+}
+
+impl Drop for WalWriter {
+    fn drop(&mut self) {
+        if self.poisoned {
+            return;
+        }
+        let synced = match self.file.flush() {
+            Ok(()) => self.sync_pending(),
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = synced {
+            log::warn!(target: "wal", "failed to sync the WAL when it was closed: {:?}", e);
+        }
+    }
+}
+
+/// Syncs the WAL in the background for the `SyncPolicy::Interval`
+///
+/// A commit only syncs once the interval has passed, so the last commits before
+/// a pause wouldn't be synced until the next one. Dropping the syncer stops it.
+pub struct IntervalSync {
+    stop: Option<mpsc::Sender<()>>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl IntervalSync {
+    /// Start the background thread, which syncs the `writer` every `interval`
+    pub fn start(writer: Arc<Mutex<WalWriter>>, interval: Duration) -> Result<IntervalSync> {
+        let (stop, stopped) = mpsc::channel::<()>();
+
+        let handle = thread::Builder::new()
+            .name("wal-sync".into())
+            .spawn(move || {
+                while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    let synced = match writer.lock() {
+                        Ok(mut writer) => writer.sync_pending(),
+                        Err(e) => Err(e.into()),
+                    };
+                    if let Err(e) = synced {
+                        // a failed sync has poisoned the writer, which rejects all further writes
+                        log::error!(target: "wal", "background sync failed, stopping syncs: {:?}", e);
+                        return;
+                    }
+                }
+            })?;
+
+        Ok(IntervalSync {
+            stop: Some(stop),
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for IntervalSync {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Sync the directory that holds the file at `path`, which makes its directory entry durable
+fn sync_directory(path: &path::Path) -> Result<()> {
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => path::Path::new("."),
+    };
+    fs::File::open(directory)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, IntervalSync, WalWriter};
+    use crate::engine::storage::lsm::wal::{Operation, SyncPolicy};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use std::{fs, thread};
+    use tempfile::tempdir;
+
+    #[test]
+    fn failed_write_poisons_the_writer() {
+        let full = fs::OpenOptions::new()
+            .write(true)
+            .open("/dev/full")
+            .unwrap();
+        let mut writer = WalWriter::new(full, SyncPolicy::Os, 0);
+        let ops = [Operation::Set("foo", "bar")];
+
+        // the record is only buffered, the write fails when it's committed
+        assert!(writer.append(1, &ops).is_ok());
+        assert!(matches!(writer.commit(), Err(Error::IoError(_))));
+
+        assert!(matches!(writer.append(2, &ops), Err(Error::Poisoned)));
+        assert!(matches!(writer.commit(), Err(Error::Poisoned)));
+    }
+
+    #[test]
+    fn interval_sync_syncs_without_further_commits() {
+        let dir = tempdir().unwrap();
+        let hour = SyncPolicy::Interval(Duration::from_secs(3600));
+        let writer = WalWriter::create(&dir.path().join("wal.log"), hour).unwrap();
+        let writer = Arc::new(Mutex::new(writer));
+        let _sync = IntervalSync::start(writer.clone(), Duration::from_millis(5)).unwrap();
+
+        writer
+            .lock()
+            .unwrap()
+            .write(1, &[Operation::Set("foo", "bar")])
+            .unwrap();
+        assert!(writer.lock().unwrap().unsynced > 0);
+
+        // the commit didn't sync, since the interval of the policy hasn't passed yet
+        for _ in 0..200 {
+            if writer.lock().unwrap().unsynced == 0 {
+                return;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        panic!("the committed record hasn't been synced in the background");
+    }
 }
@@ -0,0 +1,105 @@
+//! Optimistic transactions
+//!
+//! A transaction reads from a snapshot that is taken when it begins and buffers
+//! all of its writes locally. Nothing is written to the store until the transaction commits.
+//!
+//! On commit the transaction is validated: no key that it has read may have been written
+//! since its snapshot was taken. Writes are compared by sequence number rather than by value,
+//! so a key that was changed and then changed back still conflicts. If another write touched
+//! one of these keys in the meantime the commit fails with `Error::TransactionConflict` and none of the
+//! buffered writes are applied. Otherwise the writes are applied atomically as one `WriteBatch`.
+//!
+//! Keys that are only written but never read are not validated, so two transactions
+//! that blindly overwrite the same key both commit and the later commit wins.
+use super::{Engine, Error, Key, Result, Snapshot, Value, WriteBatch};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Debug;
+
+/// A transaction on the engine, created with `Engine::begin`
+///
+/// Dropping a transaction without committing it discards all of its writes.
+pub struct Transaction<'a> {
+    engine: &'a Engine,
+    snapshot: Snapshot,
+    reads: BTreeSet<Key>,
+    writes: BTreeMap<Key, Option<Value>>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(engine: &'a Engine, snapshot: Snapshot) -> Self {
+        Transaction {
+            engine,
+            snapshot,
+            reads: BTreeSet::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Lookup a value for the given key
+    ///
+    /// The transaction sees its own writes, all other keys are read from the state
+    /// of the store when the transaction began. The key becomes part of the read set
+    /// that is validated on commit.
+    pub fn get(&mut self, key: &Key) -> Result<Option<Value>> {
+        if let Some(write) = self.writes.get(key) {
+            return Ok(write.clone());
+        }
+        log::trace!(target: "engine", "Transactional lookup {:?}", key);
+        self.reads.insert(key.clone());
+        self.engine.get_at(key, &self.snapshot)
+    }
+
+    /// Buffer the insertion of a key value pair
+    pub fn set<K: Into<Key> + Debug, V: Into<Value> + Debug>(&mut self, key: K, value: V) {
+        log::trace!(target: "engine", "Transactional insert {:?} -> {:?}", key, value);
+        self.writes.insert(key.into(), Some(value.into()));
+    }
+
+    /// Buffer the deletion of a key
+    pub fn del(&mut self, key: &Key) {
+        log::trace!(target: "engine", "Transactional delete {:?}", key);
+        self.writes.insert(key.clone(), None);
+    }
+
+    /// Validate the transaction and apply its writes atomically
+    ///
+    /// Fails with `Error::TransactionConflict` if a key that the transaction has read
+    /// has been written since the transaction began. In that case nothing is written
+    /// and the transaction can be retried from the start.
+    pub fn commit(self) -> Result<()> {
+        let Transaction {
+            engine,
+            snapshot,
+            reads,
+            writes,
+        } = self;
+
+        let mut batch = WriteBatch::new();
+        for (key, write) in writes {
+            match write {
+                Some(value) => batch.set(key, value),
+                None => batch.del(key),
+            };
+        }
+
+        // the reads are validated while no other write can happen
+        let mut conflict = None;
+        engine.lsm.write_if(batch, |lsm| {
+            for key in &reads {
+                if lsm.latest_seq(key)? > Some(snapshot.sequence()) {
+                    conflict = Some(key.clone());
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        })?;
+
+        match conflict {
+            Some(key) => {
+                log::debug!(target: "engine", "Transaction conflict on {:?}", key);
+                Err(Error::TransactionConflict(key))
+            }
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use r2d2::engine;
+use r2d2::engine::{Engine, Key, Value, WriteBatch};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use tempfile::tempdir;
+
+const WRITERS: usize = 4;
+const READERS: usize = 4;
+const WRITES: u64 = 500;
+
+fn start_engine(path: &std::path::Path) -> anyhow::Result<Engine> {
+    let mut config_builder = engine::configuration::Builder::default();
+    config_builder
+        .storage
+        .with_storage_path(path.to_path_buf())?;
+    // small memtables, so that flushes and compactions happen while the threads run
+    config_builder.storage.with_memtable_size(4 * 1024)?;
+    Ok(Engine::start(config_builder.build()?)?)
+}
+
+fn counter(value: Option<Value>) -> u64 {
+    value
+        .map(|v| String::from_utf8(v.to_vec()).unwrap().parse().unwrap())
+        .unwrap_or(0)
+}
+
+#[test]
+fn engine_can_be_shared_between_threads() {
+    fn assert_send_and_sync<T: Send + Sync>() {}
+    assert_send_and_sync::<Engine>();
+}
+
+// Every writer increments the counter of its own key. Readers check that every read returns
+// a value that has been written concurrently to or after the last acknowledged write, and
+// that the values they see never go backwards. That is what linearizability means for registers.
+#[test]
+fn concurrent_reads_and_writes_are_linearizable() -> anyhow::Result<()> {
+    let storage_dir = tempdir()?;
+    let ngin = start_engine(storage_dir.path())?;
+    let started: Vec<AtomicU64> = (0..WRITERS).map(|_| AtomicU64::new(0)).collect();
+    let acknowledged: Vec<AtomicU64> = (0..WRITERS).map(|_| AtomicU64::new(0)).collect();
+    let done = AtomicBool::new(false);
+    let key = |writer: usize| Key::from(format!("counter-{}", writer));
+
+    thread::scope(|scope| {
+        let writers: Vec<_> = (0..WRITERS)
+            .map(|writer| {
+                let (ngin, started, acknowledged) = (&ngin, &started, &acknowledged);
+                scope.spawn(move || {
+                    for i in 1..=WRITES {
+                        started[writer].store(i, Ordering::SeqCst);
+                        ngin.set(key(writer), format!("{}", i)).unwrap();
+                        acknowledged[writer].store(i, Ordering::SeqCst);
+                    }
+                })
+            })
+            .collect();
+
+        for reader in 0..READERS {
+            let (ngin, started, acknowledged, done) = (&ngin, &started, &acknowledged, &done);
+            scope.spawn(move || {
+                let mut seen = [0; WRITERS];
+                let mut round = reader;
+                while !done.load(Ordering::SeqCst) {
+                    let writer = round % WRITERS;
+                    round += 1;
+
+                    let lower = acknowledged[writer].load(Ordering::SeqCst);
+                    let value = counter(ngin.get(&key(writer)).unwrap());
+                    let upper = started[writer].load(Ordering::SeqCst);
+
+                    assert!(value >= lower, "read {} after {} was written", value, lower);
+                    assert!(value <= upper, "read {} before it was written", value);
+                    assert!(
+                        value >= seen[writer],
+                        "read {} after {}",
+                        value,
+                        seen[writer]
+                    );
+                    seen[writer] = value;
+                }
+            });
+        }
+
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        done.store(true, Ordering::SeqCst);
+    });
+
+    for writer in 0..WRITERS {
+        assert_eq!(WRITES, counter(ngin.get(&key(writer))?));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn concurrent_readers_never_see_partial_batches() -> anyhow::Result<()> {
+    let storage_dir = tempdir()?;
+    let ngin = start_engine(storage_dir.path())?;
+    let done = AtomicBool::new(false);
+    let keys = ["a", "b", "c"];
+
+    thread::scope(|scope| {
+        let writer = scope.spawn(|| {
+            for i in 1..=WRITES {
+                let mut batch = WriteBatch::new();
+                for key in &keys {
+                    batch.set(*key, format!("{}", i));
+                }
+                ngin.write(batch).unwrap();
+            }
+        });
+
+        for _ in 0..READERS {
+            scope.spawn(|| {
+                while !done.load(Ordering::SeqCst) {
+                    let snapshot = ngin.snapshot().unwrap();
+                    let values: Vec<u64> = keys
+                        .iter()
+                        .map(|key| counter(ngin.get_at(&Key::from(*key), &snapshot).unwrap()))
+                        .collect();
+                    assert!(values.iter().all(|v| *v == values[0]), "{:?}", values);
+
+                    let scanned: Vec<u64> = ngin
+                        .iter()
+                        .unwrap()
+                        .map(|record| counter(Some(record.unwrap().1)))
+                        .collect();
+                    assert!(scanned.iter().all(|v| *v == scanned[0]), "{:?}", scanned);
+                }
+            });
+        }
+
+        writer.join().unwrap();
+        done.store(true, Ordering::SeqCst);
+    });
+
+    Ok(())
+}
+
+// Writes that are committed in the same group must all be in the WAL when their writers return
+#[test]
+fn concurrent_writes_are_recovered_after_a_restart() -> anyhow::Result<()> {
+    let storage_dir = tempdir()?;
+    let key = |writer: usize, i: u64| Key::from(format!("key-{}-{}", writer, i));
+
+    {
+        let ngin = start_engine(storage_dir.path())?;
+        thread::scope(|scope| {
+            for writer in 0..WRITERS {
+                let ngin = &ngin;
+                scope.spawn(move || {
+                    for i in 1..=WRITES {
+                        ngin.set(key(writer, i), format!("{}", i)).unwrap();
+                        if i % 10 == 0 {
+                            ngin.del(&key(writer, i)).unwrap();
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    let ngin = start_engine(storage_dir.path())?;
+    for writer in 0..WRITERS {
+        for i in 1..=WRITES {
+            let expected = if i % 10 == 0 { 0 } else { i };
+            assert_eq!(expected, counter(ngin.get(&key(writer, i))?));
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn concurrent_transactions_do_not_lose_updates() -> anyhow::Result<()> {
+    let storage_dir = tempdir()?;
+    let ngin = start_engine(storage_dir.path())?;
+    let key = Key::from("counter");
+    let increments = 100;
+
+    thread::scope(|scope| {
+        for _ in 0..WRITERS {
+            scope.spawn(|| {
+                for _ in 0..increments {
+                    // retry the read-modify-write until it doesn't conflict
+                    loop {
+                        let mut txn = ngin.begin().unwrap();
+                        let value = counter(txn.get(&key).unwrap());
+                        txn.set(key.clone(), format!("{}", value + 1));
+                        match txn.commit() {
+                            Ok(()) => break,
+                            Err(engine::Error::TransactionConflict(_)) => continue,
+                            Err(e) => panic!("commit failed: {:?}", e),
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    assert_eq!(WRITERS as u64 * increments, counter(ngin.get(&key)?));
+    Ok(())
+}
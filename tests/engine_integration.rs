@@ -9,7 +9,7 @@ fn basic_operation_works() -> anyhow::Result<()> {
         .storage
         .with_storage_path(tempdir()?.path().to_path_buf())?;
     let config = config_builder.build()?;
-    let mut ngin = engine::Engine::start(config)?;
+    let ngin = engine::Engine::start(config)?;
 
     assert_eq!(ngin.get(&Key::from("foo"))?, None);
 
@@ -27,3 +27,383 @@ fn basic_operation_works() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn iteration_covers_flushed_data_and_hides_deletes() -> anyhow::Result<()> {
+    let mut config_builder = engine::configuration::Builder::default();
+    config_builder
+        .storage
+        .with_storage_path(tempdir()?.path().to_path_buf())?;
+    config_builder.storage.with_memtable_size(64)?;
+    let config = config_builder.build()?;
+    let ngin = engine::Engine::start(config)?;
+
+    for i in 0..20 {
+        ngin.set(format!("key-{:02}", i), format!("value-{:02}", i))?;
+    }
+    ngin.set("key-05", "updated")?;
+    ngin.del(&Key::from("key-10"))?;
+
+    let records = ngin.iter()?.collect::<Result<Vec<_>, _>>()?;
+
+    let mut expected: Vec<(Key, Value)> = (0..20)
+        .filter(|i| *i != 10)
+        .map(|i| {
+            (
+                Key::from(format!("key-{:02}", i)),
+                Value::from(format!("value-{:02}", i)),
+            )
+        })
+        .collect();
+    expected[5].1 = Value::from("updated");
+    assert_eq!(expected, records);
+
+    Ok(())
+}
+
+#[test]
+fn range_and_prefix_scans_return_matching_keys_only() -> anyhow::Result<()> {
+    let mut config_builder = engine::configuration::Builder::default();
+    config_builder
+        .storage
+        .with_storage_path(tempdir()?.path().to_path_buf())?;
+    config_builder.storage.with_memtable_size(64)?;
+    let config = config_builder.build()?;
+    let ngin = engine::Engine::start(config)?;
+
+    for user in ["alice", "bob", "carol"] {
+        for i in 0..5 {
+            ngin.set(format!("{}/{}", user, i), format!("{}-{}", user, i))?;
+        }
+    }
+    ngin.del(&Key::from("bob/2"))?;
+
+    let keys = |iter: engine::EngineIterator| -> anyhow::Result<Vec<String>> {
+        iter.map(|record| Ok(String::from_utf8(record?.0.to_vec())?))
+            .collect()
+    };
+
+    assert_eq!(
+        vec!["bob/0", "bob/1", "bob/3", "bob/4"],
+        keys(ngin.scan_prefix("bob/")?)?
+    );
+    assert_eq!(
+        vec!["alice/3", "alice/4", "bob/0"],
+        keys(ngin.range(Key::from("alice/3")..Key::from("bob/1"))?)?
+    );
+    assert_eq!(
+        vec!["carol/3", "carol/4"],
+        keys(ngin.range(Key::from("carol/3")..)?)?
+    );
+    assert!(keys(ngin.scan_prefix("dave/")?)?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn cursor_moves_in_both_directions() -> anyhow::Result<()> {
+    let mut config_builder = engine::configuration::Builder::default();
+    config_builder
+        .storage
+        .with_storage_path(tempdir()?.path().to_path_buf())?;
+    config_builder.storage.with_memtable_size(64)?;
+    let config = config_builder.build()?;
+    let ngin = engine::Engine::start(config)?;
+
+    for i in 0..30 {
+        ngin.set(format!("item-{:02}", i), format!("value-{:02}", i))?;
+    }
+    ngin.del(&Key::from("item-28"))?;
+    ngin.del(&Key::from("item-11"))?;
+
+    let mut cursor = ngin.cursor()?;
+    let key = |cursor: &engine::EngineCursor| -> Option<String> {
+        cursor
+            .current()
+            .map(|(k, _)| String::from_utf8(k.to_vec()).unwrap())
+    };
+    assert_eq!(None, key(&cursor));
+
+    // the latest three items
+    cursor.seek_to_last()?;
+    let mut latest = Vec::new();
+    for _ in 0..3 {
+        latest.push(key(&cursor).unwrap());
+        cursor.prev()?;
+    }
+    assert_eq!(vec!["item-29", "item-27", "item-26"], latest);
+
+    cursor.seek(&Key::from("item-11"))?;
+    assert_eq!(Some("item-12".to_string()), key(&cursor));
+    cursor.prev()?;
+    assert_eq!(Some("item-10".to_string()), key(&cursor));
+    cursor.next()?;
+    assert_eq!(Some("item-12".to_string()), key(&cursor));
+
+    cursor.seek_for_prev(&Key::from("item-11"))?;
+    assert_eq!(
+        Some((&Key::from("item-10"), &Value::from("value-10"))),
+        cursor.current()
+    );
+
+    cursor.seek_to_first()?;
+    cursor.prev()?;
+    assert_eq!(None, key(&cursor));
+
+    Ok(())
+}
+
+#[test]
+fn snapshot_reads_see_a_frozen_view() -> anyhow::Result<()> {
+    let mut config_builder = engine::configuration::Builder::default();
+    config_builder
+        .storage
+        .with_storage_path(tempdir()?.path().to_path_buf())?;
+    config_builder.storage.with_memtable_size(64)?;
+    let config = config_builder.build()?;
+    let ngin = engine::Engine::start(config)?;
+
+    for i in 0..10 {
+        ngin.set(format!("item-{:02}", i), format!("old-{:02}", i))?;
+    }
+    let snapshot = ngin.snapshot()?;
+
+    ngin.set("item-03", "new")?;
+    ngin.set("item-10", "new")?;
+    ngin.del(&Key::from("item-05"))?;
+
+    assert_eq!(
+        Some(Value::from("old-03")),
+        ngin.get_at(&Key::from("item-03"), &snapshot)?
+    );
+    assert_eq!(None, ngin.get_at(&Key::from("item-10"), &snapshot)?);
+    assert_eq!(Some(Value::from("new")), ngin.get(&Key::from("item-03"))?);
+
+    let keys = |iter: engine::EngineIterator| -> anyhow::Result<Vec<String>> {
+        iter.map(|record| Ok(String::from_utf8(record?.0.to_vec())?))
+            .collect()
+    };
+    assert_eq!(10, keys(ngin.iter_at(&snapshot)?)?.len());
+    assert_eq!(10, keys(ngin.iter()?)?.len());
+    assert_eq!(
+        vec!["item-04", "item-05", "item-06"],
+        keys(ngin.range_at(Key::from("item-04")..Key::from("item-07"), &snapshot)?)?
+    );
+    assert_eq!(
+        vec!["item-04", "item-06"],
+        keys(ngin.range(Key::from("item-04")..Key::from("item-07"))?)?
+    );
+    assert_eq!(
+        vec![
+            "item-00", "item-01", "item-02", "item-03", "item-04", "item-05", "item-06", "item-07",
+            "item-08", "item-09"
+        ],
+        keys(ngin.scan_prefix_at("item-", &snapshot)?)?
+    );
+
+    let mut cursor = ngin.cursor_at(&snapshot)?;
+    cursor.seek_to_last()?;
+    assert_eq!(
+        Some((&Key::from("item-09"), &Value::from("old-09"))),
+        cursor.current()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn write_batches_are_applied_atomically() -> anyhow::Result<()> {
+    let mut config_builder = engine::configuration::Builder::default();
+    config_builder
+        .storage
+        .with_storage_path(tempdir()?.path().to_path_buf())?;
+    config_builder.storage.with_memtable_size(64)?;
+    let config = config_builder.build()?;
+    let ngin = engine::Engine::start(config)?;
+
+    ngin.set("account/alice", "100")?;
+    ngin.set("account/bob", "0")?;
+    let before = ngin.snapshot()?;
+
+    let mut batch = engine::WriteBatch::new();
+    batch
+        .set("account/alice", "70")
+        .set("account/bob", "30")
+        .set("transfer/1", "alice->bob:30")
+        .del("account/carol");
+    assert_eq!(4, batch.len());
+    ngin.write(batch)?;
+
+    let balances = |iter: engine::EngineIterator| -> anyhow::Result<Vec<(Key, Value)>> {
+        Ok(iter.collect::<Result<Vec<_>, _>>()?)
+    };
+    assert_eq!(
+        vec![
+            (Key::from("account/alice"), Value::from("70")),
+            (Key::from("account/bob"), Value::from("30")),
+        ],
+        balances(ngin.scan_prefix("account/")?)?
+    );
+    assert_eq!(
+        vec![
+            (Key::from("account/alice"), Value::from("100")),
+            (Key::from("account/bob"), Value::from("0")),
+        ],
+        balances(ngin.scan_prefix_at("account/", &before)?)?
+    );
+    assert_eq!(
+        Some(Value::from("alice->bob:30")),
+        ngin.get(&Key::from("transfer/1"))?
+    );
+
+    ngin.write(engine::WriteBatch::new())?;
+
+    Ok(())
+}
+
+#[test]
+fn transactions_detect_conflicting_writes() -> anyhow::Result<()> {
+    let mut config_builder = engine::configuration::Builder::default();
+    config_builder
+        .storage
+        .with_storage_path(tempdir()?.path().to_path_buf())?;
+    let config = config_builder.build()?;
+    let ngin = engine::Engine::start(config)?;
+    let balance = Key::from("balance");
+    ngin.set(balance.clone(), "100")?;
+
+    // a read-modify-write that races with another write is rejected
+    let mut txn = ngin.begin()?;
+    assert_eq!(txn.get(&balance)?, Some(Value::from("100")));
+    txn.set(balance.clone(), "150");
+    txn.set("audit", "deposit");
+    ngin.set(balance.clone(), "80")?;
+    assert!(matches!(
+        txn.commit(),
+        Err(engine::Error::TransactionConflict(key)) if key == balance
+    ));
+    assert_eq!(ngin.get(&balance)?, Some(Value::from("80")));
+    assert_eq!(ngin.get(&Key::from("audit"))?, None);
+
+    // the retry sees the new value and commits
+    let mut txn = ngin.begin()?;
+    assert_eq!(txn.get(&balance)?, Some(Value::from("80")));
+    txn.set(balance.clone(), "130");
+    txn.set("audit", "deposit");
+    txn.commit()?;
+    assert_eq!(ngin.get(&balance)?, Some(Value::from("130")));
+    assert_eq!(ngin.get(&Key::from("audit"))?, Some(Value::from("deposit")));
+
+    // deleting a key that has been read is a conflict as well
+    let mut txn = ngin.begin()?;
+    txn.get(&Key::from("audit"))?;
+    ngin.del(&Key::from("audit"))?;
+    assert!(matches!(
+        txn.commit(),
+        Err(engine::Error::TransactionConflict(_))
+    ));
+
+    // a key that is changed and changed back has still been written in the meantime
+    let mut txn = ngin.begin()?;
+    assert_eq!(txn.get(&balance)?, Some(Value::from("130")));
+    txn.set(balance.clone(), "180");
+    ngin.set(balance.clone(), "0")?;
+    ngin.set(balance.clone(), "130")?;
+    assert!(matches!(
+        txn.commit(),
+        Err(engine::Error::TransactionConflict(key)) if key == balance
+    ));
+    assert_eq!(ngin.get(&balance)?, Some(Value::from("130")));
+
+    Ok(())
+}
+
+#[test]
+fn transactions_read_their_own_writes() -> anyhow::Result<()> {
+    let mut config_builder = engine::configuration::Builder::default();
+    config_builder
+        .storage
+        .with_storage_path(tempdir()?.path().to_path_buf())?;
+    let config = config_builder.build()?;
+    let ngin = engine::Engine::start(config)?;
+    ngin.set("a", "1")?;
+    ngin.set("b", "2")?;
+
+    let mut txn = ngin.begin()?;
+    txn.set("a", "10");
+    txn.del(&Key::from("b"));
+    assert_eq!(txn.get(&Key::from("a"))?, Some(Value::from("10")));
+    assert_eq!(txn.get(&Key::from("b"))?, None);
+
+    // buffered writes are invisible outside of the transaction
+    assert_eq!(ngin.get(&Key::from("a"))?, Some(Value::from("1")));
+    assert_eq!(ngin.get(&Key::from("b"))?, Some(Value::from("2")));
+
+    // keys that are only written aren't validated, the later commit wins
+    ngin.set("a", "5")?;
+    txn.commit()?;
+    assert_eq!(ngin.get(&Key::from("a"))?, Some(Value::from("10")));
+    assert_eq!(ngin.get(&Key::from("b"))?, None);
+
+    // a transaction that is dropped doesn't write anything
+    let mut txn = ngin.begin()?;
+    txn.set("c", "3");
+    drop(txn);
+    assert_eq!(ngin.get(&Key::from("c"))?, None);
+
+    Ok(())
+}
+
+#[test]
+fn verification_reports_corrupted_tables() -> anyhow::Result<()> {
+    let storage_dir = tempdir()?;
+    let mut config_builder = engine::configuration::Builder::default();
+    config_builder
+        .storage
+        .with_storage_path(storage_dir.path().to_path_buf())?;
+    config_builder.storage.with_memtable_size(1024)?;
+    // no compaction, which would read the corrupted table as well
+    config_builder.storage.with_level0_compaction_trigger(100)?;
+    let config = config_builder.build()?;
+
+    {
+        let ngin = engine::Engine::start(config.clone())?;
+        for i in 0..100 {
+            ngin.set(format!("key-{:03}", i), format!("value-{:03}", i))?;
+        }
+        assert!(ngin.verify_all()?.is_empty());
+    }
+
+    // silent corruption of the disk flips a bit of a flushed value
+    let needle = b"value-042";
+    let table = std::fs::read_dir(storage_dir.path().join("sstables"))?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| {
+            let content = std::fs::read(path).unwrap();
+            content.windows(needle.len()).any(|window| window == needle)
+        })
+        .expect("the value has been flushed");
+    let mut content = std::fs::read(&table)?;
+    let offset = content
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .unwrap();
+    content[offset] ^= 0x20;
+    std::fs::write(&table, content)?;
+
+    let ngin = engine::Engine::start(config)?;
+    let corrupted = ngin.verify_all()?;
+    assert_eq!(1, corrupted.len(), "{:?}", corrupted);
+    assert_eq!(table, corrupted[0].path);
+    assert!(corrupted[0].offset <= offset as u64);
+    assert!((offset as u64) < corrupted[0].offset + corrupted[0].size);
+
+    // reads of the corrupted block fail rather than returning the wrong value
+    assert!(ngin.get(&Key::from("key-042")).is_err());
+    assert_eq!(
+        ngin.get(&Key::from("key-099"))?,
+        Some(Value::from("value-099"))
+    );
+
+    Ok(())
+}
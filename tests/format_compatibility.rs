@@ -0,0 +1,189 @@
+// The files in `tests/golden` have been written by earlier versions of the crate.
+// They must stay readable for as long as their format version is supported.
+// `wal-v1.log` and `table-v1.sst` have been written by the initial version of the crate.
+// Its WALs are still read, so that their data survives an upgrade. Its tables aren't
+// supported anymore and must be rejected rather than misread.
+use r2d2::engine::storage::lsm::memtable::Entry;
+use r2d2::engine::storage::lsm::sstable;
+use r2d2::engine::storage::lsm::wal::{self, reader::WalReader, Operation};
+use r2d2::engine::storage::lsm::SequenceNumber;
+use r2d2::engine::{Key, Value};
+use std::path::{Path, PathBuf};
+use tempfile::tempdir;
+
+/// The offset of the version in the header of a WAL: frame length, stanza length and stanza
+const WAL_VERSION_OFFSET: usize = 4 + 8 + 9;
+/// The offset of the version from the end of an SSTable, where it's followed by the stanza
+const SSTABLE_VERSION_OFFSET_FROM_END: usize = 1 + 13;
+
+fn golden(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("golden")
+        .join(name)
+}
+
+fn expected_wal_records() -> Vec<wal::Record<Key, Value>> {
+    vec![
+        (
+            1,
+            vec![Operation::Set(Key::from("foo"), Value::from("bar"))],
+        ),
+        (
+            2,
+            vec![
+                Operation::Set(Key::from("baz"), Value::from("qux")),
+                Operation::Delete(Key::from("foo")),
+            ],
+        ),
+    ]
+}
+
+fn read_wal(path: &Path) -> Result<Vec<wal::Record<Key, Value>>, wal::Error> {
+    WalReader::open(path)?.collect()
+}
+
+/// Copy the golden file into the `dir`, with the byte at `offset` replaced
+fn patched(name: &str, dir: &Path, offset: usize, byte: u8) -> PathBuf {
+    let mut content = std::fs::read(golden(name)).unwrap();
+    content[offset] = byte;
+    let path = dir.join(name);
+    std::fs::write(&path, content).unwrap();
+    path
+}
+
+fn open_table(path: &Path) -> Result<sstable::SSTable, sstable::Error> {
+    sstable::Slab::new(0, path, Key::from("bar"), Key::from("foo"), 0).sstable()
+}
+
+#[test]
+fn check_wal_v4_is_readable() {
+    assert_eq!(
+        expected_wal_records(),
+        read_wal(&golden("wal-v4.log")).unwrap()
+    );
+}
+
+#[test]
+fn check_wals_of_the_initial_version_are_readable() {
+    // the records of the initial version hold single operations without sequence numbers
+    let expected = vec![
+        (
+            1,
+            vec![Operation::Set(Key::from("foo"), Value::from("bar"))],
+        ),
+        (
+            2,
+            vec![Operation::Set(Key::from("baz"), Value::from("qux"))],
+        ),
+        (3, vec![Operation::Delete(Key::from("foo"))]),
+    ];
+    assert_eq!(expected, read_wal(&golden("wal-v1.log")).unwrap());
+}
+
+#[test]
+fn check_wals_of_older_versions_are_not_continued() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("wal.log");
+    std::fs::copy(golden("wal-v1.log"), &path).unwrap();
+
+    assert!(matches!(
+        wal::writer::WalWriter::resume(&path, wal::SyncPolicy::Always),
+        Err(wal::Error::UnsupportedVersion(1))
+    ));
+}
+
+#[test]
+fn check_wals_of_unknown_versions_are_rejected() {
+    let dir = tempdir().unwrap();
+    let path = patched("wal-v4.log", dir.path(), WAL_VERSION_OFFSET, 42);
+
+    assert!(matches!(
+        WalReader::open(&path),
+        Err(wal::Error::UnsupportedVersion(42))
+    ));
+}
+
+#[test]
+fn check_other_files_are_not_read_as_wals() {
+    let dir = tempdir().unwrap();
+    let garbage = dir.path().join("garbage.log");
+    std::fs::write(&garbage, b"this is not a WAL").unwrap();
+
+    for path in [golden("table-v8.sst"), golden("table-v1.sst"), garbage] {
+        assert!(matches!(
+            WalReader::open(&path),
+            Err(wal::Error::NotAWalFile(_))
+        ));
+    }
+
+    // the stanza identifies a WAL, not just the layout of the header
+    let path = patched("wal-v4.log", dir.path(), WAL_VERSION_OFFSET - 1, b'x');
+    assert!(matches!(
+        WalReader::open(&path),
+        Err(wal::Error::NotAWalFile(_))
+    ));
+}
+
+/// The golden tables hold a value for `bar` and a deleted value for `foo`
+fn check_golden_table(table: &sstable::SSTable) {
+    assert_eq!(
+        Some(Entry::Val(Value::from("baz"))),
+        table.get(&Key::from("bar"), SequenceNumber::MAX).unwrap()
+    );
+    assert_eq!(
+        Some(Entry::Tombstone),
+        table.get(&Key::from("foo"), SequenceNumber::MAX).unwrap()
+    );
+    assert_eq!(
+        Some(Entry::Val(Value::from("bar"))),
+        table.get(&Key::from("foo"), 1).unwrap()
+    );
+}
+
+#[test]
+fn check_sstable_v8_is_readable() {
+    let table = open_table(&golden("table-v8.sst")).unwrap();
+    check_golden_table(&table);
+    assert!(table.verify().unwrap().is_empty());
+}
+
+#[test]
+fn check_sstables_of_the_initial_version_are_rejected() {
+    assert!(matches!(
+        open_table(&golden("table-v1.sst")),
+        Err(sstable::Error::UnsupportedVersion(1))
+    ));
+}
+
+#[test]
+fn check_sstables_of_unknown_versions_are_rejected() {
+    let dir = tempdir().unwrap();
+    let length = std::fs::metadata(golden("table-v8.sst")).unwrap().len() as usize;
+    let path = patched(
+        "table-v8.sst",
+        dir.path(),
+        length - SSTABLE_VERSION_OFFSET_FROM_END,
+        42,
+    );
+    assert!(matches!(
+        open_table(&path),
+        Err(sstable::Error::UnsupportedVersion(42))
+    ));
+}
+
+#[test]
+fn check_other_files_are_not_read_as_sstables() {
+    let dir = tempdir().unwrap();
+    let tiny = dir.path().join("tiny.sst");
+    std::fs::write(&tiny, b"r2").unwrap();
+    let garbage = dir.path().join("garbage.sst");
+    std::fs::write(&garbage, b"this is not an SSTable").unwrap();
+
+    for path in [golden("wal-v4.log"), tiny, garbage] {
+        assert!(matches!(
+            open_table(&path),
+            Err(sstable::Error::NotAnSSTable(_))
+        ));
+    }
+}
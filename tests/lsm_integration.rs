@@ -1,3 +1,5 @@
+use r2d2::engine::storage::lsm::batch::WriteBatch;
+use r2d2::engine::storage::lsm::configuration::{CompactionStrategy, RecoveryMode};
 use r2d2::engine::storage::{self, lsm};
 use r2d2::engine::{Key, Value};
 use tempfile::tempdir;
@@ -7,7 +9,7 @@ fn check_lsm_works() -> anyhow::Result<()> {
     let mut config_builder = storage::lsm::configuration::Builder::default();
     config_builder.with_storage_path(tempdir()?.path().to_path_buf())?;
     let config = config_builder.build()?;
-    let mut lsm = lsm::LSM::new(config)?;
+    let lsm = lsm::LSM::new(config)?;
 
     let foo = Key::from("foo");
     let bar = Value::from("bar");
@@ -30,7 +32,7 @@ fn check_recovery_from_commit_log() -> anyhow::Result<()> {
     let baz = Value::from("baz");
 
     {
-        let mut lsm = lsm::LSM::new(config.clone())?;
+        let lsm = lsm::LSM::new(config.clone())?;
 
         assert!(lsm.set(foo.clone(), baz.clone()).is_ok());
         assert!(lsm.set(bar.clone(), baz.clone()).is_ok());
@@ -44,3 +46,718 @@ fn check_recovery_from_commit_log() -> anyhow::Result<()> {
     assert_eq!(Some(baz.clone()), lsm.get(&bar)?);
     Ok(())
 }
+
+#[test]
+fn check_memtable_is_flushed_to_sstable() -> anyhow::Result<()> {
+    let storage_dir = tempdir()?;
+    let mut config_builder = storage::lsm::configuration::Builder::default();
+    config_builder.with_storage_path(storage_dir.path().to_path_buf())?;
+    config_builder.with_memtable_size(64)?;
+    let config = config_builder.build()?;
+    let lsm = lsm::LSM::new(config)?;
+
+    for i in 0..100 {
+        let key = Key::from(format!("key-{:03}", i));
+        lsm.set(key, Value::from(format!("value-{:03}", i)))?;
+    }
+    lsm.flush()?;
+
+    let sstables = std::fs::read_dir(storage_dir.path().join("sstables"))?.count();
+    assert!(sstables > 1, "expected multiple sstables, got {}", sstables);
+
+    for i in 0..100 {
+        let key = Key::from(format!("key-{:03}", i));
+        assert_eq!(Some(Value::from(format!("value-{:03}", i))), lsm.get(&key)?);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn check_wal_is_truncated_after_flush() -> anyhow::Result<()> {
+    let storage_dir = tempdir()?;
+    let mut config_builder = storage::lsm::configuration::Builder::default();
+    config_builder.with_storage_path(storage_dir.path().to_path_buf())?;
+    config_builder.with_memtable_size(1024)?;
+    let config = config_builder.build()?;
+
+    {
+        let lsm = lsm::LSM::new(config.clone())?;
+        for i in 0..1000 {
+            let key = Key::from(format!("key-{:04}", i));
+            lsm.set(key, Value::from(format!("value-{:04}", i)))?;
+        }
+
+        let wal_file = newest_wal_segment(storage_dir.path())?;
+        let wal_size = std::fs::metadata(&wal_file)?.len();
+        assert!(
+            wal_size < 2048,
+            "expected bounded wal, got {} bytes",
+            wal_size
+        );
+    }
+
+    // data that has been flushed is found through the manifest after a restart
+    let lsm = lsm::LSM::new(config)?;
+    for i in 0..1000 {
+        let key = Key::from(format!("key-{:04}", i));
+        assert_eq!(Some(Value::from(format!("value-{:04}", i))), lsm.get(&key)?);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn check_deletes_shadow_flushed_values() -> anyhow::Result<()> {
+    let mut config_builder = storage::lsm::configuration::Builder::default();
+    config_builder.with_storage_path(tempdir()?.path().to_path_buf())?;
+    config_builder.with_memtable_size(256)?;
+    let config = config_builder.build()?;
+    let lsm = lsm::LSM::new(config.clone())?;
+
+    let foo = Key::from("foo");
+    let bar = Value::from("bar");
+    lsm.set(foo.clone(), bar.clone())?;
+
+    // push the key into an sstable
+    for i in 0..100 {
+        lsm.set(Key::from(format!("key-{:03}", i)), bar.clone())?;
+    }
+
+    assert_eq!(Some(bar.clone()), lsm.del(&foo)?);
+    assert_eq!(None, lsm.get(&foo)?);
+
+    // push the tombstone into an sstable as well
+    for i in 100..200 {
+        lsm.set(Key::from(format!("key-{:03}", i)), bar.clone())?;
+    }
+    assert_eq!(None, lsm.get(&foo)?);
+
+    drop(lsm);
+    let lsm = lsm::LSM::new(config)?;
+    assert_eq!(None, lsm.get(&foo)?);
+
+    Ok(())
+}
+
+#[test]
+fn check_sets_return_flushed_values() -> anyhow::Result<()> {
+    let mut config_builder = storage::lsm::configuration::Builder::default();
+    config_builder.with_storage_path(tempdir()?.path().to_path_buf())?;
+    config_builder.with_memtable_size(256)?;
+    let config = config_builder.build()?;
+    let lsm = lsm::LSM::new(config)?;
+
+    let foo = Key::from("foo");
+    let bar = Value::from("bar");
+    assert_eq!(None, lsm.set(foo.clone(), bar.clone())?);
+
+    // push the key into an sstable
+    for i in 0..100 {
+        lsm.set(Key::from(format!("key-{:03}", i)), bar.clone())?;
+    }
+    lsm.flush()?;
+
+    let baz = Value::from("baz");
+    assert_eq!(Some(bar), lsm.set(foo.clone(), baz.clone())?);
+    assert_eq!(Some(baz), lsm.get(&foo)?);
+
+    Ok(())
+}
+
+#[test]
+fn check_tables_are_compacted_into_deeper_levels() -> anyhow::Result<()> {
+    let mut config_builder = storage::lsm::configuration::Builder::default();
+    config_builder.with_storage_path(tempdir()?.path().to_path_buf())?;
+    config_builder.with_memtable_size(256)?;
+    config_builder.with_level0_compaction_trigger(2)?;
+    config_builder.with_level_size_base(1024)?;
+    config_builder.with_level_size_multiplier(2)?;
+    config_builder.with_target_table_size(512)?;
+    let config = config_builder.build()?;
+
+    {
+        let lsm = lsm::LSM::new(config.clone())?;
+        for round in 0..3 {
+            for i in (0..500).map(|i| (i * 7919) % 500) {
+                let key = Key::from(format!("key-{:03}", i));
+                lsm.set(key, Value::from(format!("value-{:03}-{}", i, round)))?;
+            }
+        }
+        for i in (0..500).filter(|i| i % 3 == 0) {
+            lsm.del(&Key::from(format!("key-{:03}", i)))?;
+        }
+    }
+
+    // dropping the lsm waits for the compaction to finish
+    let lsm = lsm::LSM::new(config)?;
+    let tables_per_level = lsm.tables_per_level()?;
+    assert!(
+        tables_per_level.iter().skip(2).sum::<usize>() > 0,
+        "expected tables on deeper levels, got {:?}",
+        tables_per_level
+    );
+
+    for i in 0..500 {
+        let key = Key::from(format!("key-{:03}", i));
+        let expected = if i % 3 == 0 {
+            None
+        } else {
+            Some(Value::from(format!("value-{:03}-2", i)))
+        };
+        assert_eq!(expected, lsm.get(&key)?);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn check_size_tiered_compaction_merges_tables_on_level0() -> anyhow::Result<()> {
+    let storage_dir = tempdir()?;
+    let mut config_builder = storage::lsm::configuration::Builder::default();
+    config_builder.with_storage_path(storage_dir.path().to_path_buf())?;
+    config_builder.with_memtable_size(256)?;
+    config_builder.with_compaction_strategy(CompactionStrategy::SizeTiered)?;
+    config_builder.with_size_tiered_min_threshold(2)?;
+    let config = config_builder.build()?;
+
+    {
+        // this results in about 70 flushes
+        let lsm = lsm::LSM::new(config.clone())?;
+        for i in 0..1000 {
+            let key = Key::from(format!("key-{:03}", i % 300));
+            lsm.set(key, Value::from(format!("value-{:04}", i)))?;
+        }
+    }
+
+    let lsm = lsm::LSM::new(config)?;
+    let tables_per_level = lsm.tables_per_level()?;
+    assert_eq!(1, tables_per_level.len(), "expected tables on level 0 only");
+    assert!(
+        tables_per_level[0] < 20,
+        "expected tables to be merged, got {:?}",
+        tables_per_level
+    );
+
+    for i in 700..1000 {
+        let key = Key::from(format!("key-{:03}", i % 300));
+        assert_eq!(Some(Value::from(format!("value-{:04}", i))), lsm.get(&key)?);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn check_repeated_lookups_hit_the_caches() -> anyhow::Result<()> {
+    let mut config_builder = storage::lsm::configuration::Builder::default();
+    config_builder.with_storage_path(tempdir()?.path().to_path_buf())?;
+    config_builder.with_memtable_size(256)?;
+    let config = config_builder.build()?;
+    let lsm = lsm::LSM::new(config)?;
+
+    for i in 0..50 {
+        let key = Key::from(format!("key-{:03}", i));
+        lsm.set(key, Value::from(format!("value-{:03}", i)))?;
+    }
+    lsm.flush()?;
+
+    let key = Key::from("key-000");
+    assert_eq!(Some(Value::from("value-000")), lsm.get(&key)?);
+    let first = lsm.cache_stats()?;
+    assert!(first.table_cache.misses > 0);
+    assert!(first.block_cache.misses > 0);
+
+    assert_eq!(Some(Value::from("value-000")), lsm.get(&key)?);
+    let second = lsm.cache_stats()?;
+    assert!(second.table_cache.hits > first.table_cache.hits);
+    assert!(second.block_cache.hits > first.block_cache.hits);
+    assert_eq!(second.block_cache.misses, first.block_cache.misses);
+
+    Ok(())
+}
+
+#[test]
+fn check_snapshots_survive_flushes_and_compaction() -> anyhow::Result<()> {
+    let mut config_builder = storage::lsm::configuration::Builder::default();
+    config_builder.with_storage_path(tempdir()?.path().to_path_buf())?;
+    config_builder.with_memtable_size(256)?;
+    config_builder.with_level0_compaction_trigger(2)?;
+    config_builder.with_level_size_base(1024)?;
+    config_builder.with_target_table_size(512)?;
+    let config = config_builder.build()?;
+    let lsm = lsm::LSM::new(config)?;
+
+    let key = |i: usize| Key::from(format!("key-{:03}", i));
+    for i in 0..200 {
+        lsm.set(key(i), Value::from(format!("value-{:03}-0", i)))?;
+    }
+    let snapshot = lsm.snapshot()?;
+
+    // enough writes to flush and compact the data of the snapshot several times
+    for round in 1..4 {
+        for i in 0..200 {
+            lsm.set(key(i), Value::from(format!("value-{:03}-{}", i, round)))?;
+        }
+    }
+    for i in (0..200).filter(|i| i % 2 == 0) {
+        lsm.del(&key(i))?;
+    }
+
+    for i in 0..200 {
+        let expected = Value::from(format!("value-{:03}-0", i));
+        assert_eq!(Some(expected), lsm.get_at(&key(i), &snapshot)?);
+
+        let current = if i % 2 == 0 {
+            None
+        } else {
+            Some(Value::from(format!("value-{:03}-3", i)))
+        };
+        assert_eq!(current, lsm.get(&key(i))?);
+    }
+
+    let records = lsm.iter_at(&snapshot)?.collect::<Result<Vec<_>, _>>()?;
+    let expected: Vec<(Key, Value)> = (0..200)
+        .map(|i| (key(i), Value::from(format!("value-{:03}-0", i))))
+        .collect();
+    assert_eq!(expected, records);
+    assert_eq!(100, lsm.iter()?.count());
+
+    Ok(())
+}
+
+#[test]
+fn check_sequence_numbers_continue_after_restart() -> anyhow::Result<()> {
+    let mut config_builder = storage::lsm::configuration::Builder::default();
+    config_builder.with_storage_path(tempdir()?.path().to_path_buf())?;
+    config_builder.with_memtable_size(64)?;
+    let config = config_builder.build()?;
+
+    let foo = Key::from("foo");
+    {
+        let lsm = lsm::LSM::new(config.clone())?;
+        for i in 0..20 {
+            lsm.set(foo.clone(), Value::from(format!("value-{}", i)))?;
+        }
+    }
+
+    let lsm = lsm::LSM::new(config.clone())?;
+    let snapshot = lsm.snapshot()?;
+    assert!(snapshot.sequence() >= 20);
+    lsm.set(foo.clone(), Value::from("latest"))?;
+    assert_eq!(Some(Value::from("latest")), lsm.get(&foo)?);
+    assert_eq!(Some(Value::from("value-19")), lsm.get_at(&foo, &snapshot)?);
+
+    Ok(())
+}
+
+#[test]
+fn check_write_batches_are_recovered_all_or_nothing() -> anyhow::Result<()> {
+    let storage_dir = tempdir()?;
+    let mut config_builder = storage::lsm::configuration::Builder::default();
+    config_builder.with_storage_path(storage_dir.path().to_path_buf())?;
+    let config = config_builder.build()?;
+
+    let (a, b, c) = (Key::from("a"), Key::from("b"), Key::from("c"));
+    {
+        let lsm = lsm::LSM::new(config.clone())?;
+        lsm.set(a.clone(), Value::from("a-0"))?;
+
+        let mut batch = WriteBatch::new();
+        batch.set("b", "b-1").set("c", "c-1").del("a");
+        lsm.write(batch)?;
+        assert_eq!(None, lsm.get(&a)?);
+        assert_eq!(Some(Value::from("b-1")), lsm.get(&b)?);
+
+        let mut batch = WriteBatch::new();
+        batch.set("a", "a-2").set("b", "b-2").del("c");
+        lsm.write(batch)?;
+    }
+
+    // a crash in the middle of writing the last batch leaves a torn record behind
+    let wal_file = newest_wal_segment(storage_dir.path())?;
+    let wal_size = std::fs::metadata(&wal_file)?.len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&wal_file)?
+        .set_len(wal_size - 5)?;
+
+    let lsm = lsm::LSM::new(config)?;
+    assert_eq!(None, lsm.get(&a)?);
+    assert_eq!(Some(Value::from("b-1")), lsm.get(&b)?);
+    assert_eq!(Some(Value::from("c-1")), lsm.get(&c)?);
+
+    Ok(())
+}
+
+#[test]
+fn check_recovery_fails_on_corrupted_records() -> anyhow::Result<()> {
+    let storage_dir = tempdir()?;
+    let mut config_builder = storage::lsm::configuration::Builder::default();
+    config_builder.with_storage_path(storage_dir.path().to_path_buf())?;
+    let config = config_builder.build()?;
+
+    let (wal_file, first_record) = {
+        let lsm = lsm::LSM::new(config.clone())?;
+        let wal_file = newest_wal_segment(storage_dir.path())?;
+        let first_record = std::fs::metadata(&wal_file)?.len() as usize;
+        lsm.set(Key::from("a"), Value::from("a-1"))?;
+        lsm.set(Key::from("b"), Value::from("b-1"))?;
+        (wal_file, first_record)
+    };
+
+    // a bit flip in the first record, which is followed by another one
+    let mut content = std::fs::read(&wal_file)?;
+    content[first_record + 14] ^= 0x01;
+    std::fs::write(&wal_file, &content)?;
+
+    assert!(matches!(
+        lsm::LSM::new(config),
+        Err(lsm::Error::WalError(lsm::wal::Error::CorruptedRecord(_)))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn check_full_memtables_are_read_until_they_are_flushed() -> anyhow::Result<()> {
+    let storage_dir = tempdir()?;
+    let mut config_builder = storage::lsm::configuration::Builder::default();
+    config_builder.with_storage_path(storage_dir.path().to_path_buf())?;
+    config_builder.with_memtable_size(256)?;
+    config_builder.with_max_immutable_memtables(1)?;
+    let config = config_builder.build()?;
+    let lsm = lsm::LSM::new(config)?;
+
+    // every key is found right away, no matter if it's in the active memtable,
+    // in a memtable that waits to be flushed or already in an SSTable
+    for i in 0..500 {
+        let key = Key::from(format!("key-{:03}", i));
+        lsm.set(key.clone(), Value::from(format!("value-{:03}", i)))?;
+        assert_eq!(Some(Value::from(format!("value-{:03}", i))), lsm.get(&key)?);
+        if i % 10 == 0 {
+            lsm.del(&key)?;
+            assert_eq!(None, lsm.get(&key)?);
+        }
+    }
+
+    lsm.flush()?;
+    let segments = std::fs::read_dir(storage_dir.path().join("wal"))?.count();
+    assert_eq!(
+        1, segments,
+        "the WAL segments of flushed memtables are removed"
+    );
+
+    let keys = lsm.iter()?.collect::<Result<Vec<_>, _>>()?.len();
+    assert_eq!(450, keys);
+
+    Ok(())
+}
+
+#[test]
+fn check_recovery_replays_all_wal_segments() -> anyhow::Result<()> {
+    let storage_dir = tempdir()?;
+    let mut config_builder = storage::lsm::configuration::Builder::default();
+    config_builder.with_storage_path(storage_dir.path().to_path_buf())?;
+    let config = config_builder.build()?;
+    let (foo, bar) = (Key::from("foo"), Key::from("bar"));
+
+    // a crash before the flush of a memtable leaves its WAL segments behind
+    {
+        let wal = lsm::wal::WalManager::init(storage_dir.path(), lsm::wal::Options::default())?;
+        let mut writer = wal.create()?;
+        writer.write(1, &[lsm::wal::Operation::Set(&foo, &Value::from("foo-1"))])?;
+        writer.write(2, &[lsm::wal::Operation::Set(&bar, &Value::from("bar-2"))])?;
+        let mut writer = wal.create()?;
+        writer.write(3, &[lsm::wal::Operation::Set(&foo, &Value::from("foo-3"))])?;
+    }
+
+    {
+        let lsm = lsm::LSM::new(config.clone())?;
+        assert_eq!(Some(Value::from("foo-3")), lsm.get(&foo)?);
+        assert_eq!(Some(Value::from("bar-2")), lsm.get(&bar)?);
+        lsm.set(bar.clone(), Value::from("bar-4"))?;
+    }
+
+    // the replayed segments are removed, only the one that has been written to since is left
+    let wal = lsm::wal::WalManager::init(storage_dir.path(), lsm::wal::Options::default())?;
+    assert_eq!(1, wal.segments()?.len());
+
+    let lsm = lsm::LSM::new(config)?;
+    assert_eq!(Some(Value::from("foo-3")), lsm.get(&foo)?);
+    assert_eq!(Some(Value::from("bar-4")), lsm.get(&bar)?);
+
+    Ok(())
+}
+
+#[test]
+fn check_recovery_replays_the_wal_of_the_initial_version() -> anyhow::Result<()> {
+    let storage_dir = tempdir()?;
+    let mut config_builder = storage::lsm::configuration::Builder::default();
+    config_builder.with_storage_path(storage_dir.path().to_path_buf())?;
+    let config = config_builder.build()?;
+    let (foo, baz) = (Key::from("foo"), Key::from("baz"));
+
+    // the initial version wrote all operations to a single WAL file
+    let initial_wal = storage_dir.path().join("wal").join("wal.log");
+    std::fs::create_dir_all(storage_dir.path().join("wal"))?;
+    std::fs::copy(
+        std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden/wal-v1.log"),
+        &initial_wal,
+    )?;
+
+    {
+        let lsm = lsm::LSM::new(config.clone())?;
+        assert_eq!(3, lsm.recovery_report().replayed);
+        assert_eq!(None, lsm.get(&foo)?);
+        assert_eq!(Some(Value::from("qux")), lsm.get(&baz)?);
+        lsm.set(foo.clone(), Value::from("bar-4"))?;
+    }
+
+    // the file is removed once its operations are in SSTables
+    assert!(!initial_wal.exists());
+
+    let lsm = lsm::LSM::new(config)?;
+    assert_eq!(Some(Value::from("bar-4")), lsm.get(&foo)?);
+    assert_eq!(Some(Value::from("qux")), lsm.get(&baz)?);
+
+    Ok(())
+}
+
+#[test]
+fn check_wal_segments_rotate_by_size() -> anyhow::Result<()> {
+    let storage_dir = tempdir()?;
+    let mut config_builder = storage::lsm::configuration::Builder::default();
+    config_builder.with_storage_path(storage_dir.path().to_path_buf())?;
+    config_builder.with_wal_segment_size(512)?;
+    let config = config_builder.build()?;
+    let wal_segments = || -> anyhow::Result<usize> {
+        Ok(std::fs::read_dir(storage_dir.path().join("wal"))?.count())
+    };
+
+    // the memtable never fills up, so all segments are needed for the recovery
+    {
+        let lsm = lsm::LSM::new(config.clone())?;
+        for i in 0..100 {
+            let key = Key::from(format!("key-{:03}", i));
+            lsm.set(key, Value::from(format!("value-{:03}", i)))?;
+        }
+    }
+    assert!(wal_segments()? > 1, "expected multiple WAL segments");
+
+    let lsm = lsm::LSM::new(config)?;
+    assert_eq!(100, lsm.recovery_report().replayed);
+    assert_eq!(1, wal_segments()?, "the recovered segments are removed");
+    for i in 0..100 {
+        let key = Key::from(format!("key-{:03}", i));
+        assert_eq!(Some(Value::from(format!("value-{:03}", i))), lsm.get(&key)?);
+    }
+
+    for i in 100..200 {
+        let key = Key::from(format!("key-{:03}", i));
+        lsm.set(key, Value::from(format!("value-{:03}", i)))?;
+    }
+    assert!(wal_segments()? > 1, "expected multiple WAL segments");
+    lsm.flush()?;
+    assert_eq!(
+        1,
+        wal_segments()?,
+        "the segments of flushed memtables are removed"
+    );
+
+    Ok(())
+}
+
+/// Write the keys `a`, `b` and `c` as records of their own and leave the WAL behind
+/// as after a crash, damaged by `damage` which gets the offsets of the records
+fn crash_with_damaged_wal(
+    dir: &std::path::Path,
+    damage: impl FnOnce(&mut Vec<u8>, &[usize]),
+) -> anyhow::Result<()> {
+    let mut config_builder = storage::lsm::configuration::Builder::default();
+    config_builder.with_storage_path(dir.to_path_buf())?;
+
+    let mut offsets = Vec::new();
+    let wal_file = {
+        let lsm = lsm::LSM::new(config_builder.build()?)?;
+        let wal_file = newest_wal_segment(dir)?;
+        for key in ["a", "b", "c"] {
+            offsets.push(std::fs::metadata(&wal_file)?.len() as usize);
+            lsm.set(Key::from(key), Value::from(key))?;
+        }
+        wal_file
+    };
+
+    let mut content = std::fs::read(&wal_file)?;
+    damage(&mut content, &offsets);
+    std::fs::write(&wal_file, &content)?;
+    Ok(())
+}
+
+/// The path of the WAL segment that receives the writes
+fn newest_wal_segment(dir: &std::path::Path) -> anyhow::Result<std::path::PathBuf> {
+    let wal = lsm::wal::WalManager::init(dir, lsm::wal::Options::default())?;
+    let segment = wal.segments()?.pop().expect("the LSM has a WAL segment");
+    Ok(segment.path().to_path_buf())
+}
+
+fn recover_with(dir: &std::path::Path, mode: RecoveryMode) -> Result<lsm::LSM, lsm::Error> {
+    let mut config_builder = storage::lsm::configuration::Builder::default();
+    config_builder.with_storage_path(dir.to_path_buf()).unwrap();
+    config_builder.with_recovery_mode(mode).unwrap();
+    lsm::LSM::new(config_builder.build().unwrap())
+}
+
+fn keys(lsm: &lsm::LSM) -> anyhow::Result<Vec<Key>> {
+    Ok(lsm
+        .iter()?
+        .map(|record| record.map(|(key, _)| key))
+        .collect::<Result<_, _>>()?)
+}
+
+#[test]
+fn check_recovery_modes_handle_torn_tails() -> anyhow::Result<()> {
+    let torn = |content: &mut Vec<u8>, _: &[usize]| content.truncate(content.len() - 3);
+
+    let storage_dir = tempdir()?;
+    crash_with_damaged_wal(storage_dir.path(), torn)?;
+    assert!(matches!(
+        recover_with(storage_dir.path(), RecoveryMode::AbsoluteConsistency),
+        Err(lsm::Error::WalError(lsm::wal::Error::TornRecord(_)))
+    ));
+
+    for mode in [
+        RecoveryMode::TolerateCorruptedTail,
+        RecoveryMode::SkipCorruptedRecords,
+    ] {
+        let storage_dir = tempdir()?;
+        crash_with_damaged_wal(storage_dir.path(), torn)?;
+        let lsm = recover_with(storage_dir.path(), mode)?;
+
+        assert_eq!(vec![Key::from("a"), Key::from("b")], keys(&lsm)?);
+        let report = lsm.recovery_report();
+        assert_eq!(2, report.replayed);
+        assert!(report.skipped.is_empty());
+        assert_eq!(1, report.truncated.len());
+        assert!(!report.is_complete());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn check_recovery_modes_handle_corrupted_records() -> anyhow::Result<()> {
+    // flip a bit in the data of the record of `b`
+    let corrupted = |content: &mut Vec<u8>, offsets: &[usize]| content[offsets[1] + 14] ^= 0x01;
+
+    for mode in [
+        RecoveryMode::AbsoluteConsistency,
+        RecoveryMode::TolerateCorruptedTail,
+    ] {
+        let storage_dir = tempdir()?;
+        crash_with_damaged_wal(storage_dir.path(), corrupted)?;
+        assert!(matches!(
+            recover_with(storage_dir.path(), mode),
+            Err(lsm::Error::WalError(lsm::wal::Error::CorruptedRecord(_)))
+        ));
+    }
+
+    let storage_dir = tempdir()?;
+    let mut offsets = Vec::new();
+    crash_with_damaged_wal(storage_dir.path(), |content, records| {
+        offsets = records.to_vec();
+        corrupted(content, records)
+    })?;
+    let lsm = recover_with(storage_dir.path(), RecoveryMode::SkipCorruptedRecords)?;
+
+    assert_eq!(vec![Key::from("a"), Key::from("c")], keys(&lsm)?);
+    let report = lsm.recovery_report();
+    assert_eq!(2, report.replayed);
+    assert_eq!(
+        vec![offsets[1] as u64],
+        report
+            .skipped
+            .iter()
+            .map(|(_, offset)| *offset)
+            .collect::<Vec<_>>()
+    );
+    assert!(report.truncated.is_empty());
+
+    // the damaged WAL is gone after the recovery
+    drop(lsm);
+    let lsm = recover_with(storage_dir.path(), RecoveryMode::AbsoluteConsistency)?;
+    assert_eq!(vec![Key::from("a"), Key::from("c")], keys(&lsm)?);
+    assert!(lsm.recovery_report().is_complete());
+
+    Ok(())
+}
+
+#[test]
+fn check_recovery_resyncs_after_a_corrupted_header() -> anyhow::Result<()> {
+    // flip a bit in the length of the record of `b`, so that its end is unknown
+    let corrupted = |content: &mut Vec<u8>, offsets: &[usize]| content[offsets[1]] ^= 0x01;
+
+    let storage_dir = tempdir()?;
+    crash_with_damaged_wal(storage_dir.path(), corrupted)?;
+    assert!(matches!(
+        recover_with(storage_dir.path(), RecoveryMode::TolerateCorruptedTail),
+        Err(lsm::Error::WalError(lsm::wal::Error::CorruptedRecord(_)))
+    ));
+
+    let storage_dir = tempdir()?;
+    let mut offsets = Vec::new();
+    crash_with_damaged_wal(storage_dir.path(), |content, records| {
+        offsets = records.to_vec();
+        corrupted(content, records)
+    })?;
+    let lsm = recover_with(storage_dir.path(), RecoveryMode::SkipCorruptedRecords)?;
+
+    // the record of `c` is found behind the corrupted one and the WAL isn't cut off
+    assert_eq!(vec![Key::from("a"), Key::from("c")], keys(&lsm)?);
+    let report = lsm.recovery_report();
+    assert_eq!(2, report.replayed);
+    assert_eq!(
+        vec![offsets[1] as u64],
+        report
+            .skipped
+            .iter()
+            .map(|(_, offset)| *offset)
+            .collect::<Vec<_>>()
+    );
+    assert!(report.truncated.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn failed_flush_rejects_further_writes() -> anyhow::Result<()> {
+    let storage_dir = tempdir()?;
+    let mut config_builder = storage::lsm::configuration::Builder::default();
+    config_builder.with_storage_path(storage_dir.path().to_path_buf())?;
+    let config = config_builder.build()?;
+
+    {
+        let lsm = lsm::LSM::new(config.clone())?;
+        lsm.set(Key::from("foo"), Value::from("bar"))?;
+
+        // the table of the flush can't be created anymore
+        std::fs::remove_dir_all(storage_dir.path().join("sstables"))?;
+        assert!(matches!(lsm.flush(), Err(lsm::Error::FlushError)));
+
+        // writes are rejected before they reach the WAL, reads continue to work
+        assert!(matches!(
+            lsm.set(Key::from("baz"), Value::from("qux")),
+            Err(lsm::Error::FlushError)
+        ));
+        assert!(matches!(lsm.flush(), Err(lsm::Error::FlushError)));
+        assert_eq!(lsm.get(&Key::from("foo"))?, Some(Value::from("bar")));
+        assert_eq!(lsm.get(&Key::from("baz"))?, None);
+    }
+
+    // the writes that have been accepted are still in the WAL
+    std::fs::create_dir(storage_dir.path().join("sstables"))?;
+    let lsm = lsm::LSM::new(config)?;
+    assert_eq!(lsm.get(&Key::from("foo"))?, Some(Value::from("bar")));
+    assert_eq!(lsm.get(&Key::from("baz"))?, None);
+
+    Ok(())
+}
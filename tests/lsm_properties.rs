@@ -0,0 +1,175 @@
+use quickcheck::{Arbitrary, Gen};
+use quickcheck_macros::quickcheck;
+use r2d2::engine::storage::lsm::batch::WriteBatch;
+use r2d2::engine::storage::lsm::configuration::CompactionStrategy;
+use r2d2::engine::storage::{self, lsm};
+use r2d2::engine::{Key, Value};
+use std::collections::BTreeMap;
+use tempfile::tempdir;
+
+const KEY_SPACE: u8 = 32;
+
+#[derive(Clone, Debug)]
+enum Op {
+    Set(u8, u8),
+    Delete(u8),
+    /// A batch of sets (with a value) and deletes (without one)
+    Batch(Vec<(u8, Option<u8>)>),
+    Snapshot,
+    Restart,
+}
+
+impl Arbitrary for Op {
+    fn arbitrary(g: &mut Gen) -> Self {
+        let key = u8::arbitrary(g) % KEY_SPACE;
+        match u8::arbitrary(g) % 20 {
+            0..=9 => Op::Set(key, u8::arbitrary(g)),
+            10..=15 => Op::Delete(key),
+            16..=17 => Op::Batch(
+                (0..=u8::arbitrary(g) % 4)
+                    .map(|_| (u8::arbitrary(g) % KEY_SPACE, Option::<u8>::arbitrary(g)))
+                    .collect(),
+            ),
+            18 => Op::Snapshot,
+            _ => Op::Restart,
+        }
+    }
+}
+
+fn key(k: u8) -> Key {
+    Key::from(format!("key-{:02}", k))
+}
+
+fn value(v: u8) -> Value {
+    Value::from(format!("value-{:03}", v))
+}
+
+#[quickcheck]
+fn lsm_behaves_like_a_map(ops: Vec<Op>) -> anyhow::Result<bool> {
+    behaves_like_a_map(ops, CompactionStrategy::Leveled)
+}
+
+#[quickcheck]
+fn lsm_with_size_tiered_compaction_behaves_like_a_map(ops: Vec<Op>) -> anyhow::Result<bool> {
+    behaves_like_a_map(ops, CompactionStrategy::SizeTiered)
+}
+
+fn behaves_like_a_map(ops: Vec<Op>, strategy: CompactionStrategy) -> anyhow::Result<bool> {
+    let storage_dir = tempdir()?;
+    let mut config_builder = storage::lsm::configuration::Builder::default();
+    config_builder.with_storage_path(storage_dir.path().to_path_buf())?;
+    // tiny memtables make sure that we end up with many overlapping sstables
+    config_builder.with_memtable_size(64)?;
+    // and small levels make sure that they are compacted into deeper levels
+    config_builder.with_level0_compaction_trigger(2)?;
+    config_builder.with_level_size_base(256)?;
+    config_builder.with_level_size_multiplier(2)?;
+    config_builder.with_target_table_size(128)?;
+    config_builder.with_size_tiered_min_threshold(2)?;
+    config_builder.with_compaction_strategy(strategy)?;
+    let config = config_builder.build()?;
+
+    let mut lsm = lsm::LSM::new(config.clone())?;
+    let mut model: BTreeMap<Key, Value> = BTreeMap::new();
+    // the most recent snapshot along with the state of the model at that time
+    let mut snapshot = None;
+
+    for op in ops {
+        match op {
+            Op::Set(k, v) => {
+                lsm.set(key(k), value(v))?;
+                model.insert(key(k), value(v));
+            }
+            Op::Delete(k) => {
+                let deleted = lsm.del(&key(k))?;
+                if deleted != model.remove(&key(k)) {
+                    return Ok(false);
+                }
+            }
+            Op::Batch(ops) => {
+                let mut batch = WriteBatch::new();
+                for (k, v) in ops {
+                    match v {
+                        Some(v) => {
+                            batch.set(key(k), value(v));
+                            model.insert(key(k), value(v));
+                        }
+                        None => {
+                            batch.del(key(k));
+                            model.remove(&key(k));
+                        }
+                    }
+                }
+                lsm.write(batch)?;
+            }
+            Op::Snapshot => {
+                snapshot = Some((lsm.snapshot()?, model.clone()));
+            }
+            Op::Restart => {
+                // snapshots don't outlive the lsm
+                snapshot = None;
+                drop(lsm);
+                lsm = lsm::LSM::new(config.clone())?;
+            }
+        }
+    }
+
+    for k in 0..KEY_SPACE {
+        if lsm.get(&key(k))? != model.get(&key(k)).cloned() {
+            return Ok(false);
+        }
+    }
+
+    if let Some((snapshot, frozen)) = snapshot {
+        for k in 0..KEY_SPACE {
+            if lsm.get_at(&key(k), &snapshot)? != frozen.get(&key(k)).cloned() {
+                return Ok(false);
+            }
+        }
+
+        let records = lsm
+            .iter_at(&snapshot)?
+            .collect::<Result<Vec<(Key, Value)>, _>>()?;
+        if records != frozen.into_iter().collect::<Vec<_>>() {
+            return Ok(false);
+        }
+    }
+
+    let records = lsm.iter()?.collect::<Result<Vec<(Key, Value)>, _>>()?;
+    if records != model.clone().into_iter().collect::<Vec<_>>() {
+        return Ok(false);
+    }
+
+    let range = key(8)..key(20);
+    let records = lsm
+        .range(range.clone())?
+        .collect::<Result<Vec<(Key, Value)>, _>>()?;
+    let expected: Vec<(Key, Value)> = model
+        .range(range)
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    if records != expected {
+        return Ok(false);
+    }
+
+    let mut cursor = lsm.cursor()?;
+    cursor.seek_to_last()?;
+    let mut reversed = Vec::new();
+    while let Some((k, v)) = cursor.current() {
+        reversed.push((k.clone(), v.clone()));
+        cursor.prev()?;
+    }
+    if reversed != model.clone().into_iter().rev().collect::<Vec<_>>() {
+        return Ok(false);
+    }
+    drop(cursor);
+
+    let records = lsm
+        .scan_prefix(&Key::from("key-1"))?
+        .collect::<Result<Vec<(Key, Value)>, _>>()?;
+    let expected: Vec<(Key, Value)> = model
+        .into_iter()
+        .filter(|(k, _)| k.as_slice().starts_with(b"key-1"))
+        .collect();
+    Ok(records == expected)
+}
@@ -1,4 +1,7 @@
+use r2d2::engine::storage::lsm::cursor::Cursor;
+use r2d2::engine::storage::lsm::memtable::Entry;
 use r2d2::engine::storage::lsm::sstable;
+use r2d2::engine::storage::lsm::SequenceNumber;
 use r2d2::engine::{Key, Value};
 use tempfile::tempdir;
 
@@ -9,25 +12,432 @@ fn check_write_sstable() {
         sstable::Writer::create(&test_storage_dir.path().to_path_buf().join("sstable")).unwrap();
 
     assert!(writer
-        .append(&Key::from("foo"), &Value::from("bar"))
+        .append(&Key::from("bar"), 1, &Value::from("baz"))
         .is_ok());
     assert!(writer
-        .append(&Key::from("bar"), &Value::from("baz"))
+        .append(&Key::from("baz"), 1, &Value::from("frooble"))
         .is_ok());
     assert!(writer
-        .append(&Key::from("baz"), &Value::from("frooble"))
+        .append(&Key::from("foo"), 1, &Value::from("bar"))
         .is_ok());
 
     let slab = writer.seal().unwrap();
-    let mut sstable = slab.sstable().unwrap();
+    let sstable = slab.sstable().unwrap();
 
     assert_eq!(
-        sstable.get(&Key::from("foo")).unwrap(),
-        Some(Value::from("bar")),
+        sstable.get(&Key::from("foo"), SequenceNumber::MAX).unwrap(),
+        Some(Entry::Val(Value::from("bar"))),
     );
     assert_eq!(
-        sstable.get(&Key::from("bar")).unwrap(),
-        Some(Value::from("baz"))
+        sstable.get(&Key::from("bar"), SequenceNumber::MAX).unwrap(),
+        Some(Entry::Val(Value::from("baz")))
     );
-    assert_eq!(sstable.get(&Key::from("foobar")).unwrap(), None);
+    assert_eq!(
+        sstable
+            .get(&Key::from("foobar"), SequenceNumber::MAX)
+            .unwrap(),
+        None
+    );
+}
+
+#[test]
+fn check_tombstones_are_persisted() {
+    let test_storage_dir = tempdir().unwrap();
+    let mut writer =
+        sstable::Writer::create(&test_storage_dir.path().to_path_buf().join("sstable")).unwrap();
+
+    assert!(writer
+        .append(&Key::from("bar"), 1, &Value::from("baz"))
+        .is_ok());
+    assert!(writer.append_tombstone(&Key::from("foo"), 1).is_ok());
+
+    let slab = writer.seal().unwrap();
+    let sstable = slab.sstable().unwrap();
+
+    assert_eq!(
+        sstable.get(&Key::from("bar"), SequenceNumber::MAX).unwrap(),
+        Some(Entry::Val(Value::from("baz")))
+    );
+    assert_eq!(
+        sstable.get(&Key::from("foo"), SequenceNumber::MAX).unwrap(),
+        Some(Entry::Tombstone)
+    );
+}
+
+#[test]
+fn check_bloom_filter_rules_out_missing_keys() {
+    let test_storage_dir = tempdir().unwrap();
+    let path = test_storage_dir.path().join("sstable");
+    let mut writer = sstable::Writer::create(&path).unwrap();
+
+    for i in 0..100 {
+        let key = Key::from(format!("key-{:03}", i * 2));
+        assert!(writer.append(&key, 1, &Value::from("value")).is_ok());
+    }
+    let sealed = writer.seal().unwrap();
+
+    // the bloom filter is read back from disk
+    let mut slab = sstable::Slab::new(
+        0,
+        &path,
+        sealed.min_key().clone(),
+        sealed.max_key().clone(),
+        sealed.size(),
+    );
+    slab.load_bloom_filter().unwrap();
+
+    for i in 0..100 {
+        assert!(slab.may_contain(&Key::from(format!("key-{:03}", i * 2))));
+    }
+
+    let false_positives = (0..100)
+        .filter(|i| slab.may_contain(&Key::from(format!("key-{:03}", i * 2 + 1))))
+        .count();
+    assert!(
+        false_positives < 10,
+        "got {} false positives",
+        false_positives
+    );
+}
+
+#[test]
+fn check_keys_must_be_appended_in_order() {
+    let test_storage_dir = tempdir().unwrap();
+    let mut writer = sstable::Writer::create(&test_storage_dir.path().join("sstable")).unwrap();
+
+    assert!(writer
+        .append(&Key::from("foo"), 1, &Value::from("bar"))
+        .is_ok());
+    assert!(matches!(
+        writer.append(&Key::from("bar"), 1, &Value::from("baz")),
+        Err(sstable::Error::UnorderedKey)
+    ));
+}
+
+#[test]
+fn check_lookup_and_iteration_span_blocks() {
+    let test_storage_dir = tempdir().unwrap();
+    let options = sstable::WriterOptions {
+        block_size: 64,
+        ..sstable::WriterOptions::default()
+    };
+    let mut writer =
+        sstable::Writer::create_with_options(&test_storage_dir.path().join("sstable"), options)
+            .unwrap();
+
+    for i in 0..500u64 {
+        let key = Key::from(format!("key-{:04}", i));
+        if i % 7 == 0 {
+            writer.append_tombstone(&key, i).unwrap();
+        } else {
+            writer
+                .append(&key, i, &Value::from(format!("value-{}", i)))
+                .unwrap();
+        }
+    }
+
+    let slab = writer.seal().unwrap();
+    let sstable = slab.sstable().unwrap();
+    assert!(sstable.block_count() > 1);
+
+    for i in 0..500 {
+        let expected = if i % 7 == 0 {
+            Entry::Tombstone
+        } else {
+            Entry::Val(Value::from(format!("value-{}", i)))
+        };
+        assert_eq!(
+            sstable
+                .get(&Key::from(format!("key-{:04}", i)), SequenceNumber::MAX)
+                .unwrap(),
+            Some(expected)
+        );
+    }
+    assert_eq!(
+        sstable
+            .get(&Key::from("key-0100a"), SequenceNumber::MAX)
+            .unwrap(),
+        None
+    );
+    assert_eq!(
+        sstable
+            .get(&Key::from("key-9999"), SequenceNumber::MAX)
+            .unwrap(),
+        None
+    );
+
+    let keys: Vec<Key> = sstable.into_iter().map(|r| r.unwrap().0).collect();
+    let expected: Vec<Key> = (0..500)
+        .map(|i| Key::from(format!("key-{:04}", i)))
+        .collect();
+    assert_eq!(keys, expected);
+}
+
+#[test]
+fn check_cursor_seeks_across_blocks() {
+    let test_storage_dir = tempdir().unwrap();
+    let options = sstable::WriterOptions {
+        block_size: 64,
+        ..sstable::WriterOptions::default()
+    };
+    let mut writer =
+        sstable::Writer::create_with_options(&test_storage_dir.path().join("sstable"), options)
+            .unwrap();
+
+    for i in 0..100 {
+        let key = Key::from(format!("key-{:03}", i * 2));
+        writer.append(&key, 1, &Value::from("value")).unwrap();
+    }
+
+    let mut cursor = writer
+        .seal()
+        .unwrap()
+        .sstable()
+        .unwrap()
+        .cursor(SequenceNumber::MAX);
+    assert!(cursor.current().is_none());
+
+    // keys that aren't in the table position the cursor at the following key
+    cursor.seek(&Key::from("key-051")).unwrap();
+    assert_eq!(cursor.current().unwrap().0, &Key::from("key-052"));
+    cursor.next().unwrap();
+    assert_eq!(cursor.current().unwrap().0, &Key::from("key-054"));
+
+    cursor.seek(&Key::from("key-198")).unwrap();
+    assert_eq!(cursor.current().unwrap().0, &Key::from("key-198"));
+    cursor.next().unwrap();
+    assert!(cursor.current().is_none());
+
+    cursor.seek(&Key::from("key-199")).unwrap();
+    assert!(cursor.current().is_none());
+
+    cursor.seek_to_first().unwrap();
+    assert_eq!(cursor.current().unwrap().0, &Key::from("key-000"));
+}
+
+#[test]
+fn check_cursor_moves_backwards_across_blocks() {
+    let test_storage_dir = tempdir().unwrap();
+    let options = sstable::WriterOptions {
+        block_size: 64,
+        ..sstable::WriterOptions::default()
+    };
+    let mut writer =
+        sstable::Writer::create_with_options(&test_storage_dir.path().join("sstable"), options)
+            .unwrap();
+
+    for i in 0..100 {
+        let key = Key::from(format!("key-{:03}", i * 2));
+        writer.append(&key, 1, &Value::from("value")).unwrap();
+    }
+
+    let mut cursor = writer
+        .seal()
+        .unwrap()
+        .sstable()
+        .unwrap()
+        .cursor(SequenceNumber::MAX);
+
+    cursor.seek_to_last().unwrap();
+    let mut keys = Vec::new();
+    while let Some((key, _)) = cursor.current() {
+        keys.push(key.clone());
+        cursor.prev().unwrap();
+    }
+    let expected: Vec<Key> = (0..100)
+        .rev()
+        .map(|i| Key::from(format!("key-{:03}", i * 2)))
+        .collect();
+    assert_eq!(expected, keys);
+
+    cursor.seek_for_prev(&Key::from("key-051")).unwrap();
+    assert_eq!(cursor.current().unwrap().0, &Key::from("key-050"));
+    cursor.seek_for_prev(&Key::from("key-999")).unwrap();
+    assert_eq!(cursor.current().unwrap().0, &Key::from("key-198"));
+    cursor.seek_for_prev(&Key::from("a")).unwrap();
+    assert!(cursor.current().is_none());
+}
+
+#[test]
+fn check_reads_see_the_versions_of_their_sequence_number() {
+    let test_storage_dir = tempdir().unwrap();
+    let options = sstable::WriterOptions {
+        block_size: 64,
+        ..sstable::WriterOptions::default()
+    };
+    let mut writer =
+        sstable::Writer::create_with_options(&test_storage_dir.path().join("sstable"), options)
+            .unwrap();
+
+    // the versions of "b" span several blocks, from the most recent to the oldest one
+    writer
+        .append(&Key::from("a"), 1, &Value::from("a-1"))
+        .unwrap();
+    for seq in (10..50).rev() {
+        let value = Value::from(format!("b-{}", seq));
+        writer.append(&Key::from("b"), seq, &value).unwrap();
+    }
+    writer
+        .append(&Key::from("c"), 60, &Value::from("c-60"))
+        .unwrap();
+    writer.append_tombstone(&Key::from("d"), 70).unwrap();
+    writer
+        .append(&Key::from("d"), 5, &Value::from("d-5"))
+        .unwrap();
+    assert!(matches!(
+        writer.append(&Key::from("d"), 6, &Value::from("d-6")),
+        Err(sstable::Error::UnorderedKey)
+    ));
+
+    let slab = writer.seal().unwrap();
+    assert_eq!(70, slab.max_seq());
+    let sstable = slab.sstable().unwrap();
+    assert!(sstable.block_count() > 2);
+
+    let value = |v: &str| Some(Entry::Val(Value::from(v)));
+    assert_eq!(sstable.get(&Key::from("b"), 9).unwrap(), None);
+    assert_eq!(sstable.get(&Key::from("b"), 10).unwrap(), value("b-10"));
+    assert_eq!(sstable.get(&Key::from("b"), 30).unwrap(), value("b-30"));
+    assert_eq!(sstable.get(&Key::from("b"), 100).unwrap(), value("b-49"));
+    assert_eq!(sstable.get(&Key::from("d"), 69).unwrap(), value("d-5"));
+    assert_eq!(
+        sstable.get(&Key::from("d"), 70).unwrap(),
+        Some(Entry::Tombstone)
+    );
+
+    let visible = |seq| {
+        let mut cursor = slab.sstable().unwrap().cursor(seq);
+        let mut forward = Vec::new();
+        cursor.seek_to_first().unwrap();
+        while let Some((key, entry)) = cursor.current() {
+            forward.push((key.clone(), entry.clone()));
+            cursor.next().unwrap();
+        }
+
+        let mut backward = Vec::new();
+        cursor.seek_to_last().unwrap();
+        while let Some((key, entry)) = cursor.current() {
+            backward.push((key.clone(), entry.clone()));
+            cursor.prev().unwrap();
+        }
+        backward.reverse();
+        assert_eq!(forward, backward);
+        forward
+    };
+    let entry = |k: &str, v: &str| (Key::from(k), Entry::Val(Value::from(v)));
+
+    assert_eq!(vec![entry("a", "a-1"), entry("d", "d-5")], visible(9));
+    assert_eq!(
+        vec![entry("a", "a-1"), entry("b", "b-20"), entry("d", "d-5")],
+        visible(20)
+    );
+    assert_eq!(
+        vec![
+            entry("a", "a-1"),
+            entry("b", "b-49"),
+            entry("c", "c-60"),
+            (Key::from("d"), Entry::Tombstone)
+        ],
+        visible(100)
+    );
+
+    let mut cursor = slab.sstable().unwrap().cursor(20);
+    cursor.seek_for_prev(&Key::from("c")).unwrap();
+    assert_eq!(
+        Some(entry("b", "b-20")),
+        cursor.current().map(|(k, e)| (k.clone(), e.clone()))
+    );
+    cursor.seek(&Key::from("b")).unwrap();
+    assert_eq!(
+        Some(entry("b", "b-20")),
+        cursor.current().map(|(k, e)| (k.clone(), e.clone()))
+    );
+}
+
+/// Flip a bit of the first occurrence of `needle` in the file at `path`
+fn corrupt(path: &std::path::Path, needle: &[u8]) -> usize {
+    let mut content = std::fs::read(path).unwrap();
+    let offset = content
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .unwrap();
+    content[offset] ^= 0x20;
+    std::fs::write(path, content).unwrap();
+    offset
+}
+
+#[test]
+fn check_corrupted_blocks_are_detected() {
+    let test_storage_dir = tempdir().unwrap();
+    let options = sstable::WriterOptions {
+        block_size: 32,
+        ..sstable::WriterOptions::default()
+    };
+    let mut writer =
+        sstable::Writer::create_with_options(&test_storage_dir.path().join("sstable"), options)
+            .unwrap();
+    for i in 0..10 {
+        let key = Key::from(format!("key-{}", i));
+        writer
+            .append(&key, 1, &Value::from(format!("value-{}", i)))
+            .unwrap();
+    }
+    let slab = writer.seal().unwrap();
+    assert!(slab.sstable().unwrap().verify().unwrap().is_empty());
+
+    let corrupted = corrupt(slab.path(), b"value-5") as u64;
+    let key = Key::from("key-5");
+
+    let sstable = slab.sstable().unwrap();
+    let range = match sstable.get(&key, SequenceNumber::MAX) {
+        Err(sstable::Error::CorruptedBlock(range)) => range,
+        other => panic!("expected a corrupted block, got {:?}", other),
+    };
+    assert_eq!(slab.path(), range.path);
+    assert!(range.offset <= corrupted && corrupted < range.offset + range.size);
+    assert_eq!(vec![range], sstable.verify().unwrap());
+
+    // the other blocks are still readable
+    assert_eq!(
+        Some(Entry::Val(Value::from("value-0"))),
+        sstable
+            .get(&Key::from("key-0"), SequenceNumber::MAX)
+            .unwrap()
+    );
+
+    // without verification the corruption goes unnoticed
+    let unverified = sstable::ReadOptions {
+        verify_checksums: false,
+    };
+    let sstable = slab.sstable_with_options(unverified).unwrap();
+    assert_eq!(
+        Some(Entry::Val(Value::from("Value-5"))),
+        sstable.get(&key, SequenceNumber::MAX).unwrap()
+    );
+}
+
+#[test]
+fn check_corrupted_indexes_are_detected() {
+    let test_storage_dir = tempdir().unwrap();
+    let mut writer = sstable::Writer::create(&test_storage_dir.path().join("sstable")).unwrap();
+    writer
+        .append(&Key::from("foo"), 1, &Value::from("bar"))
+        .unwrap();
+    let slab = writer.seal().unwrap();
+
+    // the key is stored in the data block first and in the index afterwards
+    let mut content = std::fs::read(slab.path()).unwrap();
+    let index = content
+        .windows(3)
+        .rposition(|window| window == b"foo")
+        .unwrap();
+    content[index] ^= 0x20;
+    std::fs::write(slab.path(), content).unwrap();
+
+    match slab.sstable() {
+        Err(sstable::Error::CorruptedBlock(range)) => {
+            assert!(range.offset <= index as u64 && (index as u64) < range.offset + range.size)
+        }
+        other => panic!("expected a corrupted index, got {:?}", other.map(|_| ())),
+    }
 }
@@ -1,54 +1,70 @@
 use r2d2::engine::storage::lsm::wal;
 use r2d2::engine::{Key, Value};
 use tempfile::tempdir;
+use wal::reader::WalReader;
 use wal::Operation;
 
+/// The reader of the segment that has been created last
+fn open_newest(wal: &wal::WalManager) -> WalReader {
+    wal.segments().unwrap().pop().unwrap().open().unwrap()
+}
+
 #[test]
 fn check_wal_works() {
     let test_storage_dir = tempdir().unwrap();
-    let wal = wal::WalManager::init(&test_storage_dir.path().to_path_buf()).unwrap();
+    let wal = wal::WalManager::init(test_storage_dir.path(), wal::Options::default()).unwrap();
     let mut log_writer = wal.create().unwrap();
-    let mut log_reader = wal.open().unwrap();
+    let mut log_reader = open_newest(&wal);
     let foo = Key::from("foo");
     let bar = Value::from("bar");
     let baz = Value::from("baz");
 
-    assert!(log_writer.write(Operation::Set(&foo, &bar)).is_ok());
-    assert!(log_writer.write(Operation::Set(&foo, &baz)).is_ok());
-    assert!(log_writer.write(Operation::Delete(&foo)).is_ok());
+    assert!(log_writer.write(1, &[Operation::Set(&foo, &bar)]).is_ok());
+    assert!(log_writer.write(2, &[Operation::Set(&foo, &baz)]).is_ok());
+    assert!(log_writer
+        .write(3, &[Operation::<_, &Value>::Delete(&foo)])
+        .is_ok());
 
     let op1 = log_reader.read().unwrap();
-    assert_eq!(Operation::Set(foo.clone(), bar.clone()), op1);
+    assert_eq!((1, vec![Operation::Set(foo.clone(), bar.clone())]), op1);
 
     let op2 = log_reader.read().unwrap();
-    assert_eq!(Operation::Set(foo.clone(), baz.clone()), op2);
+    assert_eq!((2, vec![Operation::Set(foo.clone(), baz.clone())]), op2);
 
     let op3 = log_reader.read().unwrap();
-    assert_eq!(Operation::Delete(foo.clone()), op3);
+    assert_eq!((3, vec![Operation::Delete(foo.clone())]), op3);
 }
 
 #[test]
 fn check_wal_iterator() {
     let test_storage_dir = tempdir().unwrap();
-    let wal = wal::WalManager::init(&test_storage_dir.path().to_path_buf()).unwrap();
+    let wal = wal::WalManager::init(test_storage_dir.path(), wal::Options::default()).unwrap();
     let mut log_writer = wal.create().unwrap();
     let foo = Key::from("foo");
     let baz = Value::from("baz");
     let bar = Value::from("bar");
 
-    assert!(log_writer.write(Operation::Set(&foo, &bar)).is_ok());
-    assert!(log_writer.write(Operation::Set(&foo, &baz)).is_ok());
-    assert!(log_writer.write(Operation::Delete(&foo)).is_ok());
+    assert!(log_writer.write(1, &[Operation::Set(&foo, &bar)]).is_ok());
+    assert!(log_writer.write(2, &[Operation::Set(&foo, &baz)]).is_ok());
+    assert!(log_writer
+        .write(3, &[Operation::<_, &Value>::Delete(&foo)])
+        .is_ok());
 
-    let mut log_reader = wal.open().unwrap();
+    let mut log_reader = open_newest(&wal);
     let op1 = log_reader.next().unwrap().unwrap();
-    assert_eq!(wal::Operation::Set(foo.clone(), bar.clone()), op1);
+    assert_eq!(
+        (1, vec![wal::Operation::Set(foo.clone(), bar.clone())]),
+        op1
+    );
 
     let op2 = log_reader.next().unwrap().unwrap();
-    assert_eq!(wal::Operation::Set(foo.clone(), baz.clone()), op2);
+    assert_eq!(
+        (2, vec![wal::Operation::Set(foo.clone(), baz.clone())]),
+        op2
+    );
 
     let op3 = log_reader.next().unwrap().unwrap();
-    assert_eq!(wal::Operation::Delete(foo.clone()), op3);
+    assert_eq!((3, vec![wal::Operation::Delete(foo.clone())]), op3);
 
     assert!(log_reader.next().is_none());
 }
@@ -56,9 +72,9 @@ fn check_wal_iterator() {
 #[test]
 fn check_iterator_empty_file() {
     let test_storage_dir = tempdir().unwrap();
-    let wal = wal::WalManager::init(&test_storage_dir.path().to_path_buf()).unwrap();
+    let wal = wal::WalManager::init(test_storage_dir.path(), wal::Options::default()).unwrap();
     let _log_writer = wal.create().unwrap();
-    let mut log_reader = wal.open().unwrap();
+    let mut log_reader = open_newest(&wal);
 
     assert!(log_reader.next().is_none());
 }
@@ -66,25 +82,203 @@ fn check_iterator_empty_file() {
 #[test]
 fn check_log_resume() {
     let test_storage_dir = tempdir().unwrap();
-    let wal = wal::WalManager::init(&test_storage_dir.path().to_path_buf()).unwrap();
+    let wal = wal::WalManager::init(test_storage_dir.path(), wal::Options::default()).unwrap();
     let foo = Key::from("foo");
     let foobar = Key::from("foobar");
     let bar = Value::from("bar");
 
     {
         let mut log_writer = wal.create().unwrap();
-        assert!(log_writer.write(Operation::Set(&foo, &bar)).is_ok());
+        assert!(log_writer.write(1, &[Operation::Set(&foo, &bar)]).is_ok());
     }
 
     {
         let mut log_writer = wal.resume().unwrap();
-        assert!(log_writer.write(Operation::Set(&foobar, &bar)).is_ok());
+        assert!(log_writer
+            .write(2, &[Operation::Set(&foobar, &bar)])
+            .is_ok());
     }
 
-    let mut log_reader = wal.open().unwrap();
+    let mut log_reader = open_newest(&wal);
     let op1 = log_reader.next().unwrap().unwrap();
-    assert_eq!(wal::Operation::Set(foo.clone(), bar.clone()), op1);
+    assert_eq!(
+        (1, vec![wal::Operation::Set(foo.clone(), bar.clone())]),
+        op1
+    );
 
     let op2 = log_reader.next().unwrap().unwrap();
-    assert_eq!(wal::Operation::Set(foobar.clone(), bar.clone()), op2);
+    assert_eq!(
+        (2, vec![wal::Operation::Set(foobar.clone(), bar.clone())]),
+        op2
+    );
+}
+
+#[test]
+fn check_segments_are_read_in_order() {
+    let test_storage_dir = tempdir().unwrap();
+    let wal = wal::WalManager::init(test_storage_dir.path(), wal::Options::default()).unwrap();
+    let foo = Key::from("foo");
+    let bar = Value::from("bar");
+    assert!(!wal.recovery_needed().unwrap());
+
+    let mut log_writer = wal.create().unwrap();
+    log_writer.write(1, &[Operation::Set(&foo, &bar)]).unwrap();
+    let first = wal.newest();
+    let mut log_writer = wal.create().unwrap();
+    log_writer.write(2, &[Operation::Set(&foo, &bar)]).unwrap();
+    let second = wal.newest();
+    let mut log_writer = wal.create().unwrap();
+    log_writer
+        .write(3, &[Operation::<_, &Value>::Delete(&foo)])
+        .unwrap();
+    assert!(first < second);
+
+    // the numbering continues after a restart
+    let wal = wal::WalManager::init(test_storage_dir.path(), wal::Options::default()).unwrap();
+    assert!(wal.recovery_needed().unwrap());
+    let segments: Vec<u64> = wal.segments().unwrap().iter().map(|s| s.number()).collect();
+    assert_eq!(vec![first, second, second + 1], segments);
+
+    let sequence_numbers: Vec<u64> = wal
+        .open()
+        .unwrap()
+        .into_iter()
+        .flatten()
+        .map(|record| record.unwrap().0)
+        .collect();
+    assert_eq!(vec![1, 2, 3], sequence_numbers);
+
+    wal.remove_before(second).unwrap();
+    assert_eq!(2, wal.segments().unwrap().len());
+    let _log_writer = wal.create().unwrap();
+    assert!(wal.newest() > second + 1);
+}
+
+#[test]
+fn check_full_segments_are_rotated() {
+    let test_storage_dir = tempdir().unwrap();
+    let options = wal::Options {
+        segment_size: 256,
+        ..wal::Options::default()
+    };
+    let wal = wal::WalManager::init(test_storage_dir.path(), options).unwrap();
+    let foo = Key::from("foo");
+    let bar = Value::from("bar");
+
+    let mut log_writer = wal.create().unwrap();
+    for seq in 1..=100 {
+        log_writer
+            .write(seq, &[Operation::Set(&foo, &bar)])
+            .unwrap();
+        wal.rotate_if_full(&mut log_writer).unwrap();
+    }
+
+    let segments = wal.segments().unwrap();
+    assert!(segments.len() > 1);
+    for segment in &segments[..segments.len() - 1] {
+        assert!(std::fs::metadata(segment.path()).unwrap().len() >= 256);
+    }
+    let sequence_numbers: Vec<u64> = wal
+        .open()
+        .unwrap()
+        .into_iter()
+        .flatten()
+        .map(|record| record.unwrap().0)
+        .collect();
+    assert_eq!((1..=100).collect::<Vec<_>>(), sequence_numbers);
+}
+
+#[test]
+fn check_committed_records_are_readable_with_every_sync_policy() {
+    let policies = [
+        wal::SyncPolicy::Always,
+        wal::SyncPolicy::Interval(std::time::Duration::from_millis(10)),
+        wal::SyncPolicy::Bytes(ubyte::ByteUnit::Kibibyte(1)),
+        wal::SyncPolicy::Os,
+    ];
+    let foo = Key::from("foo");
+    let bar = Value::from("bar");
+
+    for policy in policies {
+        let test_storage_dir = tempdir().unwrap();
+        let options = wal::Options {
+            sync_policy: policy,
+            ..wal::Options::default()
+        };
+        let wal = wal::WalManager::init(test_storage_dir.path(), options).unwrap();
+        {
+            let mut log_writer = wal.create().unwrap();
+            for seq in 1..=100 {
+                log_writer
+                    .append(seq, &[Operation::Set(&foo, &bar)])
+                    .unwrap();
+                log_writer.commit().unwrap();
+            }
+        }
+
+        let records: Vec<_> = open_newest(&wal).map(|record| record.unwrap()).collect();
+        assert_eq!(100, records.len(), "{:?}", policy);
+    }
+}
+
+#[test]
+fn check_torn_records_at_the_end_are_cut_off() {
+    let test_storage_dir = tempdir().unwrap();
+    let wal = wal::WalManager::init(test_storage_dir.path(), wal::Options::default()).unwrap();
+    let foo = Key::from("foo");
+    let bar = Value::from("bar");
+
+    let mut log_writer = wal.create().unwrap();
+    let wal_file = wal.segments().unwrap()[0].path().to_path_buf();
+    log_writer.write(1, &[Operation::Set(&foo, &bar)]).unwrap();
+    let complete = std::fs::metadata(&wal_file).unwrap().len();
+    log_writer.write(2, &[Operation::Set(&foo, &bar)]).unwrap();
+
+    // the last bytes of the second record never made it to disk
+    let mut content = std::fs::read(&wal_file).unwrap();
+    let last = content.len() - 1;
+    content[last] ^= 0xff;
+    std::fs::write(&wal_file, &content).unwrap();
+
+    let mut log_reader = open_newest(&wal);
+    let records: Vec<_> = (&mut log_reader).map(|record| record.unwrap().0).collect();
+    assert_eq!(vec![1], records);
+    assert_eq!(Some(complete), log_reader.torn_tail());
+
+    assert!(log_reader.truncate_torn_tail().unwrap());
+    assert_eq!(complete, std::fs::metadata(&wal_file).unwrap().len());
+
+    // writes continue after the last complete record
+    let mut log_writer = wal.resume().unwrap();
+    log_writer.write(3, &[Operation::Set(&foo, &bar)]).unwrap();
+    let records: Vec<_> = open_newest(&wal).map(|record| record.unwrap().0).collect();
+    assert_eq!(vec![1, 3], records);
+}
+
+#[test]
+fn check_corrupted_records_before_the_end_are_reported() {
+    let test_storage_dir = tempdir().unwrap();
+    let wal = wal::WalManager::init(test_storage_dir.path(), wal::Options::default()).unwrap();
+    let foo = Key::from("foo");
+    let bar = Value::from("bar");
+
+    let mut log_writer = wal.create().unwrap();
+    let wal_file = wal.segments().unwrap()[0].path().to_path_buf();
+    log_writer.write(1, &[Operation::Set(&foo, &bar)]).unwrap();
+    let second = std::fs::metadata(&wal_file).unwrap().len();
+    log_writer.write(2, &[Operation::Set(&foo, &bar)]).unwrap();
+    log_writer.write(3, &[Operation::Set(&foo, &bar)]).unwrap();
+
+    // flip a bit in the data of the second record
+    let mut content = std::fs::read(&wal_file).unwrap();
+    content[second as usize + 14] ^= 0x01;
+    std::fs::write(&wal_file, &content).unwrap();
+
+    let mut log_reader = open_newest(&wal);
+    assert_eq!(1, log_reader.next().unwrap().unwrap().0);
+    match log_reader.next() {
+        Some(Err(wal::Error::CorruptedRecord(offset))) => assert_eq!(second, offset),
+        other => panic!("expected a corrupted record, got {:?}", other),
+    }
+    assert_eq!(None, log_reader.torn_tail());
 }